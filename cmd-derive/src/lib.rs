@@ -2,7 +2,10 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::{Data, DeriveInput, Error, Ident, parse_macro_input, spanned::Spanned};
 
-#[proc_macro_derive(ChatCommand, attributes(help, only_gm, only_not_gm, default, alias))]
+#[proc_macro_derive(
+    ChatCommand,
+    attributes(help, only_gm, only_not_gm, default, alias, rest)
+)]
 pub fn packet_read_write_derive(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     build_parser(&input).unwrap_or_else(|err| err.to_compile_error().into())
@@ -48,10 +51,10 @@ impl Attributes {
                     }
                 }
                 syn::Meta::NameValue(meta) => {
-                    if let syn::Expr::Lit(expr) = &meta.value {
-                        if let syn::Lit::Str(lit_str) = &expr.lit {
-                            ret.doc.push_str(&lit_str.value());
-                        }
+                    if let syn::Expr::Lit(expr) = &meta.value
+                        && let syn::Lit::Str(lit_str) = &expr.lit
+                    {
+                        ret.doc.push_str(&lit_str.value());
                     }
                 }
             }
@@ -112,6 +115,7 @@ fn build_parser(ast: &DeriveInput) -> syn::Result<TokenStream> {
     let mut gm_only_help = String::new();
     let mut not_gm_only_help = String::new();
     let mut parse_variant_stream = quote! {};
+    let mut is_command_stream = quote! {};
 
     for variant in &cmds.variants {
         let variant_name = &variant.ident;
@@ -120,7 +124,10 @@ fn build_parser(ast: &DeriveInput) -> syn::Result<TokenStream> {
         let mut variant_stream = quote! {};
         if attributes.is_help {
             parse_variant_stream.extend(quote! {
-                (#cmd_name, is_gm) => Ok(Self::#variant_name(Self::get_help(is_gm))),
+                (#cmd_name, is_gm) => Ok(Self::#variant_name(Self::get_help(is_gm, extra_help))),
+            });
+            is_command_stream.extend(quote! {
+                (#cmd_name, _) => true,
             });
             continue;
         }
@@ -132,7 +139,23 @@ fn build_parser(ast: &DeriveInput) -> syn::Result<TokenStream> {
                 .ok_or_else(|| Error::new(variant.span(), "Tuple fields are not supported"))?;
             fields.push(name.clone());
             let ty = &field.ty;
-            if attributes.default {
+            let is_rest = field.attrs.iter().any(|a| a.path().is_ident("rest"));
+            if is_rest {
+                // field consumes all remaining arguments
+                variant_stream.extend(quote! {
+                    let #name = data_stream.by_ref().collect::<Vec<_>>().join(" ");
+                });
+                if !attributes.default {
+                    variant_stream.extend(quote! {
+                        if #name.is_empty() {
+                            return Err(format!("{{red}}Missing argument: {}{{def}}", stringify!(#name)));
+                        }
+                    });
+                }
+                variant_stream.extend(quote! {
+                    let #name = #name.parse::<#ty>().map_err(|e| e.to_string())?;
+                });
+            } else if attributes.default {
                 variant_stream.extend(quote! {
                     let #name = data_stream.next().and_then(|s| s.parse::<#ty>().ok()).unwrap_or_default();
                 })
@@ -156,6 +179,14 @@ fn build_parser(ast: &DeriveInput) -> syn::Result<TokenStream> {
             })
         }
         let aliases = &attributes.aliases;
+        let gm_pattern = match attributes.cmd_type {
+            CmdType::AllPlayer => quote! { _ },
+            CmdType::GmOnly => quote! { true },
+            CmdType::NotGmOnly => quote! { false },
+        };
+        is_command_stream.extend(quote! {
+            (#cmd_name #(| #aliases)*, #gm_pattern) => true,
+        });
         match attributes.cmd_type {
             CmdType::AllPlayer => {
                 parse_variant_stream.extend(quote! {
@@ -180,24 +211,34 @@ fn build_parser(ast: &DeriveInput) -> syn::Result<TokenStream> {
 
     let code = quote! {
         impl #name {
-            fn parse(string: &str, is_gm: bool) -> Result<Self, String> {
+            /// Parses a command. `extra_help` is appended to the help message (e.g. for commands
+            /// that are handled elsewhere).
+            fn parse(string: &str, is_gm: bool, extra_help: &str) -> Result<Self, String> {
                 if string.is_empty() {
-                    return Err(Self::get_help(is_gm));
+                    return Err(Self::get_help(is_gm, extra_help));
                 }
                 let mut data_stream = string.split_whitespace();
                 let cmd = data_stream.next().unwrap_or_default();
                 match (cmd, is_gm) {
                     #parse_variant_stream
-                    (unk_cmd, _) => Err(format!("{{red}}Unknown command: {unk_cmd}{{def}}\n{}", Self::get_help(is_gm)))
+                    (unk_cmd, _) => Err(format!("{{red}}Unknown command: {unk_cmd}{{def}}\n{}", Self::get_help(is_gm, extra_help)))
+                }
+            }
+            /// Checks if the command (or its alias) exists and is available for the player.
+            fn is_command(cmd: &str, is_gm: bool) -> bool {
+                match (cmd, is_gm) {
+                    #is_command_stream
+                    _ => false,
                 }
             }
-            fn get_help(is_gm: bool) -> String {
+            fn get_help(is_gm: bool, extra_help: &str) -> String {
                 let mut help = String::from(#help_message);
                 if is_gm {
                     help.push_str(#gm_only_help);
                 } else {
                     help.push_str(#not_gm_only_help);
                 }
+                help.push_str(extra_help);
                 help
             }
         }
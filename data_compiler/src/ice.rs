@@ -7,6 +7,7 @@ pub enum Group {
     Group2,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 #[repr(C)]
 pub struct IceFlags {
@@ -1,7 +1,10 @@
 mod ice;
 use data_structs::{
     SerDeFile as _, ServerData,
-    inventory::{DefaultClassesData, DefaultClassesDataReadable, ItemName},
+    inventory::{
+        DefaultClassesData, DefaultClassesDataReadable, ItemEffect, ItemName, ItemPrice,
+        ItemUpgrades,
+    },
     map::MapData,
     name_to_id,
     quest::QuestData,
@@ -51,6 +54,35 @@ fn main() {
         server_data.item_params.names = data;
     }
 
+    // parse item prices
+    println!("Parsing item prices...");
+    let mut prices_file = filename.to_path_buf();
+    prices_file.push("item_prices");
+    prices_file = select_ext(prices_file);
+    if prices_file.is_file() {
+        let data = Vec::<ItemPrice>::load_file(&prices_file).unwrap();
+        server_data.item_params.prices = data;
+    }
+
+    // parse item upgrades
+    println!("Parsing item upgrades...");
+    let mut upgrades_file = filename.to_path_buf();
+    upgrades_file.push("item_upgrades");
+    upgrades_file = select_ext(upgrades_file);
+    if upgrades_file.is_file() {
+        server_data.item_params.upgrades = ItemUpgrades::load_file(&upgrades_file).unwrap();
+    }
+
+    // parse item effects
+    println!("Parsing item effects...");
+    let mut effects_file = filename.to_path_buf();
+    effects_file.push("item_effects");
+    effects_file = select_ext(effects_file);
+    if effects_file.is_file() {
+        let data = Vec::<ItemEffect>::load_file(&effects_file).unwrap();
+        server_data.item_params.effects = data;
+    }
+
     // parse item attributes
     println!("Parsing item attributes...");
     let mut attrs_file = filename.to_path_buf();
@@ -309,7 +341,7 @@ fn parse_enemy_stats(
 
         let mut base = EnemyBaseStats::load_file(base_stats_path)?;
         let mut stats = std::mem::take(&mut base.levels);
-        stats.sort_by(|a, b| a.level.cmp(&b.level));
+        stats.sort_by_key(|a| a.level);
         base.levels = duplicate_stats(stats);
 
         data.base = base;
@@ -323,7 +355,7 @@ fn parse_enemy_stats(
         {
             let base = &mut stats.stats;
             let mut stats = std::mem::take(&mut base.levels);
-            stats.sort_by(|a, b| a.level.cmp(&b.level));
+            stats.sort_by_key(|a| a.level);
             base.levels = duplicate_stats(stats);
         }
 
@@ -21,6 +21,113 @@ pub struct ItemParameters {
     pub vita_attrs: Vec<u8>,
     pub attrs: ItemAttributesPC,
     pub names: Vec<ItemName>,
+    /// Prices that NPC shops pay for items.
+    pub prices: Vec<ItemPrice>,
+    pub upgrades: ItemUpgrades,
+    /// Effects of usable consumables.
+    pub effects: Vec<ItemEffect>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemPrice {
+    pub id: ItemId,
+    /// Sell price of one item.
+    pub sell_price: u32,
+}
+
+/// Grinding and affixing recipes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemUpgrades {
+    /// Grind levels starting from +1. Weapons can't be ground past the last level.
+    pub grinds: Vec<GrindLevel>,
+    /// Meseta cost of one affix transfer.
+    pub affix_meseta: u32,
+    /// Success rates of affixing by the number of affixes on the weapon after the transfer,
+    /// starting from one affix. Weapons can't have more affixes than there are rates.
+    pub affix_rates: Vec<f64>,
+    /// Effects of special abilities.
+    pub affixes: Vec<AffixData>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrindLevel {
+    pub meseta: u32,
+    pub materials: Vec<UpgradeMaterial>,
+    /// Chance of success from 0.0 to 1.0.
+    pub success_rate: f64,
+    /// Weapon power added by this level.
+    pub power: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpgradeMaterial {
+    pub item: ItemId,
+    pub amount: u16,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AffixData {
+    pub id: u16,
+    pub name: String,
+    pub mel_pwr: i32,
+    pub rng_pwr: i32,
+    pub tec_pwr: i32,
+    pub hp: i32,
+}
+
+impl ItemUpgrades {
+    /// Returns the weapon power added by all levels up to `grind`.
+    pub fn grind_power(&self, grind: u8) -> u32 {
+        self.grinds
+            .iter()
+            .take(grind as usize)
+            .map(|g| g.power)
+            .sum()
+    }
+    pub fn get_affix(&self, id: u16) -> Option<&AffixData> {
+        self.affixes.iter().find(|a| a.id == id)
+    }
+}
+
+/// Effect of using a consumable item.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemEffect {
+    pub id: ItemId,
+    /// Restored HP in percent of max HP.
+    pub hp_percent: f32,
+    pub pp: u32,
+    /// Whether the item revives incapacitated players.
+    pub revive: bool,
+    pub buff: Option<BuffData>,
+    /// Distance in which party members are also affected. 0 only affects the user.
+    pub range: f64,
+    /// Cooldown of the item in seconds.
+    pub cooldown: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuffData {
+    pub attack_mul: f32,
+    pub defense_mul: f32,
+    /// Duration of the buff in seconds.
+    pub duration: u32,
+}
+
+impl Default for BuffData {
+    fn default() -> Self {
+        Self {
+            attack_mul: 1.0,
+            defense_mul: 1.0,
+            duration: 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,7 +180,7 @@ pub struct DefaultItem {
 }
 
 impl StorageInventory {
-    pub fn generate_info(&self) -> StorageInfo {
+    pub const fn generate_info(&self) -> StorageInfo {
         StorageInfo {
             total_space: self.total_space,
             used_space: self.items.len() as u32,
@@ -11,7 +11,10 @@ pub mod stats;
 
 use inventory::DefaultClassesData;
 use serde::{Serialize, de::DeserializeOwned};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -62,6 +65,12 @@ pub enum Error {
     #[cfg(feature = "ship")]
     #[error("AEAD error: {0}")]
     AEADError(String),
+    #[cfg(feature = "ship")]
+    #[error("Incompatible protocol version (local: {local}, remote: {remote})")]
+    IncompatibleProtocol { local: u32, remote: u32 },
+    #[cfg(feature = "ship")]
+    #[error("Peer doesn't support protocol version negotiation")]
+    UnknownProtocol,
 }
 
 pub trait SerDeFile: Serialize + DeserializeOwned {
@@ -79,6 +88,13 @@ pub trait SerDeFile: Serialize + DeserializeOwned {
             Self::deserialize(&mut rmp_serde::Deserializer::new(data).with_human_readable())?;
         Ok(names)
     }
+    #[cfg(feature = "rmp")]
+    fn load_from_mp_comp_slice(data: &[u8]) -> Result<Self, Error> {
+        let data = zstd::Decoder::new(data)?;
+        let names =
+            Self::deserialize(&mut rmp_serde::Deserializer::new(data).with_human_readable())?;
+        Ok(names)
+    }
     #[cfg(feature = "json")]
     fn load_from_json_file<T: AsRef<std::path::Path>>(path: T) -> Result<Self, Error> {
         let data = std::fs::read_to_string(path)?;
@@ -161,6 +177,14 @@ pub struct ServerData {
     pub default_classes: DefaultClassesData,
 }
 
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the Unix epoch")
+        .as_secs()
+}
+
 pub fn name_to_id(name: &str) -> u32 {
     name.chars().fold(0u32, |acc, c| {
         acc ^ ((acc << 6).overflowing_add((acc >> 2).overflowing_sub(0x61c88647 - c as u32).0)).0
@@ -1,4 +1,5 @@
 use pso2packetlib::protocol::{
+    items::ItemId,
     models::Position,
     server::{LoadLevelPacket, ZoneSettings},
     spawn::{EventSpawnPacket, NPCSpawnPacket, ObjectSpawnPacket, TransporterSpawnPacket},
@@ -46,6 +47,23 @@ pub struct NPCData {
     pub is_active: bool,
     pub data: NPCSpawnPacket,
     pub lua_data: Option<String>,
+    /// Shop that is opened when a player interacts with the NPC.
+    pub shop: Option<ShopData>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ShopData {
+    pub name: String,
+    pub items: Vec<ShopItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ShopItem {
+    pub item: ItemId,
+    /// Price of one item.
+    pub price: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
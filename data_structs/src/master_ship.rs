@@ -26,6 +26,105 @@ use tokio::{
     net::UdpSocket,
 };
 
+/// Version of the ship <-> master ship protocol.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version that can still be talked to.
+///
+/// Version 1 peers predate the handshake and are not supported.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+const HELLO_MAGIC: [u8; 4] = *b"PSMS";
+const HELLO_LEN: usize = 20;
+
+/// Set of optional protocol features.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Server data is versioned and can be downloaded in chunks.
+    pub const DATA_CHUNKS: Self = Self(1 << 0);
+    /// Emergency quest notifications.
+    pub const EMERGENCY_QUESTS: Self = Self(1 << 1);
+    /// Network wide announcements.
+    pub const ANNOUNCEMENTS: Self = Self(1 << 2);
+    /// Banned users are reported with [`UserLoginResult::Banned`].
+    pub const USER_BANS: Self = Self(1 << 3);
+    /// Network wide quest clear time records.
+    pub const QUEST_RECORDS: Self = Self(1 << 4);
+
+    /// Capabilities supported by this build.
+    pub const fn supported() -> Self {
+        Self(
+            Self::DATA_CHUNKS.0
+                | Self::EMERGENCY_QUESTS.0
+                | Self::ANNOUNCEMENTS.0
+                | Self::USER_BANS.0
+                | Self::QUEST_RECORDS.0,
+        )
+    }
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Protocol info that is exchanged during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Hello {
+    version: u32,
+    min_version: u32,
+    capabilities: Capabilities,
+}
+
+impl Hello {
+    const fn local() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+    fn to_bytes(self) -> [u8; HELLO_LEN] {
+        let mut out = [0; HELLO_LEN];
+        out[..4].copy_from_slice(&HELLO_MAGIC);
+        out[4..8].copy_from_slice(&self.version.to_le_bytes());
+        out[8..12].copy_from_slice(&self.min_version.to_le_bytes());
+        out[12..].copy_from_slice(&self.capabilities.0.to_le_bytes());
+        out
+    }
+    fn from_bytes(data: &[u8; HELLO_LEN]) -> Result<Self, Error> {
+        // peers without version negotiation send their ECDH key instead
+        if data[..4] != HELLO_MAGIC {
+            return Err(Error::UnknownProtocol);
+        }
+        Ok(Self {
+            version: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            min_version: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            capabilities: Capabilities(u64::from_le_bytes(data[12..].try_into().unwrap())),
+        })
+    }
+    /// Checks that both sides can talk to each other and returns the negotiated protocol.
+    fn negotiate(self, remote: Self) -> Result<(u32, Capabilities), Error> {
+        if remote.version < self.min_version || self.version < remote.min_version {
+            return Err(Error::IncompatibleProtocol {
+                local: self.version,
+                remote: remote.version,
+            });
+        }
+        Ok((
+            self.version.min(remote.version),
+            self.capabilities.intersection(remote.capabilities),
+        ))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MasterShipComm {
     pub id: u32,
@@ -93,11 +192,139 @@ pub enum MasterShipAction {
     SetFormat(SerializerFormat),
     ServerDataRequest,
     ServerDataResponse(ServerDataResult),
+    /// (MS->S) Master ship's server data has changed and should be reloaded.
+    /// Parameter is the new data version.
+    ServerDataChanged(String),
+    /// (S->MS) Ship wants to know the version of master ship's server data.
+    ServerDataInfoRequest,
+    /// (MS->S) Version info of the server data, `None` if no data is available.
+    ServerDataInfo(Option<ServerDataInfo>),
+    /// (S->MS) Ship wants to download a part of the compressed server data.
+    ServerDataChunkRequest {
+        version: String,
+        index: u32,
+    },
+    /// (MS->S) Part of the compressed server data.
+    ServerDataChunk(ServerDataChunk),
+    /// (S->MS) Version of the server data that the ship is currently running.
+    ReportDataVersion(String),
+    /// (MS->S) Emergency quest has been scheduled or is currently running.
+    EmergencyQuest(EmergencyQuest),
+    /// (S->MS) Ship wants to send an announcement to the whole network.
+    /// (MS->S) Announcement that should be shown to all players.
+    Announcement(Announcement),
     Ping,
     Pong,
     Ok,
     /// Error has occured
     Error(String),
+    // since protocol version 2 new actions are appended to keep variant indices of older ones and
+    // are gated by `required_capabilities`
+    /// (S->MS) Character has cleared a quest.
+    PutQuestRecord(QuestRecord),
+    /// (S->MS) Ship wants the best clear times of a quest on every ship.
+    GetQuestRecords {
+        quest_id: u32,
+        difficulty: u16,
+    },
+    /// (MS->S) Best clear times of a quest, fastest first.
+    QuestRecords(Vec<QuestRecord>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmergencyQuest {
+    /// Name id of the quest.
+    pub quest_id: u32,
+    /// Message that is announced to all players when the quest starts.
+    pub announcement: String,
+    /// Start of the quest window (UNIX timestamp in seconds).
+    pub start: u64,
+    /// End of the quest window (UNIX timestamp in seconds).
+    pub end: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServerDataInfo {
+    /// Hex encoded SHA-256 hash of the compressed data.
+    pub version: String,
+    /// Size of the compressed data in bytes.
+    pub size: u64,
+    /// Number of chunks the data is split into.
+    pub chunk_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerDataChunk {
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuestRecord {
+    /// Name id of the quest.
+    pub quest_id: u32,
+    pub difficulty: u16,
+    /// Clear time in milliseconds.
+    pub time: u64,
+    pub char_id: u32,
+    pub char_name: String,
+    /// Names of the other party members.
+    pub party: Vec<String>,
+    /// Id of the ship where the record was set. Set by the master ship.
+    pub ship_id: u32,
+    /// When the record was set (UNIX timestamp in seconds).
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub message: String,
+    pub kind: AnnouncementType,
+}
+
+/// Announcement that is periodically repeated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduledAnnouncement {
+    /// Message to announce.
+    pub message: String,
+    /// How the message is displayed.
+    #[serde(default)]
+    pub kind: AnnouncementType,
+    /// Interval between announcements in minutes.
+    pub interval: u64,
+    /// Block to send the announcement to (whole ship if omitted). Only used by ships.
+    #[serde(default)]
+    pub block: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnnouncementType {
+    /// Message is shown in the system chat.
+    #[default]
+    SystemMessage,
+    /// Message scrolls across the top of the screen.
+    Scrolling,
+}
+
+impl MasterShipAction {
+    /// Capabilities that both sides need to support for this action to be sent.
+    pub const fn required_capabilities(&self) -> Capabilities {
+        match self {
+            Self::ServerDataChanged(_)
+            | Self::ServerDataInfoRequest
+            | Self::ServerDataInfo(_)
+            | Self::ServerDataChunkRequest { .. }
+            | Self::ServerDataChunk(_)
+            | Self::ReportDataVersion(_) => Capabilities::DATA_CHUNKS,
+            Self::EmergencyQuest(_) => Capabilities::EMERGENCY_QUESTS,
+            Self::Announcement(_) => Capabilities::ANNOUNCEMENTS,
+            Self::PutQuestRecord(_) | Self::GetQuestRecords { .. } | Self::QuestRecords(_) => {
+                Capabilities::QUEST_RECORDS
+            }
+            _ => Capabilities::empty(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -147,6 +374,8 @@ pub enum UserLoginResult {
     },
     InvalidPassword(u32),
     NotFound,
+    /// User is banned. Parameter is the user id.
+    Banned(u32),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -184,6 +413,8 @@ pub struct ShipConnection {
     aes: Aes256Gcm,
     format: SerializerFormat,
     deferred_fmt: Option<SerializerFormat>,
+    protocol_version: u32,
+    capabilities: Capabilities,
 }
 
 #[cfg(feature = "ship")]
@@ -201,12 +432,23 @@ impl ShipConnection {
             .await?;
         stream.write_all(hostkey).await?;
 
+        let local_hello = Hello::local().to_bytes();
+        stream.write_all(&local_hello).await?;
+        let mut remote_hello = [0; HELLO_LEN];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut remote_hello))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let (protocol_version, capabilities) =
+            Hello::local().negotiate(Hello::from_bytes(&remote_hello)?)?;
+
         let shared_secret = ShipConnection::key_exchange(&mut stream).await?;
         let hash = {
             use sha2::Digest;
             let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
             hasher.update(shared_secret);
             hasher.update(hostkey);
+            hasher.update(local_hello);
+            hasher.update(remote_hello);
             hasher.finalize()
         };
 
@@ -223,6 +465,8 @@ impl ShipConnection {
             aes: Aes256Gcm::new(&shared_secret.into()),
             format: SerializerFormat::Json,
             deferred_fmt: None,
+            protocol_version,
+            capabilities,
         })
     }
     pub async fn new_client<F>(mut stream: tokio::net::TcpStream, check: F) -> Result<Self, Error>
@@ -248,12 +492,25 @@ impl ShipConnection {
             return Err(Error::UnknownHostkey(hostkey));
         }
 
+        let mut remote_hello = [0; HELLO_LEN];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut remote_hello))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let local_hello = Hello::local().to_bytes();
+        // send our version even if we can't talk to the server, so that it can report the error
+        stream.write_all(&local_hello).await?;
+        let (protocol_version, capabilities) =
+            Hello::local().negotiate(Hello::from_bytes(&remote_hello)?)?;
+
         let shared_secret = ShipConnection::key_exchange(&mut stream).await?;
+        // hellos are signed by the server, so they can't be tampered with
         let hash = {
             use sha2::Digest;
             let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
             hasher.update(shared_secret);
             hasher.update(&hostkey);
+            hasher.update(remote_hello);
+            hasher.update(local_hello);
             hasher.finalize()
         };
 
@@ -277,6 +534,8 @@ impl ShipConnection {
             aes: Aes256Gcm::new(&shared_secret.into()),
             format: SerializerFormat::Json,
             deferred_fmt: None,
+            protocol_version,
+            capabilities,
         })
     }
     pub async fn read(&mut self) -> Result<MasterShipComm, Error> {
@@ -284,10 +543,10 @@ impl ShipConnection {
     }
     pub async fn read_for(&mut self, time: Duration) -> Result<MasterShipComm, Error> {
         let mut buf = [0; 4096];
-        if !self.raw_read_buffer.is_empty() {
-            if let Some(data) = self.extract_data()? {
-                return Ok(data);
-            }
+        if !self.raw_read_buffer.is_empty()
+            && let Some(data) = self.extract_data()?
+        {
+            return Ok(data);
         }
         loop {
             let read_bytes = self.read_timeout(&mut buf, time).await?;
@@ -390,10 +649,18 @@ impl ShipConnection {
             .map_err(|_| Error::HKDFError)?;
         Ok(output)
     }
-    pub fn set_format(&mut self, format: SerializerFormat) {
+    /// Protocol version negotiated with the peer.
+    pub const fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+    /// Capabilities supported by both sides of the connection.
+    pub const fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    pub const fn set_format(&mut self, format: SerializerFormat) {
         self.format = format;
     }
-    pub fn set_deferred_fmt(&mut self, format: SerializerFormat) {
+    pub const fn set_deferred_fmt(&mut self, format: SerializerFormat) {
         self.deferred_fmt = Some(format);
     }
 }
@@ -458,6 +725,24 @@ impl std::fmt::Debug for ServerDataResult {
     }
 }
 
+impl std::fmt::Debug for ServerDataChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerDataChunk")
+            .field("index", &self.index)
+            .field("data", &format_args!("[{} bytes]", self.data.len()))
+            .finish()
+    }
+}
+
+/// Calculates the version string of compressed server data.
+pub fn data_version(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 impl SerializerFormat {
     fn serialize<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, Error> {
         match self {
@@ -480,3 +765,55 @@ impl SerializerFormat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, Hello, MasterShipAction, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_negotiation() {
+        let local = Hello::local();
+        let remote = Hello {
+            version: local.version + 1,
+            min_version: local.min_version,
+            capabilities: Capabilities::DATA_CHUNKS,
+        };
+        let remote = Hello::from_bytes(&remote.to_bytes()).unwrap();
+        let (version, caps) = local.negotiate(remote).unwrap();
+        assert_eq!(version, local.version);
+        assert_eq!(caps, Capabilities::DATA_CHUNKS);
+        assert!(!caps.contains(Capabilities::ANNOUNCEMENTS));
+
+        let too_new = Hello {
+            version: local.version + 2,
+            min_version: local.version + 1,
+            capabilities: Capabilities::supported(),
+        };
+        assert!(local.negotiate(too_new).is_err());
+        assert!(Hello::from_bytes(&[0; 20]).is_err());
+        let v1 = Hello {
+            version: 1,
+            min_version: 1,
+            capabilities: Capabilities::empty(),
+        };
+        assert!(local.negotiate(v1).is_err());
+    }
+
+    #[test]
+    fn test_fewer_capabilities() {
+        let master = Hello::local();
+        let ship = Hello {
+            version: PROTOCOL_VERSION,
+            min_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::DATA_CHUNKS,
+        };
+        let (version, caps) = master.negotiate(ship).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(caps, Capabilities::DATA_CHUNKS);
+        // the ship doesn't get actions it doesn't know about
+        assert!(caps.contains(MasterShipAction::ServerDataInfoRequest.required_capabilities()));
+        assert!(caps.contains(MasterShipAction::Ping.required_capabilities()));
+        let record = MasterShipAction::QuestRecords(vec![]);
+        assert!(!caps.contains(record.required_capabilities()));
+    }
+}
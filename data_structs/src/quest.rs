@@ -1,9 +1,11 @@
 use crate::map::{MapData, ZoneId};
 use pso2packetlib::protocol::{
+    items::ItemId,
     questlist::{Quest, QuestDifficulty},
     spawn::EnemySpawnPacket,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[serde(default)]
@@ -13,6 +15,36 @@ pub struct QuestData {
     pub map: MapData,
     pub enemies: Vec<EnemyData>,
     pub immediate_move: bool,
+    /// Conditions that clear the quest. The quest is cleared when any of them is met.
+    pub clear_conditions: Vec<ClearCondition>,
+    pub rewards: QuestRewards,
+    pub fail_conditions: FailConditions,
+    /// Fail conditions for specific difficulties. They replace `fail_conditions`.
+    pub difficulty_fail_conditions: HashMap<u16, FailConditions>,
+    /// Conditions that unlock the quest. If not set, the quest can only be unlocked by scripts.
+    pub unlock: Option<UnlockConditions>,
+    /// Enemy level and HP scaling by party size.
+    pub enemy_scaling: EnemyScaling,
+    /// Enemy scaling for specific difficulties. They replace `enemy_scaling`.
+    pub difficulty_enemy_scaling: HashMap<u16, EnemyScaling>,
+    /// Maximum number of parties (up to 4) that can share one instance of the quest.
+    /// 0 and 1 mean that every party gets its own instance.
+    pub max_parties: u8,
+}
+
+impl QuestData {
+    /// Returns fail conditions for the difficulty.
+    pub fn get_fail_conditions(&self, diff: u16) -> &FailConditions {
+        self.difficulty_fail_conditions
+            .get(&diff)
+            .unwrap_or(&self.fail_conditions)
+    }
+    /// Returns enemy scaling for the difficulty.
+    pub fn get_enemy_scaling(&self, diff: u16) -> &EnemyScaling {
+        self.difficulty_enemy_scaling
+            .get(&diff)
+            .unwrap_or(&self.enemy_scaling)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -22,3 +54,107 @@ pub struct EnemyData {
     pub mapid: ZoneId,
     pub data: EnemySpawnPacket,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearCondition {
+    /// Enemy with the specified name is killed.
+    KillEnemy(String),
+    /// Any player enters the zone with the specified name.
+    ReachZone(String),
+    /// Map script sends the signal with the specified name (`quest_signal(name)`).
+    Signal(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct QuestRewards {
+    pub exp: u32,
+    pub meseta: u32,
+    pub items: Vec<ItemId>,
+    /// Maximum clear times (in seconds) for S, A and B ranks. If empty, the rank is always S.
+    pub rank_times: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct FailConditions {
+    /// Time limit in seconds. 0 means no limit.
+    pub time_limit: u32,
+    /// Number of party member deaths that fails the quest. 0 means no limit.
+    pub max_deaths: u32,
+    /// Map script signals that fail the quest (`quest_signal(name)`).
+    pub signals: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct UnlockConditions {
+    /// Quests (name ids) that must be cleared.
+    pub cleared_quests: Vec<u32>,
+    /// Minimum level of the main class.
+    pub min_level: u16,
+    /// Account flags that must be set.
+    pub account_flags: Vec<usize>,
+    /// Character flags that must be set.
+    pub character_flags: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct EnemyScaling {
+    /// Levels added to the monster level of the difficulty.
+    pub level_offset: i32,
+    /// Enemy HP multiplier.
+    pub hp_multiplier: f32,
+    /// Levels added for each party member after the first.
+    pub party_level: f32,
+    /// HP multiplier added for each party member after the first.
+    pub party_hp_multiplier: f32,
+}
+
+impl EnemyScaling {
+    /// Returns the enemy level and HP multiplier for the base level and party size.
+    pub fn scale(&self, base_level: u32, party_size: usize) -> (u32, f32) {
+        let extra_members = party_size.saturating_sub(1) as f32;
+        let level = base_level as f32
+            + self.level_offset as f32
+            + (self.party_level * extra_members).round();
+        let hp_mul = self.hp_multiplier * (1.0 + self.party_hp_multiplier * extra_members);
+        (level.max(1.0) as u32, hp_mul.max(0.0))
+    }
+}
+
+impl Default for EnemyScaling {
+    fn default() -> Self {
+        Self {
+            level_offset: 0,
+            hp_multiplier: 1.0,
+            party_level: 0.0,
+            party_hp_multiplier: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnemyScaling;
+
+    #[test]
+    fn enemy_scaling() {
+        let scaling = EnemyScaling {
+            level_offset: 2,
+            hp_multiplier: 2.0,
+            party_level: 0.5,
+            party_hp_multiplier: 0.25,
+        };
+        assert_eq!(scaling.scale(10, 1), (12, 2.0));
+        assert_eq!(scaling.scale(10, 4), (14, 3.5));
+        assert_eq!(EnemyScaling::default().scale(10, 4), (10, 1.0));
+        let scaling = EnemyScaling {
+            level_offset: -20,
+            ..Default::default()
+        };
+        assert_eq!(scaling.scale(10, 0).0, 1);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{Error, MSData, Settings, announcements};
+use data_structs::master_ship::{Announcement, AnnouncementType};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const HELP: &[&str] = &[
+    "auth <token> - authenticate",
+    "ships - list registered ships",
+    "users - list users",
+    "ban <user id> - ban a user",
+    "unban <user id> - unban a user",
+    "passwd <user id> <password> - reset password of a SEGA ID user",
+    "registration [on|off] - show or toggle ship auto registration",
+    "psk list - list fingerprints of ship PSKs",
+    "psk add <psk> - add a ship PSK",
+    "psk revoke <psk> - revoke a ship PSK",
+    "announce <message> - send a system message to all ships",
+    "scroll <message> - send a scrolling message to all ships",
+    "records <quest id> <difficulty> - list the best clear times of a quest on all ships",
+    "quit - close the connection",
+];
+
+/// Line based admin interface.
+///
+/// Every command is answered with zero or more data lines followed by either `ok` or
+/// `error: <reason>`.
+pub async fn run(ms_data: Arc<MSData>, address: String, token: String) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Failed to start admin interface on {address}: {e}");
+            return;
+        }
+    };
+    log::info!("Admin interface listening on {address}");
+    let token = Arc::new(token);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("Failed to accept admin connection: {e}");
+                continue;
+            }
+        };
+        let ms_data = ms_data.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, &ms_data, &token).await {
+                log::warn!("Admin connection {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: TcpStream, ms_data: &MSData, token: &str) -> Result<(), Error> {
+    let addr = stream.peer_addr()?;
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut authed = false;
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        if cmd == "quit" {
+            break;
+        }
+        if !authed {
+            if cmd == "auth" && token_eq(args, token) {
+                log::info!("Admin {addr} authenticated");
+                authed = true;
+                write.write_all(b"ok\n").await?;
+                continue;
+            }
+            log::warn!("Admin {addr} failed to authenticate");
+            write.write_all(b"error: not authenticated\n").await?;
+            break;
+        }
+        let response = match run_command(ms_data, cmd, args).await {
+            Ok(mut lines) => {
+                lines.push("ok".into());
+                lines
+            }
+            Err(e) => vec![format!("error: {e}")],
+        };
+        for line in response {
+            write.write_all(line.as_bytes()).await?;
+            write.write_all(b"\n").await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns a short hash of the PSK, so it can be told apart from others without revealing it.
+fn psk_fingerprint(psk: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(psk)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Compares tokens without leaking the position of the first mismatch.
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn run_command(ms_data: &MSData, cmd: &str, args: &str) -> Result<Vec<String>, Error> {
+    let sql = &ms_data.sql;
+    let mut out = vec![];
+    match cmd {
+        "help" => out.extend(HELP.iter().map(|l| l.to_string())),
+        "ships" => {
+            for ship in ms_data.ships.read().iter() {
+                let info = &ship.info;
+                let state = match ship.disconnected_at {
+                    Some(_) => "disconnected",
+                    None => "online",
+                };
+                let version = ship.data_version.as_deref().unwrap_or("-");
+                out.push(format!(
+                    "{} {} {}:{} {:?} {state} data:{version}",
+                    info.id, info.name, info.ip, info.port, info.status
+                ));
+            }
+        }
+        "users" => {
+            for user in sql.get_users().await? {
+                out.push(format!(
+                    "{} username:{} psn:{} nickname:{} gm:{} banned:{}",
+                    user.id,
+                    user.username,
+                    user.psn_username,
+                    user.nickname,
+                    user.isgm,
+                    user.banned
+                ));
+            }
+        }
+        "ban" | "unban" => {
+            let id = parse_id(args)?;
+            let banned = cmd == "ban";
+            sql.set_banned(id, banned).await?;
+            log::info!("Admin: user {id} banned: {banned}");
+        }
+        "passwd" => {
+            let (id, password) = args.split_once(' ').ok_or(Error::InvalidData)?;
+            let id = parse_id(id)?;
+            sql.set_password(id, password.trim()).await?;
+            log::info!("Admin: password of user {id} has been reset");
+        }
+        "registration" => {
+            match args {
+                "" => {}
+                "on" | "off" => {
+                    let enabled = args == "on";
+                    Settings::save_registration_enabled(&ms_data.settings_path, enabled).await?;
+                    sql.set_registration_enabled(enabled);
+                    log::info!("Admin: auto registration enabled: {enabled}");
+                }
+                _ => return Err(Error::InvalidData),
+            }
+            let state = if sql.registration_enabled() {
+                "on"
+            } else {
+                "off"
+            };
+            out.push(format!("registration: {state}"));
+        }
+        "psk" => {
+            let (subcmd, psk) = args.split_once(' ').unwrap_or((args, ""));
+            let psk = psk.trim();
+            match subcmd {
+                "list" => out.extend(
+                    sql.get_ship_psks()
+                        .await?
+                        .iter()
+                        .map(|psk| psk_fingerprint(psk)),
+                ),
+                "add" if !psk.is_empty() => {
+                    if !sql.get_ship_data(psk.as_bytes()).await? {
+                        sql.put_ship_data(psk.as_bytes()).await?;
+                    }
+                    log::info!("Admin: added ship PSK");
+                }
+                "revoke" if !psk.is_empty() => {
+                    if !sql.delete_ship_data(psk.as_bytes()).await? {
+                        return Err(Error::UnknownShip);
+                    }
+                    log::info!("Admin: revoked ship PSK");
+                }
+                _ => return Err(Error::InvalidData),
+            }
+        }
+        "announce" | "scroll" if !args.is_empty() => {
+            let kind = match cmd {
+                "scroll" => AnnouncementType::Scrolling,
+                _ => AnnouncementType::SystemMessage,
+            };
+            let message = args.to_string();
+            announcements::broadcast(ms_data, Announcement { message, kind });
+        }
+        "records" => {
+            let (quest_id, difficulty) = args.split_once(' ').ok_or(Error::InvalidData)?;
+            let quest_id = parse_id(quest_id)?;
+            let difficulty = difficulty.trim().parse().map_err(|_| Error::InvalidData)?;
+            let records = sql.get_quest_records(quest_id, difficulty, 50).await?;
+            for (i, record) in records.iter().enumerate() {
+                out.push(format!(
+                    "{}. {}ms {} (char {}) ship:{} party:[{}]",
+                    i + 1,
+                    record.time,
+                    record.char_name,
+                    record.char_id,
+                    record.ship_id,
+                    record.party.join(", ")
+                ));
+            }
+        }
+        _ => return Err(Error::InvalidAction),
+    }
+    Ok(out)
+}
+
+fn parse_id(id: &str) -> Result<u32, Error> {
+    id.trim().parse().map_err(|_| Error::InvalidData)
+}
@@ -0,0 +1,27 @@
+use crate::MSData;
+use data_structs::master_ship::{Announcement, MasterShipAction, ScheduledAnnouncement};
+use std::{sync::Arc, time::Duration};
+
+/// Sends an announcement to all registered ships.
+pub fn broadcast(ms_data: &MSData, announcement: Announcement) {
+    log::info!("Network announcement: {}", announcement.message);
+    // no receivers just means that no ships are connected
+    let _ = ms_data
+        .notif_ch
+        .send(MasterShipAction::Announcement(announcement));
+}
+
+pub async fn run_scheduled(ms_data: Arc<MSData>, announcement: ScheduledAnnouncement) {
+    let period = Duration::from_secs(announcement.interval.max(1) * 60);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        broadcast(
+            &ms_data,
+            Announcement {
+                message: announcement.message.clone(),
+                kind: announcement.kind,
+            },
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{Error, MSData, async_write};
+use data_structs::{
+    master_ship::{EmergencyQuest, MasterShipAction},
+    unix_time,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+const SECS_IN_DAY: u64 = 24 * 3600;
+
+/// Daily scheduled emergency quest.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmergencyQuestSchedule {
+    /// Name id of the quest.
+    pub quest_id: u32,
+    /// Message that is announced to all players when the quest starts.
+    pub announcement: String,
+    /// Hour (UTC) at which the quest starts.
+    pub hour: u8,
+    /// Minute at which the quest starts.
+    pub minute: u8,
+    /// Duration of the quest window in minutes.
+    pub duration: u64,
+}
+
+impl EmergencyQuestSchedule {
+    /// Checks that the start time is a valid time of day.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.hour >= 24 || self.minute >= 60 {
+            return Err(Error::InvalidEQTime(self.quest_id, self.hour, self.minute));
+        }
+        Ok(())
+    }
+    /// Returns the quest window that contains `now`, if any.
+    fn active_window(&self, now: u64) -> Option<EmergencyQuest> {
+        let day_start = now - now % SECS_IN_DAY;
+        let start_offset = self.hour as u64 * 3600 + self.minute as u64 * 60;
+        let duration = self.duration * 60;
+        let today = day_start + start_offset;
+        // window might have started yesterday and still be running
+        [today, today.saturating_sub(SECS_IN_DAY)]
+            .into_iter()
+            .find(|&start| start <= now && now < start + duration)
+            .map(|start| EmergencyQuest {
+                quest_id: self.quest_id,
+                announcement: self.announcement.clone(),
+                start,
+                end: start + duration,
+            })
+    }
+}
+
+/// Starts and ends scheduled emergency quests, notifying all ships.
+pub async fn eq_scheduler(ms_data: Arc<MSData>, schedule: Vec<EmergencyQuestSchedule>) {
+    if schedule.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let now = unix_time();
+        let mut active = async_write(&ms_data.active_eqs).await;
+        active.retain(|eq| {
+            let is_active = eq.end > now;
+            if !is_active {
+                log::info!("Emergency quest {} has ended", eq.quest_id);
+            }
+            is_active
+        });
+        for eq in schedule.iter().filter_map(|s| s.active_window(now)) {
+            if active
+                .iter()
+                .any(|a| a.quest_id == eq.quest_id && a.start == eq.start)
+            {
+                continue;
+            }
+            log::info!("Emergency quest {} has started", eq.quest_id);
+            // no receivers just means that no ships are connected
+            let _ = ms_data
+                .notif_ch
+                .send(MasterShipAction::EmergencyQuest(eq.clone()));
+            active.push(eq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmergencyQuestSchedule;
+
+    #[test]
+    fn start_time() {
+        let mut schedule = EmergencyQuestSchedule {
+            quest_id: 1,
+            announcement: String::new(),
+            hour: 23,
+            minute: 59,
+            duration: 30,
+        };
+        assert!(schedule.validate().is_ok());
+        schedule.hour = 24;
+        assert!(schedule.validate().is_err());
+        schedule.hour = 0;
+        schedule.minute = 60;
+        assert!(schedule.validate().is_err());
+    }
+}
@@ -1,13 +1,18 @@
 #![deny(clippy::undocumented_unsafe_blocks)]
 #![warn(clippy::future_not_send)]
 #![allow(clippy::await_holding_lock)]
+mod admin;
+mod announcements;
+mod emergency;
 pub mod sql;
 use clap::Parser;
 use data_structs::{
     SerDeFile, ServerData,
     master_ship::{
-        MasterShipAction, MasterShipComm, RegisterShipResult, ServerDataResult, SetNicknameResult,
-        ShipConnection, ShipInfo, ShipLoginResult, UserLoginResult, start_discovery_loop,
+        Capabilities, EmergencyQuest, MasterShipAction, MasterShipComm, RegisterShipResult,
+        ScheduledAnnouncement, ServerDataChunk, ServerDataInfo, ServerDataResult,
+        SetNicknameResult, ShipConnection, ShipInfo, ShipLoginResult, UserLoginResult,
+        data_version, start_discovery_loop,
     },
 };
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
@@ -22,12 +27,16 @@ use serde::{Deserialize, Serialize};
 use std::{
     io,
     net::{IpAddr, Ipv4Addr},
-    sync::{Arc, atomic::AtomicBool},
-    time::Duration,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64},
+    },
+    time::{Duration, Instant},
 };
 use tokio::{
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
+    sync::broadcast,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -39,6 +48,23 @@ struct Settings {
     file_log_level: log::LevelFilter,
     console_log_level: log::LevelFilter,
     data_path: Option<String>,
+    /// Time (in seconds) for which a disconnected ship stays registered.
+    ship_grace_period: u64,
+    /// Time (in seconds) of inactivity after which a ship is pinged.
+    ping_interval: u64,
+    /// Time (in seconds) without any response after which a ship is considered dead.
+    ship_timeout: u64,
+    /// Address of the admin interface.
+    admin_address: String,
+    /// Token required to use the admin interface. The interface is disabled if not set.
+    admin_token: Option<String>,
+    /// Daily scheduled emergency quests.
+    emergency_quests: Vec<emergency::EmergencyQuestSchedule>,
+    /// Periodic announcements sent to all ships.
+    announcements: Vec<ScheduledAnnouncement>,
+    /// Location of the settings file.
+    #[serde(skip)]
+    path: String,
 }
 
 #[derive(Parser, Debug)]
@@ -68,6 +94,15 @@ struct Args {
     /// Location of complied server data file
     #[arg(short, long)]
     data_path: Option<String>,
+    /// Time (in seconds) for which a disconnected ship stays registered
+    #[arg(short('g'), long)]
+    ship_grace_period: Option<u64>,
+    /// Time (in seconds) of inactivity after which a ship is pinged
+    #[arg(long)]
+    ping_interval: Option<u64>,
+    /// Time (in seconds) without any response after which a ship is considered dead
+    #[arg(long)]
+    ship_timeout: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -77,16 +112,49 @@ struct Keys {
 }
 
 struct MSData {
-    ships: RwLock<Vec<ShipInfo>>,
+    ships: RwLock<Vec<RegisteredShip>>,
     sql: sql::Sql,
-    srv_data: Option<ServerData>,
+    srv_data: RwLock<Option<Arc<LoadedData>>>,
+    grace_period: Duration,
+    ping_interval: Duration,
+    ship_timeout: Duration,
+    /// Currently running emergency quests.
+    active_eqs: RwLock<Vec<EmergencyQuest>>,
+    /// Notifications that are sent to all registered ships.
+    notif_ch: broadcast::Sender<MasterShipAction>,
+    /// Location of the settings file.
+    settings_path: String,
+}
+
+struct RegisteredShip {
+    info: ShipInfo,
+    /// Id of the connection that owns this registration.
+    conn_id: u64,
+    /// Set when the owning connection was lost without unregistering.
+    disconnected_at: Option<Instant>,
+    /// Time of the last message received from the ship.
+    last_seen: Instant,
+    /// Version of the server data reported by the ship.
+    data_version: Option<String>,
+}
+
+/// Server data together with its compressed form that is sent to ships.
+struct LoadedData {
+    data: ServerData,
+    /// Contents of the data file.
+    compressed: Vec<u8>,
+    info: ServerDataInfo,
 }
 
 struct Ship {
     conn: ShipConnection,
     ms_data: Arc<MSData>,
     authed: bool,
-    pings: u8,
+    last_seen: Instant,
+    conn_id: u64,
+    ship_id: Option<u32>,
+    /// Version of the server data reported by the ship.
+    data_version: Option<String>,
 }
 
 macro_rules! args_to_settings {
@@ -120,9 +188,28 @@ impl Settings {
         args_to_settings!(args.log_dir => settings.log_dir);
         args_to_settings!(args.file_log_level => settings.file_log_level);
         args_to_settings!(args.console_log_level => settings.console_log_level);
+        args_to_settings!(args.ship_grace_period => settings.ship_grace_period);
+        args_to_settings!(args.ping_interval => settings.ping_interval);
+        args_to_settings!(args.ship_timeout => settings.ship_timeout);
         settings.data_path = args.data_path.or(settings.data_path);
+        for eq in &settings.emergency_quests {
+            eq.validate()?;
+        }
+        settings.path = path.to_string();
         Ok(settings)
     }
+    /// Updates the auto registration state in the settings file, leaving the rest of the file
+    /// (including comments) as is.
+    pub async fn save_registration_enabled(path: &str, enabled: bool) -> Result<(), Error> {
+        let mut settings: toml_edit::DocumentMut = match tokio::fs::read_to_string(path).await {
+            Ok(s) => s.parse()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        settings["registration_enabled"] = toml_edit::value(enabled);
+        tokio::fs::write(path, settings.to_string()).await?;
+        Ok(())
+    }
 }
 
 impl Default for Settings {
@@ -134,6 +221,14 @@ impl Default for Settings {
             file_log_level: log::LevelFilter::Info,
             console_log_level: log::LevelFilter::Debug,
             data_path: None,
+            ship_grace_period: 30,
+            ping_interval: 30,
+            ship_timeout: 120,
+            admin_address: String::from("127.0.0.1:15001"),
+            admin_token: None,
+            emergency_quests: vec![],
+            announcements: vec![],
+            path: String::new(),
         }
     }
 }
@@ -150,8 +245,12 @@ pub enum Error {
     InvalidPassword(u32),
     #[error("No user")]
     NoUser,
+    #[error("User {0} is banned")]
+    UserBanned(u32),
     #[error("Unable to hash the password")]
     HashError,
+    #[error("Emergency quest {0} has an invalid start time {1:02}:{2:02}")]
+    InvalidEQTime(u32, u8, u8),
     #[error("Failed to get network interfaces: {0}")]
     NetworkInterfacesError(#[from] network_interface::Error),
 
@@ -165,6 +264,8 @@ pub enum Error {
     TomlSerError(#[from] toml::ser::Error),
     #[error("TOML Deserialization error: {0}")]
     TomlDeError(#[from] toml::de::Error),
+    #[error("TOML Edit error: {0}")]
+    TomlEditError(#[from] toml_edit::TomlError),
     #[error("MP Serialization error: {0}")]
     RMPEncodeError(#[from] rmp_serde::encode::Error),
     #[error("MP Deserialization error: {0}")]
@@ -181,10 +282,59 @@ enum AddrType {
     Global,
 }
 
+/// Size of the server data chunks sent to ships.
+const DATA_CHUNK_SIZE: usize = 256 * 1024;
+/// Number of quest records returned to ships.
+const QUEST_RECORDS_LIMIT: u32 = 10;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
 static IS_RUNNING: AtomicBool = AtomicBool::new(true);
 
-async fn load_data(path: &str) -> Result<ServerData, Error> {
-    Ok(ServerData::load_from_mp_comp(path)?)
+async fn load_data(path: &str) -> Result<LoadedData, Error> {
+    let compressed = tokio::fs::read(path).await?;
+    let data = ServerData::load_from_mp_comp_slice(&compressed)?;
+    let info = ServerDataInfo {
+        version: data_version(&compressed),
+        size: compressed.len() as u64,
+        chunk_count: compressed.len().div_ceil(DATA_CHUNK_SIZE) as u32,
+    };
+    log::info!("Server data version: {}", info.version);
+    Ok(LoadedData {
+        data,
+        compressed,
+        info,
+    })
+}
+
+/// Reloads server data when the data file changes and notifies all ships about it.
+async fn data_watcher(ms_data: Arc<MSData>, path: String) {
+    let modified_time = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified_time(&path);
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let modified = modified_time(&path);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        log::info!("Server data file changed, reloading...");
+        match load_data(&path).await {
+            Ok(data) => {
+                let version = data.info.version.clone();
+                let old_data = ms_data.srv_data.write().replace(Arc::new(data));
+                if old_data.is_some_and(|d| d.info.version == version) {
+                    log::info!("Server data didn't change");
+                    continue;
+                }
+                log::info!("Server data reloaded");
+                let _ = ms_data
+                    .notif_ch
+                    .send(MasterShipAction::ServerDataChanged(version));
+            }
+            Err(e) => log::warn!("Failed to reload server data: {e}"),
+        }
+    }
 }
 
 pub async fn run() -> Result<(), Error> {
@@ -217,9 +367,9 @@ pub async fn run() -> Result<(), Error> {
     tokio::spawn(ctrl_c_handler());
     let sql = sql::Sql::new(&settings.db_name, settings.registration_enabled).await?;
     let servers = RwLock::new(vec![]);
-    let server_data = if let Some(path) = settings.data_path {
-        match load_data(&path).await {
-            Ok(d) => Some(d),
+    let server_data = if let Some(path) = settings.data_path.as_ref() {
+        match load_data(path).await {
+            Ok(d) => Some(Arc::new(d)),
             Err(e) => {
                 log::warn!("Failed to load server data: {e}");
                 None
@@ -231,10 +381,30 @@ pub async fn run() -> Result<(), Error> {
     let ms_data = Arc::new(MSData {
         sql,
         ships: servers,
-        srv_data: server_data,
+        srv_data: RwLock::new(server_data),
+        grace_period: Duration::from_secs(settings.ship_grace_period),
+        ping_interval: Duration::from_secs(settings.ping_interval),
+        ship_timeout: Duration::from_secs(settings.ship_timeout),
+        active_eqs: RwLock::new(vec![]),
+        notif_ch: broadcast::channel(16).0,
+        settings_path: settings.path,
     });
     start_discovery_loop(15000).await?;
     tokio::spawn(make_keys(ms_data.clone()));
+    tokio::spawn(ship_watchdog(ms_data.clone()));
+    if let Some(path) = settings.data_path {
+        tokio::spawn(data_watcher(ms_data.clone(), path));
+    }
+    tokio::spawn(emergency::eq_scheduler(
+        ms_data.clone(),
+        settings.emergency_quests,
+    ));
+    for announcement in settings.announcements {
+        tokio::spawn(announcements::run_scheduled(ms_data.clone(), announcement));
+    }
+    if let Some(token) = settings.admin_token {
+        tokio::spawn(admin::run(ms_data.clone(), settings.admin_address, token));
+    }
     make_query(ms_data.clone()).await?;
     make_block_balance(ms_data.clone()).await?;
     ship_receiver(ms_data).await?;
@@ -283,6 +453,11 @@ async fn ship_receiver(ms_data: Arc<MSData>) -> Result<(), Error> {
                     return;
                 }
             };
+            log::info!(
+                "Ship connected using protocol version {} (capabilities: {:#x})",
+                conn.protocol_version(),
+                conn.capabilities().bits()
+            );
             connection_handler(conn, ms_data).await
         });
     }
@@ -293,50 +468,149 @@ async fn connection_handler(conn: ShipConnection, ms_data: Arc<MSData>) {
         conn,
         ms_data,
         authed: false,
-        pings: 0,
+        last_seen: Instant::now(),
+        conn_id: NEXT_CONN_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        ship_id: None,
+        data_version: None,
     };
+    let mut notifs = ship.ms_data.notif_ch.subscribe();
     loop {
-        match ship.conn.read_for(Duration::from_secs(5 * 60)).await {
-            Ok(d) => match run_action(&mut ship, d).await {
-                Ok(a) => match ship.conn.write(a).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::warn!("Write error: {e}");
-                        break;
+        let ping_interval = ship.ms_data.ping_interval;
+        let is_registered = ship.ship_id.is_some();
+        tokio::select! {
+            result = ship.conn.read_for(ping_interval) => match result {
+                Ok(d) => match run_action(&mut ship, d).await {
+                    Ok(a) => {
+                        if let Err(e) = ship.conn.write(a).await {
+                            log::warn!("Write error: {e}");
+                            break;
+                        }
+                        // bring newly registered ship up to date
+                        if !is_registered
+                            && ship.ship_id.is_some()
+                            && let Err(e) = send_active_notifs(&mut ship).await
+                        {
+                            log::warn!("Write error: {e}");
+                            break;
+                        }
                     }
+                    Err(e) => log::warn!("Action error: {e}"),
                 },
-                Err(e) => log::warn!("Action error: {e}"),
+                Err(data_structs::Error::IOError(e))
+                    if e.kind() == io::ErrorKind::ConnectionAborted =>
+                {
+                    log::info!("Ship disconnected");
+                    break;
+                }
+                Err(data_structs::Error::Timeout) => {
+                    if ship.last_seen.elapsed() >= ship.ms_data.ship_timeout {
+                        log::warn!("Ship connection timed out");
+                        break;
+                    }
+                    let _ = ship
+                        .conn
+                        .write(MasterShipComm {
+                            id: 0,
+                            action: MasterShipAction::Ping,
+                        })
+                        .await;
+                }
+                Err(e) => {
+                    log::warn!("Read error: {e}");
+                    break;
+                }
             },
-            Err(data_structs::Error::IOError(e))
-                if e.kind() == io::ErrorKind::ConnectionAborted =>
-            {
-                log::info!("Ship disconnected");
-                break;
-            }
-            Err(data_structs::Error::Timeout) => {
-                if ship.pings > 5 {
+            Ok(action) = notifs.recv(), if is_registered => {
+                if let Err(e) = send_notif(&mut ship, action).await {
+                    log::warn!("Write error: {e}");
                     break;
                 }
-                ship.pings += 1;
-                let _ = ship
-                    .conn
-                    .write(MasterShipComm {
-                        id: 0,
-                        action: MasterShipAction::Ping,
-                    })
-                    .await;
-            }
-            Err(e) => {
-                log::warn!("Read error: {e}");
-                return;
             }
         }
     }
-    let Ok(ip) = ship.conn.get_ip() else { return };
-    let IpAddr::V4(ip) = ip else { return };
+    let Some(ship_id) = ship.ship_id else { return };
+    let conn_id = ship.conn_id;
+    let mut lock = async_write(&ship.ms_data.ships).await;
+    if let Some(entry) = lock
+        .iter_mut()
+        .find(|s| s.info.id == ship_id && s.conn_id == conn_id)
+    {
+        // give the ship some time to reconnect before dropping the registration
+        log::info!(
+            "Ship {ship_id} lost connection, keeping it registered for {} seconds",
+            ship.ms_data.grace_period.as_secs()
+        );
+        entry.disconnected_at = Some(Instant::now());
+    }
+}
+
+/// Sends currently active notifications (e.g. running EQs) to the ship.
+async fn send_active_notifs(ship: &mut Ship) -> Result<(), Error> {
+    // data might have changed while the ship was disconnected
+    let version = ship
+        .ms_data
+        .srv_data
+        .read()
+        .as_ref()
+        .map(|d| d.info.version.clone());
+    if let Some(version) = version
+        && ship.data_version.as_ref().is_some_and(|v| *v != version)
+    {
+        send_notif(ship, MasterShipAction::ServerDataChanged(version)).await?;
+    }
+    let eqs = ship.ms_data.active_eqs.read().clone();
+    for eq in eqs {
+        send_notif(ship, MasterShipAction::EmergencyQuest(eq)).await?;
+    }
+    Ok(())
+}
+
+/// Sends a notification to the ship if it supports it.
+async fn send_notif(ship: &mut Ship, action: MasterShipAction) -> Result<(), Error> {
+    if !ship
+        .conn
+        .capabilities()
+        .contains(action.required_capabilities())
+    {
+        return Ok(());
+    }
+    ship.conn.write(MasterShipComm { id: 0, action }).await?;
+    Ok(())
+}
+
+/// Periodically removes ships that either didn't reconnect in time or stopped responding.
+async fn ship_watchdog(ms_data: Arc<MSData>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        let mut lock = async_write(&ms_data.ships).await;
+        lock.retain(|ship| {
+            let (id, name) = (ship.info.id, &ship.info.name);
+            match ship.disconnected_at {
+                Some(time) if time.elapsed() >= ms_data.grace_period => {
+                    log::info!("Ship {id} ({name}) is down: didn't reconnect in time");
+                    false
+                }
+                None if ship.last_seen.elapsed() >= ms_data.ship_timeout => {
+                    log::info!("Ship {id} ({name}) is down: stopped responding");
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+}
+
+/// Updates heartbeat data of the ship registered by this connection.
+async fn update_last_seen(ship: &mut Ship) {
+    ship.last_seen = Instant::now();
+    let Some(ship_id) = ship.ship_id else { return };
     let mut lock = async_write(&ship.ms_data.ships).await;
-    if let Some((i, _)) = lock.iter().enumerate().find(|(_, s)| s.ip == ip) {
-        lock.swap_remove(i);
+    if let Some(entry) = lock
+        .iter_mut()
+        .find(|s| s.info.id == ship_id && s.conn_id == ship.conn_id)
+    {
+        entry.last_seen = ship.last_seen;
     }
 }
 
@@ -345,9 +619,9 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
         id: action.id,
         action: MasterShipAction::Ok,
     };
+    update_last_seen(ship).await;
     let sql = &ship.ms_data.sql;
     let ships = &ship.ms_data.ships;
-    ship.pings = 0;
     match action.action {
         MasterShipAction::ShipLogin(psk) if !ship.authed => {
             let psk = psk.psk;
@@ -372,23 +646,52 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
         _ if !ship.authed => {
             response.action = MasterShipAction::Error(String::from("Unauthenticated"));
         }
-        MasterShipAction::RegisterShip(ship) => {
+        MasterShipAction::RegisterShip(info) => {
             let mut lock = async_write(ships).await;
-            for known_ship in lock.iter() {
-                if known_ship.id == ship.id {
+            let id = info.id;
+            let entry = RegisteredShip {
+                info,
+                conn_id: ship.conn_id,
+                disconnected_at: None,
+                last_seen: Instant::now(),
+                data_version: ship.data_version.clone(),
+            };
+            match lock.iter_mut().find(|s| s.info.id == id) {
+                // ship reconnected (possibly before we noticed the old connection dying)
+                Some(known_ship)
+                    if known_ship.disconnected_at.is_some()
+                        || known_ship.info.ip == entry.info.ip =>
+                {
+                    log::info!("Ship {id} reconnected");
+                    let data_version = known_ship.data_version.take();
+                    *known_ship = entry;
+                    known_ship.data_version = known_ship.data_version.take().or(data_version);
+                }
+                Some(_) => {
                     response.action =
                         MasterShipAction::RegisterShipResult(RegisterShipResult::AlreadyTaken);
                     return Ok(response);
                 }
+                None => {
+                    log::info!("Ship {id} ({}) is up", entry.info.name);
+                    lock.push(entry);
+                }
             }
-            lock.push(ship);
+            ship.ship_id = Some(id);
             response.action = MasterShipAction::RegisterShipResult(RegisterShipResult::Success);
         }
         MasterShipAction::RegisterShipResult(_) => {}
         MasterShipAction::UnregisterShip(id) => {
             let mut lock = async_write(ships).await;
-            if let Some(pos) = lock.iter().enumerate().find(|x| x.1.id == id).map(|x| x.0) {
-                lock.swap_remove(pos);
+            if let Some(pos) = lock
+                .iter()
+                .position(|x| x.info.id == id && x.conn_id == ship.conn_id)
+            {
+                let removed = lock.swap_remove(pos);
+                log::info!("Ship {id} ({}) is down: unregistered", removed.info.name);
+            }
+            if ship.ship_id == Some(id) {
+                ship.ship_id = None;
             }
         }
         MasterShipAction::Ok => {}
@@ -410,6 +713,9 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
                 Err(ref e) if matches!(e, Error::NoUser) => {
                     response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
                 }
+                Err(Error::UserBanned(id)) => {
+                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Banned(id))
+                }
                 Err(Error::InvalidPassword(id)) => {
                     response.action =
                         MasterShipAction::UserLoginResult(UserLoginResult::InvalidPassword(id))
@@ -445,6 +751,9 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
                 Err(ref e) if matches!(e, Error::NoUser) => {
                     response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
                 }
+                Err(Error::UserBanned(id)) => {
+                    response.action = MasterShipAction::UserLoginResult(UserLoginResult::Banned(id))
+                }
                 Err(e) => response.action = MasterShipAction::Error(e.to_string()),
             }
         }
@@ -511,6 +820,9 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
             Err(ref e) if matches!(e, Error::NoUser) => {
                 response.action = MasterShipAction::UserLoginResult(UserLoginResult::NotFound)
             }
+            Err(Error::UserBanned(id)) => {
+                response.action = MasterShipAction::UserLoginResult(UserLoginResult::Banned(id))
+            }
             Err(e) => response.action = MasterShipAction::Error(e.to_string()),
         },
         MasterShipAction::GetUserInfo(id) => match sql.get_user_info(id).await {
@@ -556,9 +868,10 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
             response.action = MasterShipAction::Ok;
         }
         MasterShipAction::ServerDataRequest => {
-            if let Some(data) = ship.ms_data.srv_data.as_ref() {
+            let data = ship.ms_data.srv_data.read().clone();
+            if let Some(data) = data {
                 response.action = MasterShipAction::ServerDataResponse(ServerDataResult::Ok(
-                    Box::new(data.clone()),
+                    Box::new(data.data.clone()),
                 ));
             } else {
                 response.action =
@@ -566,7 +879,90 @@ async fn run_action(ship: &mut Ship, action: MasterShipComm) -> Result<MasterShi
             }
         }
         MasterShipAction::ServerDataResponse(_) => {}
+        MasterShipAction::ServerDataChanged(_) => {}
+        MasterShipAction::ServerDataInfoRequest => {
+            let data = ship.ms_data.srv_data.read().clone();
+            response.action = MasterShipAction::ServerDataInfo(data.map(|d| d.info.clone()));
+        }
+        MasterShipAction::ServerDataInfo(_) => {}
+        MasterShipAction::ServerDataChunkRequest { version, index } => {
+            let data = ship.ms_data.srv_data.read().clone();
+            let chunk = data
+                .as_ref()
+                .filter(|d| d.info.version == version)
+                .and_then(|d| {
+                    d.compressed
+                        .chunks(DATA_CHUNK_SIZE)
+                        .nth(index as usize)
+                        .map(|c| c.to_vec())
+                });
+            response.action = match chunk {
+                Some(data) => MasterShipAction::ServerDataChunk(ServerDataChunk { index, data }),
+                None => MasterShipAction::Error(format!(
+                    "Server data chunk {index} of version {version} is not available"
+                )),
+            };
+        }
+        MasterShipAction::ServerDataChunk(_) => {}
+        MasterShipAction::ReportDataVersion(version) => {
+            let current = ship
+                .ms_data
+                .srv_data
+                .read()
+                .as_ref()
+                .map(|d| d.info.version.clone());
+            let outdated = if current.is_some_and(|c| c != version) {
+                " (outdated)"
+            } else {
+                ""
+            };
+            log::info!(
+                "Ship connection {} is running server data version {version}{outdated}",
+                ship.conn_id
+            );
+            if let Some(id) = ship.ship_id
+                && let Some(entry) = async_write(ships)
+                    .await
+                    .iter_mut()
+                    .find(|s| s.info.id == id && s.conn_id == ship.conn_id)
+            {
+                entry.data_version = Some(version.clone());
+            }
+            ship.data_version = Some(version);
+            response.action = MasterShipAction::Ok;
+        }
         MasterShipAction::Pong => {}
+        MasterShipAction::EmergencyQuest(_) => {}
+        MasterShipAction::Announcement(announcement) => {
+            announcements::broadcast(&ship.ms_data, announcement);
+        }
+        MasterShipAction::PutQuestRecord(mut record) => match ship.ship_id {
+            Some(ship_id) => {
+                record.ship_id = ship_id;
+                match sql.put_quest_record(&record).await {
+                    Ok(_) => response.action = MasterShipAction::Ok,
+                    Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+                }
+            }
+            // records are grouped by ships
+            None => response.action = MasterShipAction::Error(Error::InvalidAction.to_string()),
+        },
+        MasterShipAction::GetQuestRecords {
+            quest_id,
+            difficulty,
+        } => match sql
+            .get_quest_records(quest_id, difficulty, QUEST_RECORDS_LIMIT)
+            .await
+        {
+            Ok(records) => response.action = MasterShipAction::QuestRecords(records),
+            Err(e) => response.action = MasterShipAction::Error(e.to_string()),
+        },
+        MasterShipAction::QuestRecords(_) => {}
+    }
+    if let MasterShipAction::UserLoginResult(UserLoginResult::Banned(_)) = response.action
+        && !ship.conn.capabilities().contains(Capabilities::USER_BANS)
+    {
+        response.action = MasterShipAction::Error(String::from("User is banned"));
     }
     Ok(response)
 }
@@ -625,7 +1021,7 @@ async fn send_query(stream: TcpStream, servers: Arc<MSData>) -> Result<(), Error
         PublicKey::None,
     );
     let mut ships = vec![];
-    for server in servers.ships.read().iter() {
+    for server in servers.ships.read().iter().map(|s| &s.info) {
         ships.push(login::ShipEntry {
             id: server.id * 1000,
             name: format!("Ship{:02}", server.id).into(),
@@ -695,7 +1091,7 @@ async fn send_block_balance(stream: TcpStream, servers: Arc<MSData>) -> Result<(
         PublicKey::None,
     );
     let servers = servers.ships.read();
-    let Some(server) = servers.iter().find(|x| x.id == id) else {
+    let Some(server) = servers.iter().map(|s| &s.info).find(|x| x.id == id) else {
         con.write_packet_async(&Packet::LoginResponse(login::LoginResponsePacket {
             status: login::LoginStatus::Failure,
             error: "Server is offline".to_string(),
@@ -732,7 +1128,7 @@ async fn send_keys(mut stream: TcpStream, servers: Arc<MSData>) -> Result<(), Er
     };
     let lock = servers.ships.read();
     let mut data = vec![];
-    for ship in lock.iter() {
+    for ship in lock.iter().map(|s| &s.info) {
         let mut key = vec![0x06, 0x02, 0x00, 0x00, 0x00, 0xA4, 0x00, 0x00];
         key.append(&mut b"RSA1".to_vec());
         key.append(&mut (ship.key.n.len() as u32 * 8).to_le_bytes().to_vec());
@@ -751,7 +1147,7 @@ async fn send_keys(mut stream: TcpStream, servers: Arc<MSData>) -> Result<(), Er
     Ok(())
 }
 
-async fn async_write<T>(mutex: &RwLock<T>) -> RwLockWriteGuard<T>
+async fn async_write<T>(mutex: &RwLock<T>) -> RwLockWriteGuard<'_, T>
 where
     T: Send + Sync,
 {
@@ -813,3 +1209,35 @@ fn get_addr(remote_ip: Ipv4Addr, local_ip: Ipv4Addr, ship_ip: Ipv4Addr) -> Resul
 
     Ok(send_ip)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::Settings;
+
+    #[tokio::test]
+    async fn test_save_registration() {
+        let dir = std::env::temp_dir().join(format!("master_settings_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("master_ship.toml");
+        let path = path.to_str().unwrap();
+        let settings = "# Location of the SQLite database\ndb_name = \"test.db\"\n\n\
+            # Is auto registration enabled?\nregistration_enabled = false\n";
+        std::fs::write(path, settings).unwrap();
+
+        Settings::save_registration_enabled(path, true)
+            .await
+            .expect("Saving settings failed");
+        let saved = std::fs::read_to_string(path).unwrap();
+        assert_eq!(saved, settings.replace("= false", "= true"));
+        let loaded: Settings = toml::from_str(&saved).unwrap();
+        assert!(loaded.registration_enabled);
+        assert_eq!(loaded.db_name, "test.db");
+
+        Settings::save_registration_enabled(path, false)
+            .await
+            .expect("Saving settings failed");
+        let loaded: Settings = toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert!(!loaded.registration_enabled);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
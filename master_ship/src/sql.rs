@@ -1,6 +1,6 @@
 use crate::Error;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
-use data_structs::{flags::Flags, inventory::AccountStorages};
+use data_structs::{flags::Flags, inventory::AccountStorages, master_ship::QuestRecord};
 use pso2packetlib::{
     AsciiString,
     protocol::login::{LoginAttempt, LoginResult, UserInfoPacket},
@@ -11,12 +11,25 @@ use std::{
     net::Ipv4Addr,
     ops::Add,
     str::from_utf8,
+    sync::atomic::{AtomicBool, Ordering},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// Best clear time of every character per quest and difficulty.
+const QUEST_RECORDS_TABLE: &str = "
+    create table if not exists QuestRecords (
+        ShipId integer,
+        CharId integer,
+        QuestId integer,
+        Difficulty integer,
+        Time integer,
+        Data blob
+    );
+";
+
 pub struct Sql {
     connection: sqlx::SqlitePool,
-    registration_enabled: bool,
+    registration_enabled: AtomicBool,
 }
 
 #[derive(PartialEq, Debug)]
@@ -29,6 +42,16 @@ pub struct User {
     pub last_uuid: u64,
 }
 
+/// Short user description for the admin interface.
+pub struct UserSummary {
+    pub id: u32,
+    pub username: String,
+    pub psn_username: String,
+    pub nickname: String,
+    pub isgm: bool,
+    pub banned: bool,
+}
+
 #[derive(Default, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 struct UserData {
@@ -39,6 +62,7 @@ struct UserData {
     flags: Flags,
     isgm: bool,
     last_uuid: u64,
+    banned: bool,
 }
 
 impl Sql {
@@ -47,9 +71,11 @@ impl Sql {
             return Self::create_db(path, reg_enabled).await;
         }
         let conn = sqlx::SqlitePool::connect(path).await?;
+        // added after the initial schema
+        conn.execute(QUEST_RECORDS_TABLE).await?;
         Ok(Self {
             connection: conn,
-            registration_enabled: reg_enabled,
+            registration_enabled: reg_enabled.into(),
         })
     }
     async fn create_db(path: &str, reg_enabled: bool) -> Result<Self, Error> {
@@ -97,9 +123,10 @@ impl Sql {
         ",
         )
         .await?;
+        conn.execute(QUEST_RECORDS_TABLE).await?;
         Ok(Self {
             connection: conn,
-            registration_enabled: reg_enabled,
+            registration_enabled: reg_enabled.into(),
         })
     }
     pub async fn get_sega_user(
@@ -142,8 +169,12 @@ impl Sql {
                         return Err(e);
                     }
                 }
-                self.put_login(id, ip, LoginResult::Successful).await?;
                 let user_data: UserData = rmp_serde::from_slice(data.try_get("Data")?)?;
+                if user_data.banned {
+                    self.put_login(id, ip, LoginResult::LoginError).await?;
+                    return Err(Error::UserBanned(id));
+                }
+                self.put_login(id, ip, LoginResult::Successful).await?;
                 Ok(User {
                     id,
                     nickname: user_data.nickname,
@@ -229,6 +260,9 @@ impl Sql {
                 .fetch_one(&self.connection)
                 .await?;
             let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+            if user_data.banned {
+                return Err(Error::UserBanned(user_id));
+            }
             return Ok(User {
                 id: user_id,
                 nickname: user_data.nickname,
@@ -251,6 +285,10 @@ impl Sql {
             Some(data) => {
                 let id = data.try_get::<i64, _>("Id")? as u32;
                 let user_data: UserData = rmp_serde::from_slice(data.try_get("Data")?)?;
+                if user_data.banned {
+                    self.put_login(id, ip, LoginResult::LoginError).await?;
+                    return Err(Error::UserBanned(id));
+                }
                 self.put_login(id, ip, LoginResult::Successful).await?;
                 Ok(User {
                     id,
@@ -291,19 +329,7 @@ impl Sql {
         })
     }
     pub async fn create_sega_user(&self, username: &str, password: &str) -> Result<User, Error> {
-        // SAFETY: references do not outlive the scope because the thread is immediately
-        // joined
-        let password: &'static str = unsafe { std::mem::transmute(password) };
-        let hash = tokio::task::spawn_blocking(|| {
-            let salt = SaltString::generate(&mut OsRng);
-            let argon2 = Argon2::default();
-            match argon2.hash_password(password.as_bytes(), &salt) {
-                Ok(x) => Ok(x.to_string()),
-                Err(_) => Err(Error::HashError),
-            }
-        })
-        .await
-        .unwrap()?;
+        let hash = hash_password(password).await?;
 
         let mut transaction = self.connection.begin().await?;
         let user_data = UserData {
@@ -406,7 +432,67 @@ impl Sql {
         Ok(count != 0)
     }
     pub fn registration_enabled(&self) -> bool {
-        self.registration_enabled
+        self.registration_enabled.load(Ordering::Relaxed)
+    }
+    pub fn set_registration_enabled(&self, enabled: bool) {
+        self.registration_enabled.store(enabled, Ordering::Relaxed)
+    }
+    pub async fn get_ship_psks(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let rows = sqlx::query("select PSK from Ships")
+            .fetch_all(&self.connection)
+            .await?;
+        let mut psks = vec![];
+        for row in rows {
+            psks.push(row.try_get("PSK")?);
+        }
+        Ok(psks)
+    }
+    /// Returns `false` if the PSK wasn't known.
+    pub async fn delete_ship_data(&self, psk: &[u8]) -> Result<bool, Error> {
+        let result = sqlx::query("delete from Ships where PSK = ?")
+            .bind(psk)
+            .execute(&self.connection)
+            .await?;
+        Ok(result.rows_affected() != 0)
+    }
+    pub async fn get_users(&self) -> Result<Vec<UserSummary>, Error> {
+        let rows = sqlx::query("select * from Users order by Id")
+            .fetch_all(&self.connection)
+            .await?;
+        let mut users = vec![];
+        for row in rows {
+            let user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
+            users.push(UserSummary {
+                id: row.try_get::<i64, _>("Id")? as u32,
+                username: String::from_utf8_lossy(row.try_get("Username")?).into_owned(),
+                psn_username: String::from_utf8_lossy(row.try_get("PSNUsername")?).into_owned(),
+                nickname: user_data.nickname,
+                isgm: user_data.isgm,
+                banned: user_data.banned,
+            });
+        }
+        Ok(users)
+    }
+    pub async fn set_banned(&self, user_id: u32, banned: bool) -> Result<(), Error> {
+        self.update_userdata(user_id, |user_data| user_data.banned = banned)
+            .await
+    }
+    /// Sets a new password for a SEGA ID user.
+    pub async fn set_password(&self, user_id: u32, password: &str) -> Result<(), Error> {
+        if password.is_empty() {
+            return Err(Error::InvalidData);
+        }
+        let hash = hash_password(password).await?;
+        let result = sqlx::query("update Users set Password = ? where Id = ? and Username != ?")
+            .bind(hash.as_bytes())
+            .bind(user_id as i64)
+            .bind(&b""[..])
+            .execute(&self.connection)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(Error::NoUser);
+        }
+        Ok(())
     }
     pub async fn put_ship_data(&self, psk: &[u8]) -> Result<(), Error> {
         sqlx::query("insert into Ships (PSK) values (?)")
@@ -432,15 +518,80 @@ impl Sql {
         Ok(true)
     }
 
+    /// Saves the record if it's the best time of the character. Returns `true` if it was saved.
+    pub async fn put_quest_record(&self, record: &QuestRecord) -> Result<bool, Error> {
+        let mut transaction = self.connection.begin().await?;
+        let best = sqlx::query(
+            "select Time from QuestRecords where ShipId = ? and CharId = ? and QuestId = ? and Difficulty = ?",
+        )
+        .bind(record.ship_id as i64)
+        .bind(record.char_id as i64)
+        .bind(record.quest_id as i64)
+        .bind(record.difficulty as i64)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(row) = best
+            && row.try_get::<i64, _>("Time")? as u64 <= record.time
+        {
+            return Ok(false);
+        }
+        sqlx::query(
+            "delete from QuestRecords where ShipId = ? and CharId = ? and QuestId = ? and Difficulty = ?",
+        )
+        .bind(record.ship_id as i64)
+        .bind(record.char_id as i64)
+        .bind(record.quest_id as i64)
+        .bind(record.difficulty as i64)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            "insert into QuestRecords (ShipId, CharId, QuestId, Difficulty, Time, Data) values (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(record.ship_id as i64)
+        .bind(record.char_id as i64)
+        .bind(record.quest_id as i64)
+        .bind(record.difficulty as i64)
+        .bind(record.time as i64)
+        .bind(rmp_serde::to_vec(record)?)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+    /// Returns the best clear times of a quest on all ships, fastest first.
+    pub async fn get_quest_records(
+        &self,
+        quest_id: u32,
+        difficulty: u16,
+        limit: u32,
+    ) -> Result<Vec<QuestRecord>, Error> {
+        let rows = sqlx::query(
+            "select Data from QuestRecords where QuestId = ? and Difficulty = ? order by Time asc limit ?",
+        )
+        .bind(quest_id as i64)
+        .bind(difficulty as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.connection)
+        .await?;
+        let mut records = vec![];
+        for row in rows {
+            records.push(rmp_serde::from_slice(row.try_get("Data")?)?);
+        }
+        Ok(records)
+    }
+
     async fn update_userdata<F>(&self, user_id: u32, f: F) -> Result<(), Error>
     where
         F: FnOnce(&mut UserData) + Send,
     {
         let mut transaction = self.connection.begin().await?;
-        let row = sqlx::query("select Data from Users where Id = ?")
+        let Some(row) = sqlx::query("select Data from Users where Id = ?")
             .bind(user_id as i64)
-            .fetch_one(&mut *transaction)
-            .await?;
+            .fetch_optional(&mut *transaction)
+            .await?
+        else {
+            return Err(Error::NoUser);
+        };
         let mut user_data: UserData = rmp_serde::from_slice(row.try_get("Data")?)?;
         f(&mut user_data);
         sqlx::query("update Users set Data = ? where Id = ?")
@@ -453,10 +604,24 @@ impl Sql {
     }
 }
 
+async fn hash_password(password: &str) -> Result<String, Error> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::default();
+        match argon2.hash_password(password.as_bytes(), &salt) {
+            Ok(x) => Ok(x.to_string()),
+            Err(_) => Err(Error::HashError),
+        }
+    })
+    .await
+    .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::sql::Sql;
-    use data_structs::flags::Flags;
+    use crate::{Error, sql::Sql};
+    use data_structs::{flags::Flags, master_ship::QuestRecord};
     use pso2packetlib::{
         AsciiString,
         protocol::{
@@ -554,6 +719,42 @@ mod tests {
             .expect("Failed to read settings");
         assert_eq!(read_settings, settings);
 
+        db.set_banned(created_user.id, true)
+            .await
+            .expect("Failed to ban user");
+        let banned_login = db.get_sega_user(segaid, pass, Ipv4Addr::UNSPECIFIED).await;
+        assert!(matches!(banned_login, Err(Error::UserBanned(_))));
+        db.set_banned(created_user.id, false)
+            .await
+            .expect("Failed to unban user");
+        db.set_password(created_user.id, "new_password")
+            .await
+            .expect("Failed to reset password");
+        db.get_sega_user(segaid, "new_password", Ipv4Addr::UNSPECIFIED)
+            .await
+            .expect("Login with the new password failed");
+
+        let mut record = QuestRecord {
+            quest_id: 1,
+            char_id: 2,
+            time: 1000,
+            ..Default::default()
+        };
+        assert!(db.put_quest_record(&record).await.unwrap());
+        record.time = 2000;
+        assert!(!db.put_quest_record(&record).await.unwrap());
+        record.time = 500;
+        assert!(db.put_quest_record(&record).await.unwrap());
+        let other = QuestRecord {
+            quest_id: 1,
+            char_id: 3,
+            time: 700,
+            ..Default::default()
+        };
+        db.put_quest_record(&other).await.unwrap();
+        let records = db.get_quest_records(1, 0, 10).await.unwrap();
+        assert_eq!(records, vec![record, other]);
+
         let _ = std::fs::remove_file("test.db");
     }
 }
@@ -0,0 +1,104 @@
+use crate::{BlockData, Error, mutex::RwLock, send_to_ship};
+use data_structs::master_ship::{
+    Announcement, AnnouncementType, MasterShipAction, ScheduledAnnouncement,
+};
+use pso2packetlib::protocol::{
+    Packet,
+    unk19::{MessageType, SystemMessagePacket},
+};
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+/// Set of players that receive an announcement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastScope {
+    /// Players on the current block.
+    Block,
+    /// Players on every block of this ship.
+    Ship,
+    /// Players on every ship connected to the master ship.
+    Network,
+}
+
+impl FromStr for BroadcastScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "ship" => Ok(Self::Ship),
+            "all" | "network" => Ok(Self::Network),
+            _ => Err(format!(
+                "{{red}}Unknown scope: {s} (expected block, ship or all){{def}}"
+            )),
+        }
+    }
+}
+
+pub fn announcement_packet(announcement: &Announcement) -> Packet {
+    let msg_type = match announcement.kind {
+        AnnouncementType::SystemMessage => MessageType::SystemMessage,
+        AnnouncementType::Scrolling => MessageType::GoldenMessage,
+    };
+    Packet::SystemMessage(SystemMessagePacket {
+        message: announcement.message.clone(),
+        msg_type,
+        ..Default::default()
+    })
+}
+
+/// Sends an announcement from the specified block.
+///
+/// Caller must not hold a lock on any user of the ship.
+pub async fn broadcast(
+    block: &BlockData,
+    scope: BroadcastScope,
+    announcement: Announcement,
+) -> Result<(), Error> {
+    match scope {
+        BroadcastScope::Block => block.send_to_all(&announcement_packet(&announcement)).await,
+        BroadcastScope::Ship => {
+            send_to_ship(&block.block_list, &announcement_packet(&announcement)).await
+        }
+        // master ship will send it back to us
+        BroadcastScope::Network => match block
+            .sql
+            .run_action(MasterShipAction::Announcement(announcement))
+            .await?
+        {
+            MasterShipAction::Ok => {}
+            MasterShipAction::Error(e) => return Err(Error::MSError(e)),
+            _ => return Err(Error::MSUnexpected),
+        },
+    }
+    Ok(())
+}
+
+pub async fn run_scheduled(
+    block_list: Arc<RwLock<Vec<Arc<BlockData>>>>,
+    announcement: ScheduledAnnouncement,
+) {
+    let period = Duration::from_secs(announcement.interval.max(1) * 60);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let packet = announcement_packet(&Announcement {
+        message: announcement.message,
+        kind: announcement.kind,
+    });
+    loop {
+        interval.tick().await;
+        match announcement.block {
+            Some(id) => {
+                let block = block_list
+                    .read()
+                    .await
+                    .iter()
+                    .find(|b| b.block_id == id)
+                    .cloned();
+                match block {
+                    Some(block) => block.send_to_all(&packet).await,
+                    None => log::warn!("Scheduled announcement for unknown block {id}"),
+                }
+            }
+            None => send_to_ship(&block_list, &packet).await,
+        }
+    }
+}
@@ -1,17 +1,25 @@
 use crate::{Error, User};
-use data_structs::{ServerData, stats::EnemyHitbox};
+use data_structs::{
+    ServerData,
+    inventory::{ItemEffect, ItemUpgrades},
+    stats::EnemyHitbox,
+};
 use pso2packetlib::protocol::{
+    items::{ItemType, WeaponItem},
     models::{Position, character::Class},
     objects::{DamageReceivePacket, EnemyKilledPacket},
     playerstatus::DealDamagePacket,
     spawn::EnemySpawnPacket,
 };
 use rand::distributions::Distribution;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Default)]
 pub struct PlayerStats {
     max_hp: u32,
     hp: u32,
+    max_pp: u32,
+    pp: u32,
     dex: u32,
 
     base_mel_pwr: u32,
@@ -24,6 +32,15 @@ pub struct PlayerStats {
     base_mel_def: u32,
     base_rng_def: u32,
     base_tec_def: u32,
+
+    buff: Option<ActiveBuff>,
+}
+
+#[derive(Debug, Clone)]
+struct ActiveBuff {
+    attack_mul: f32,
+    defense_mul: f32,
+    expires: Instant,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -67,7 +84,7 @@ impl PlayerStats {
         let Some(char) = &user.character else {
             unreachable!("User should be in state >= `PreInGame`")
         };
-        let server_data = &user.get_blockdata().server_data;
+        let server_data = &user.get_blockdata().server_data();
 
         let char_data = &char.character;
         let class = char_data.classes.main_class as usize;
@@ -103,15 +120,42 @@ impl PlayerStats {
             resulting_stats.weapon_mel_pwr = weapon_stats.melee_dmg as _;
             resulting_stats.weapon_rng_pwr = weapon_stats.range_dmg as _;
             resulting_stats.weapon_tec_pwr = weapon_stats.gender_force_dmg.force_dmg as _;
+            if let ItemType::Weapon(data) = &equiped_item.data {
+                resulting_stats.apply_weapon_upgrades(data, &server_data.item_params.upgrades);
+            }
         }
         Ok(resulting_stats)
     }
+    /// Adds grind and special ability bonuses of the weapon.
+    fn apply_weapon_upgrades(&mut self, weapon: &WeaponItem, upgrades: &ItemUpgrades) {
+        let grind_power = upgrades.grind_power(weapon.grind);
+        for power in [
+            &mut self.weapon_mel_pwr,
+            &mut self.weapon_rng_pwr,
+            &mut self.weapon_tec_pwr,
+        ] {
+            if *power != 0 {
+                *power += grind_power;
+            }
+        }
+        for affix in weapon
+            .affixes
+            .iter()
+            .filter_map(|id| upgrades.get_affix(*id))
+        {
+            self.weapon_mel_pwr = self.weapon_mel_pwr.saturating_add_signed(affix.mel_pwr);
+            self.weapon_rng_pwr = self.weapon_rng_pwr.saturating_add_signed(affix.rng_pwr);
+            self.weapon_tec_pwr = self.weapon_tec_pwr.saturating_add_signed(affix.tec_pwr);
+            self.max_hp = self.max_hp.saturating_add_signed(affix.hp).max(1);
+        }
+        self.hp = self.max_hp;
+    }
     fn calculate_class_stats(user: &User, class: usize, level: usize) -> Self {
         let Some(char) = &user.character else {
             unreachable!("User should be in state >= `PreInGame`")
         };
         let mut resulting_stats = Self::default();
-        let player_stats = &user.get_blockdata().server_data.player_stats;
+        let player_stats = &user.get_blockdata().server_data().player_stats;
 
         let stats = &player_stats.stats[class][level - 1];
 
@@ -121,6 +165,8 @@ impl PlayerStats {
 
         resulting_stats.hp = (stats.hp + (stats.hp * 0.01 * modifiers.hp as f32).floor()) as _;
         resulting_stats.max_hp = resulting_stats.hp;
+        resulting_stats.pp = stats.pp as _;
+        resulting_stats.max_pp = resulting_stats.pp;
         resulting_stats.dex = (stats.dex + (stats.dex * 0.01 * modifiers.dex as f32).floor()) as _;
         resulting_stats.base_mel_pwr =
             (stats.mel_pow + (stats.mel_pow * 0.01 * modifiers.mel_pow as f32).floor()) as _;
@@ -138,9 +184,12 @@ impl PlayerStats {
         resulting_stats
     }
     pub fn update(player: &mut User) -> Result<(), Error> {
-        let old_hp = player.get_stats().hp;
+        let old_stats = player.get_stats();
+        let (old_hp, old_pp, buff) = (old_stats.hp, old_stats.pp, old_stats.buff.clone());
         let mut new_stats = Self::build(player)?;
-        new_stats.hp = old_hp;
+        new_stats.hp = old_hp.min(new_stats.max_hp);
+        new_stats.pp = old_pp.min(new_stats.max_pp);
+        new_stats.buff = buff;
         *player.get_stats_mut() = new_stats;
 
         Ok(())
@@ -148,6 +197,53 @@ impl PlayerStats {
     pub const fn get_hp(&self) -> (u32, u32) {
         (self.hp, self.max_hp)
     }
+    pub const fn get_pp(&self) -> (u32, u32) {
+        (self.pp, self.max_pp)
+    }
+    pub const fn is_incapacitated(&self) -> bool {
+        self.hp == 0
+    }
+    /// Checks if the consumable item would change anything for the player. Incapacitated
+    /// players are only affected by revival items.
+    pub fn is_affected_by(&self, effect: &ItemEffect) -> bool {
+        if self.is_incapacitated() {
+            return effect.revive;
+        }
+        (effect.hp_percent > 0.0 && self.hp < self.max_hp)
+            || (effect.pp != 0 && self.pp < self.max_pp)
+            || effect.buff.is_some()
+    }
+    /// Applies the effect of a consumable item.
+    ///
+    /// Returns whether the player was affected.
+    pub fn apply_effect(&mut self, effect: &ItemEffect) -> bool {
+        if !self.is_affected_by(effect) {
+            return false;
+        }
+        if self.is_incapacitated() {
+            self.hp = 1;
+        }
+        let heal = (self.max_hp as f32 * effect.hp_percent / 100.0).ceil() as u32;
+        self.hp = self.hp.saturating_add(heal).min(self.max_hp);
+        self.pp = self.pp.saturating_add(effect.pp).min(self.max_pp);
+        if let Some(buff) = &effect.buff {
+            self.buff = Some(ActiveBuff {
+                attack_mul: buff.attack_mul,
+                defense_mul: buff.defense_mul,
+                expires: Instant::now() + Duration::from_secs(buff.duration as u64),
+            });
+        }
+        true
+    }
+    fn active_buff(&self) -> Option<&ActiveBuff> {
+        self.buff.as_ref().filter(|b| b.expires > Instant::now())
+    }
+    fn attack_mul(&self) -> f32 {
+        self.active_buff().map_or(1.0, |b| b.attack_mul)
+    }
+    fn defense_mul(&self) -> f32 {
+        self.active_buff().map_or(1.0, |b| b.defense_mul)
+    }
     pub fn damage_enemy(
         &mut self,
         enemy: &mut EnemyStats,
@@ -189,7 +285,7 @@ impl PlayerStats {
             data_structs::stats::AttackType::Rng => enemy.rng_def,
             data_structs::stats::AttackType::Tec => enemy.tec_def,
         };
-        let total_mul = 1.0 * hitbox.damage_mul;
+        let total_mul = self.attack_mul() * hitbox.damage_mul;
         let min_pure_attack =
             (base_pwr as f32 + weapon_pwr as f32 * 0.9 - def as f32).clamp(1.0, f32::MAX);
         let pure_attack = (base_pwr + weapon_pwr)
@@ -270,6 +366,11 @@ impl EnemyStats {
             .get(name)
             .ok_or(Error::NoEnemyData(name.to_string()))?;
         resulting_stats.hitboxes.clone_from(&enemy_stats.hitboxes);
+        let max_level = base_stats.levels.len().min(enemy_stats.levels.len()) as u32;
+        if max_level == 0 {
+            return Err(Error::NoEnemyData(name.to_string()));
+        }
+        let level = level.clamp(1, max_level);
         let base_level_stats = &base_stats.levels[level as usize - 1];
         let level_stats = &enemy_stats.levels[level as usize - 1];
 
@@ -296,6 +397,17 @@ impl EnemyStats {
 
         Ok(resulting_stats)
     }
+    /// Multiplies max HP of the enemy and restores its HP.
+    pub fn scale_hp(&mut self, multiplier: f32) {
+        self.max_hp = ((self.max_hp as f32 * multiplier).floor() as u32).max(1);
+        self.hp = self.max_hp;
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub const fn get_position(&self) -> Position {
+        self.pos
+    }
     pub fn create_spawn_packet(&self, id: u32) -> EnemySpawnPacket {
         EnemySpawnPacket {
             object: pso2packetlib::protocol::ObjectHeader {
@@ -345,6 +457,7 @@ impl EnemyStats {
             data_structs::stats::AttackType::Rng => player.base_rng_def,
             data_structs::stats::AttackType::Tec => player.base_tec_def,
         };
+        let def = (def as f32 * player.defense_mul()) as u32;
         let total_mul = 1.0;
         let min_pure_attack = min_pwr.saturating_sub(def).clamp(1, u32::MAX) as f32;
         let pure_attack = max_pwr.saturating_sub(def).clamp(1, u32::MAX) as f32;
@@ -408,3 +521,52 @@ impl EnemyStats {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerStats;
+    use data_structs::inventory::{BuffData, ItemEffect};
+
+    #[test]
+    fn item_effects() {
+        let mut stats = PlayerStats {
+            max_hp: 100,
+            hp: 50,
+            max_pp: 100,
+            pp: 0,
+            ..Default::default()
+        };
+        let mate = ItemEffect {
+            hp_percent: 30.0,
+            ..Default::default()
+        };
+        assert!(stats.apply_effect(&mate));
+        assert!(stats.apply_effect(&mate));
+        assert_eq!(stats.get_hp(), (100, 100));
+        // healing does nothing at full HP
+        assert!(!stats.is_affected_by(&mate));
+        assert!(!stats.apply_effect(&mate));
+
+        let atomizer = ItemEffect {
+            hp_percent: 50.0,
+            pp: 20,
+            revive: true,
+            buff: Some(BuffData {
+                attack_mul: 1.1,
+                duration: 60,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        stats.hp = 0;
+        // incapacitated players can only be revived
+        assert!(!stats.is_affected_by(&mate));
+        assert!(!stats.apply_effect(&mate));
+        assert!(stats.is_affected_by(&atomizer));
+        assert!(stats.apply_effect(&atomizer));
+        assert_eq!(stats.get_hp(), (51, 100));
+        assert_eq!(stats.get_pp(), (20, 100));
+        assert_eq!(stats.attack_mul(), 1.1);
+        assert_eq!(stats.defense_mul(), 1.0);
+    }
+}
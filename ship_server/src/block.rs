@@ -2,12 +2,15 @@ use crate::{
     Action, BlockData, BlockInfo, Error, map,
     mutex::{Mutex, RwLock},
     sql,
-    user::User,
+    user::{User, UserState},
 };
 use pso2packetlib::{PrivateKey, connection::ConnectionError};
 use std::{
     io,
-    sync::{Arc, atomic::AtomicU32},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
 };
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -16,6 +19,7 @@ use tokio::{
 
 pub async fn init_block(
     blocks: Arc<RwLock<Vec<BlockInfo>>>,
+    block_list: Arc<RwLock<Vec<Arc<BlockData>>>>,
     this_block: BlockInfo,
     sql: Arc<sql::Sql>,
     key: PrivateKey,
@@ -24,7 +28,8 @@ pub async fn init_block(
 
     let latest_mapid = AtomicU32::new(1);
 
-    let Some(lobby) = this_block.server_data.maps.get(&this_block.lobby_map) else {
+    let server_data = this_block.data.server_data();
+    let Some(lobby) = server_data.maps.get(&this_block.lobby_map) else {
         return Err(Error::NoMapFound(this_block.lobby_map.clone()));
     };
 
@@ -37,21 +42,26 @@ pub async fn init_block(
     let block_data = Arc::new(BlockData {
         sql,
         blocks,
+        block_list: block_list.clone(),
         block_id: this_block.id,
         block_name: this_block.name,
         lobby,
         key,
         latest_mapid,
         latest_partyid: AtomicU32::new(1),
-        server_data: this_block.server_data,
-        quests: this_block.quests,
+        data: this_block.data,
+        scripts: this_block.scripts,
         clients: Mutex::new(vec![]),
+        client_count: AtomicUsize::new(0),
+        quest_instances: Default::default(),
     });
     // we are the only owner of the map, so this never blocks
     block_data
         .lobby
         .lock_blocking()
         .set_block_data(block_data.clone());
+    map::Map::start_timers(&block_data.lobby);
+    block_list.write().await.push(block_data.clone());
 
     let mut conn_id = 0usize;
     let (send, mut recv) = mpsc::channel(10);
@@ -104,6 +114,7 @@ async fn new_conn_handler(
     let client = Arc::new(Mutex::new(client));
     let mut clients = block_data.clients.lock().await;
     clients.push((conn_id, client.clone()));
+    block_data.client_count.fetch_add(1, Ordering::Relaxed);
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
         loop {
@@ -187,7 +198,14 @@ async fn run_action(
         Action::Nothing => {}
         Action::Disconnect => {
             log::info!("Client disconnected");
-            clients.remove(pos);
+            let (_, user) = clients.remove(pos);
+            drop(clients);
+            block_data.client_count.fetch_sub(1, Ordering::Relaxed);
+            let mut user = user.lock().await;
+            if user.state == UserState::InGame {
+                block.scripts.run("on_logout", &mut user, &()).await;
+            }
+            drop(user);
 
             let mut lock = block_data.blocks.write().await;
             if let Some(block) = lock.iter_mut().find(|x| x.id == block_data.block_id) {
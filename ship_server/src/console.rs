@@ -0,0 +1,249 @@
+use crate::{
+    BlockData, UserState,
+    announcements::{self, BroadcastScope},
+    data_store::DataStore,
+    mutex::RwLock,
+    sql::Sql,
+};
+use data_structs::master_ship::{Announcement, AnnouncementType};
+use indicatif::HumanBytes;
+use memory_stats::memory_stats;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::Notify,
+};
+
+const HELP: &[&str] = &[
+    "players - list connected players",
+    "kick <player id> [reason] - disconnect a player",
+    "announce <ship|all> <message> - send a system message",
+    "scroll <ship|all> <message> - send a scrolling message",
+    "mem - show memory usage",
+    "maps - show map statistics",
+    "reload - reload server data",
+    "shutdown - disconnect all players and stop the server",
+];
+
+/// How long to wait for players to disconnect during shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Operator console that reads commands from stdin.
+pub async fn run(
+    block_list: Arc<RwLock<Vec<Arc<BlockData>>>>,
+    data: Arc<DataStore>,
+    sql: Arc<Sql>,
+    shutdown: Arc<Notify>,
+) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            // stdin is closed, e.g. when running as a service
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Failed to read console input: {e}");
+                return;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match cmd {
+            "help" => HELP.iter().for_each(|l| println!("{l}")),
+            "players" => list_players(&block_list).await,
+            "kick" => kick(&block_list, args).await,
+            "announce" | "scroll" => {
+                let kind = match cmd {
+                    "scroll" => AnnouncementType::Scrolling,
+                    _ => AnnouncementType::SystemMessage,
+                };
+                announce(&block_list, args, kind).await;
+            }
+            "mem" => match memory_stats() {
+                Some(mem) => println!(
+                    "Physical memory: {}\nVirtual memory: {}",
+                    HumanBytes(mem.physical_mem as u64),
+                    HumanBytes(mem.virtual_mem as u64),
+                ),
+                None => println!("Couldn't gather memory info"),
+            },
+            "maps" => map_stats(&block_list).await,
+            "reload" => match data.reload(&sql).await {
+                Ok(true) => println!("Server data reloaded (version {})", data.version()),
+                Ok(false) => println!("Server data is already up to date"),
+                Err(e) => println!("Failed to reload data: {e}"),
+            },
+            "shutdown" => {
+                shutdown.notify_one();
+                return;
+            }
+            _ => println!("Unknown command: {cmd} (type \"help\" for the list of commands)"),
+        }
+    }
+}
+
+async fn list_players(block_list: &RwLock<Vec<Arc<BlockData>>>) {
+    let blocks = block_list.read().await.clone();
+    for block in blocks {
+        let clients: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        println!(
+            "Block {} ({}): {} connections",
+            block.block_id,
+            block.block_name,
+            clients.len()
+        );
+        for client in clients {
+            let user = client.lock().await;
+            let char_name = user
+                .character
+                .as_ref()
+                .map(|c| c.character.name.as_str())
+                .unwrap_or("-");
+            println!(
+                "  {} {} ({char_name}) {}",
+                user.get_user_id(),
+                user.user_data.nickname,
+                user.state
+            );
+        }
+    }
+}
+
+async fn kick(block_list: &RwLock<Vec<Arc<BlockData>>>, args: &str) {
+    let (id, reason) = args.split_once(' ').unwrap_or((args, ""));
+    let Ok(id) = id.parse::<u32>() else {
+        println!("Usage: kick <player id> [reason]");
+        return;
+    };
+    let reason = match reason.trim() {
+        "" => "You have been disconnected by the server operator",
+        reason => reason,
+    };
+    let blocks = block_list.read().await.clone();
+    for block in blocks {
+        let clients: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        for client in clients {
+            let mut user = client.lock().await;
+            if user.get_user_id() != id || user.state == UserState::LoggingIn {
+                continue;
+            }
+            if let Err(e) = user.kick(reason).await {
+                log::warn!("Failed to notify player {id} about the kick: {e}");
+            }
+            log::info!("Player {id} was kicked: {reason}");
+            return;
+        }
+    }
+    println!("Player {id} not found");
+}
+
+async fn announce(block_list: &RwLock<Vec<Arc<BlockData>>>, args: &str, kind: AnnouncementType) {
+    let (scope, message) = args.split_once(' ').unwrap_or((args, ""));
+    let scope = match scope.parse() {
+        Ok(BroadcastScope::Block) | Err(_) => {
+            println!("Scope must be either \"ship\" or \"all\"");
+            return;
+        }
+        Ok(scope) => scope,
+    };
+    let message = message.trim().to_string();
+    if message.is_empty() {
+        println!("Missing message");
+        return;
+    }
+    let Some(block) = block_list.read().await.first().cloned() else {
+        println!("No blocks are running");
+        return;
+    };
+    if let Err(e) = announcements::broadcast(&block, scope, Announcement { message, kind }).await {
+        println!("Failed to send announcement: {e}");
+    }
+}
+
+async fn map_stats(block_list: &RwLock<Vec<Arc<BlockData>>>) {
+    let blocks = block_list.read().await.clone();
+    for block in blocks {
+        let lobby_players = block.lobby.lock().await.player_count();
+        let clients: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        // maps are only owned by the players in them, so collect them from players
+        let mut maps = vec![];
+        for client in clients {
+            let Some(map) = client.lock().await.get_current_map() else {
+                continue;
+            };
+            if !maps.iter().any(|m| Arc::ptr_eq(m, &map)) {
+                maps.push(map);
+            }
+        }
+        let mut quest_maps = 0;
+        let mut quest_players = 0;
+        for map in maps {
+            let map = map.lock().await;
+            if !map.is_lobby() {
+                quest_maps += 1;
+                quest_players += map.player_count();
+            }
+        }
+        println!(
+            "Block {} ({}): {lobby_players} players in lobby, {quest_maps} quest maps with {quest_players} players",
+            block.block_id, block.block_name
+        );
+    }
+}
+
+/// Disconnects all players and waits for their data to be saved.
+pub async fn shutdown(block_list: &RwLock<Vec<Arc<BlockData>>>) {
+    log::info!("Disconnecting all players...");
+    let blocks = block_list.read().await.clone();
+    for block in &blocks {
+        let clients: Vec<_> = block
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        for client in clients {
+            let _ = client.lock().await.kick("Server is shutting down").await;
+        }
+    }
+    let start = tokio::time::Instant::now();
+    loop {
+        let mut connected = 0;
+        for block in &blocks {
+            connected += block.clients.lock().await.len();
+        }
+        if connected == 0 {
+            break;
+        }
+        if start.elapsed() >= SHUTDOWN_TIMEOUT {
+            log::warn!("{connected} players didn't disconnect in time");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // player data is saved in background tasks when players are dropped
+    tokio::time::sleep(Duration::from_secs(1)).await;
+}
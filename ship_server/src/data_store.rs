@@ -0,0 +1,277 @@
+use crate::{Error, quests::Quests, sql::Sql};
+use data_structs::{
+    SerDeFile, ServerData,
+    master_ship::{MasterShipAction, ServerDataChunk, data_version},
+    quest::QuestData,
+};
+use mlua::{Lua, StdLib};
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Server data shared by all blocks that can be swapped at runtime.
+///
+/// Lobbies and new quest instances pick up the reloaded data, running quests keep using the data
+/// they were created with.
+pub struct DataStore {
+    /// Location of the data file. If not set, then the data is requested from the master ship.
+    data_file: Option<String>,
+    /// Directory where data received from the master ship is cached.
+    cache_dir: String,
+    /// Lobby maps used by the blocks, these must be present in the data.
+    lobby_maps: Vec<String>,
+    current: RwLock<LoadedData>,
+}
+
+struct LoadedData {
+    /// Hash of the compressed data.
+    version: String,
+    server_data: Arc<ServerData>,
+    quests: Arc<Quests>,
+}
+
+impl DataStore {
+    pub async fn load(
+        data_file: Option<String>,
+        cache_dir: String,
+        lobby_maps: Vec<String>,
+        sql: &Sql,
+    ) -> Result<Self, Error> {
+        let (version, compressed) = fetch_data(data_file.as_deref(), &cache_dir, sql).await?;
+        let maps = lobby_maps.clone();
+        let data =
+            tokio::task::spawn_blocking(move || decode_data(version, &compressed, &maps)).await??;
+        report_version(sql, &data.version).await;
+        Ok(Self {
+            data_file,
+            cache_dir,
+            lobby_maps,
+            current: RwLock::new(data),
+        })
+    }
+    /// Creates a store from already loaded data.
+    ///
+    /// The data is not validated and reloading it will request the data from the master ship.
+    pub fn from_server_data(mut data: ServerData) -> Self {
+        let quests = Quests::load(std::mem::take(&mut data.quests));
+        Self {
+            data_file: None,
+            cache_dir: String::new(),
+            lobby_maps: vec![],
+            current: RwLock::new(LoadedData {
+                version: "local".into(),
+                server_data: Arc::new(data),
+                quests: Arc::new(quests),
+            }),
+        }
+    }
+    pub fn version(&self) -> String {
+        self.current.read().version.clone()
+    }
+    pub fn server_data(&self) -> Arc<ServerData> {
+        self.current.read().server_data.clone()
+    }
+    pub fn quests(&self) -> Arc<Quests> {
+        self.current.read().quests.clone()
+    }
+    /// Swaps in already loaded data. The data is not validated.
+    #[cfg(any(test, feature = "harness"))]
+    pub fn replace(&self, mut data: ServerData) {
+        let quests = Quests::load(std::mem::take(&mut data.quests));
+        quests.copy_emergencies(&self.quests());
+        *self.current.write() = LoadedData {
+            version: "local".into(),
+            server_data: Arc::new(data),
+            quests: Arc::new(quests),
+        };
+    }
+    /// Loads the data again, validates it and swaps it in.
+    ///
+    /// Returns `false` if the data didn't change.
+    pub async fn reload(&self, sql: &Sql) -> Result<bool, Error> {
+        log::info!("Reloading server data...");
+        let (version, compressed) =
+            fetch_data(self.data_file.as_deref(), &self.cache_dir, sql).await?;
+        if version == self.version() {
+            log::info!("Server data is already up to date");
+            return Ok(false);
+        }
+        let lobby_maps = self.lobby_maps.clone();
+        let new_data =
+            tokio::task::spawn_blocking(move || decode_data(version, &compressed, &lobby_maps))
+                .await??;
+        new_data.quests.copy_emergencies(&self.quests());
+        report_version(sql, &new_data.version).await;
+        *self.current.write() = new_data;
+        log::info!("Server data reloaded");
+        Ok(true)
+    }
+}
+
+/// Returns the version and the compressed server data.
+async fn fetch_data(
+    data_file: Option<&str>,
+    cache_dir: &str,
+    sql: &Sql,
+) -> Result<(String, Vec<u8>), Error> {
+    if let Some(data_path) = data_file {
+        log::info!("Loading server data...");
+        let data = tokio::fs::read(data_path).await?;
+        return Ok((data_version(&data), data));
+    }
+    log::warn!("No server data file provided, receiving from master ship...");
+    let info = match sql
+        .run_action(MasterShipAction::ServerDataInfoRequest)
+        .await?
+    {
+        MasterShipAction::ServerDataInfo(Some(info)) => info,
+        MasterShipAction::ServerDataInfo(None) => {
+            log::error!("No data available from master ship!");
+            return Err(Error::NoShipData);
+        }
+        MasterShipAction::Error(e) => return Err(Error::MSError(e)),
+        _ => return Err(Error::MSUnexpected),
+    };
+    let version = info.version;
+    let cache_file = PathBuf::from(cache_dir).join(format!("{version}.mp"));
+    if let Ok(data) = tokio::fs::read(&cache_file).await
+        && data_version(&data) == version
+    {
+        log::info!("Using cached server data {version}");
+        return Ok((version, data));
+    }
+
+    log::info!("Downloading server data {version} ({} bytes)...", info.size);
+    let mut data = Vec::with_capacity(info.size as usize);
+    for index in 0..info.chunk_count {
+        let action = MasterShipAction::ServerDataChunkRequest {
+            version: version.clone(),
+            index,
+        };
+        match sql.run_action(action).await? {
+            MasterShipAction::ServerDataChunk(ServerDataChunk {
+                index: chunk_index,
+                data: chunk,
+            }) if chunk_index == index => data.extend_from_slice(&chunk),
+            MasterShipAction::Error(e) => return Err(Error::MSError(e)),
+            _ => return Err(Error::MSUnexpected),
+        }
+    }
+    if data_version(&data) != version {
+        return Err(Error::InvalidServerData(
+            "downloaded data doesn't match its version".into(),
+        ));
+    }
+    if let Err(e) = save_cache(cache_dir, &cache_file, &data).await {
+        log::warn!("Failed to cache server data: {e}");
+    }
+    Ok((version, data))
+}
+
+/// Replaces previously cached data with the new one.
+async fn save_cache(cache_dir: &str, cache_file: &PathBuf, data: &[u8]) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(cache_dir).await?;
+    let mut entries = tokio::fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if is_cache_file(&path) {
+            tokio::fs::remove_file(path).await?;
+        }
+    }
+    tokio::fs::write(cache_file, data).await
+}
+
+/// Checks if the file was created by [`save_cache`], i.e. it's named after a data version.
+fn is_cache_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "mp")
+        && path.file_stem().and_then(|s| s.to_str()).is_some_and(|s| {
+            s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        })
+}
+
+async fn report_version(sql: &Sql, version: &str) {
+    log::info!("Server data version: {version}");
+    let action = MasterShipAction::ReportDataVersion(version.to_owned());
+    match sql.run_action(action).await {
+        Ok(MasterShipAction::Ok) => {}
+        Ok(MasterShipAction::Error(e)) => log::warn!("Failed to report data version: {e}"),
+        Ok(_) => log::warn!("Failed to report data version: unexpected response"),
+        Err(e) => log::warn!("Failed to report data version: {e}"),
+    }
+}
+
+fn decode_data(version: String, data: &[u8], lobby_maps: &[String]) -> Result<LoadedData, Error> {
+    let mut data = ServerData::load_from_mp_comp_slice(data)?;
+    validate(&data, lobby_maps)?;
+    let quests = Quests::load(std::mem::take(&mut data.quests));
+    Ok(LoadedData {
+        version,
+        server_data: Arc::new(data),
+        quests: Arc::new(quests),
+    })
+}
+
+fn validate(data: &ServerData, lobby_maps: &[String]) -> Result<(), Error> {
+    let invalid = |msg: String| Err(Error::InvalidServerData(msg));
+    for lobby in lobby_maps {
+        if !data.maps.contains_key(lobby) {
+            return invalid(format!("lobby map {lobby} is missing"));
+        }
+    }
+    if data.player_stats.stats.is_empty() {
+        return invalid("no player stats".into());
+    }
+    if data.default_classes.classes.is_empty() {
+        return invalid("no default class data".into());
+    }
+
+    let lua = Lua::new_with(StdLib::NONE, mlua::LuaOptions::default())?;
+    let check_map = |name: &str, zones: usize, luas: &HashMap<String, String>| {
+        if zones == 0 {
+            return invalid(format!("map {name} has no zones"));
+        }
+        for (lua_name, script) in luas {
+            if let Err(e) = lua
+                .load(script)
+                .set_name(format!("{name}/{lua_name}"))
+                .into_function()
+            {
+                return invalid(format!("failed to compile lua {name}/{lua_name}: {e}"));
+            }
+        }
+        Ok(())
+    };
+    for (name, map) in &data.maps {
+        check_map(name, map.zones.len(), &map.luas)?;
+    }
+    for QuestData {
+        definition, map, ..
+    } in &data.quests
+    {
+        let name = format!("quest {}", definition.name_id);
+        check_map(&name, map.zones.len(), &map.luas)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_cache_file;
+    use data_structs::master_ship::data_version;
+    use std::path::Path;
+
+    #[test]
+    fn cache_files() {
+        let version = data_version(b"data");
+        assert!(is_cache_file(
+            &Path::new("data").join(format!("{version}.mp"))
+        ));
+        assert!(!is_cache_file(Path::new("data/com_data.mp")));
+        assert!(!is_cache_file(
+            &Path::new("data").join(format!("{version}.json"))
+        ));
+    }
+}
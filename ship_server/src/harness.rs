@@ -0,0 +1,329 @@
+//! Offline harness for testing map scripts.
+//!
+//! The harness runs a map with mock players connected over loopback sockets, so scripts can be
+//! tested without the master ship or a game client.
+use crate::{
+    BlockData, Error, User, UserState,
+    data_store::DataStore,
+    map::{Map, MapType},
+    master_conn::MasterConnection,
+    mutex::{Mutex, RwLock},
+    party::Party,
+    quests::QuestProgress,
+    scripts::ServerScripts,
+    sql::{CharData, Sql},
+};
+use data_structs::{ServerData, map::MapData, quest::QuestData};
+use pso2packetlib::{
+    Connection, PrivateKey, PublicKey,
+    protocol::{
+        ObjectHeader, ObjectType, Packet, PacketType,
+        flag::{CutsceneEndPacket, SkitItemAddRequestPacket},
+        objects::InteractPacket,
+        unk19::SystemMessagePacket,
+    },
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize},
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{UnboundedReceiver, unbounded_channel},
+};
+
+/// Marks the end of the packets sent before [`MapHarness::take_packets`] was called.
+const SYNC_MARKER: &str = "#harness-sync#";
+/// How long to wait for packets to arrive.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct MockPlayer {
+    id: u32,
+    user: Arc<Mutex<User>>,
+    packets: UnboundedReceiver<Packet>,
+}
+
+/// Runs a map with mock players.
+pub struct MapHarness {
+    block: Arc<BlockData>,
+    map: Arc<Mutex<Map>>,
+    players: Vec<MockPlayer>,
+    next_id: u32,
+}
+
+/// Creates an offline block with the provided lobby map and server data.
+pub(crate) async fn new_block(
+    lobby: MapData,
+    server_data: ServerData,
+) -> Result<Arc<BlockData>, Error> {
+    let latest_mapid = AtomicU32::new(1);
+    let mut lobby = Map::new_from_data(lobby, &latest_mapid)?;
+    lobby.set_map_type(MapType::Lobby);
+
+    let sql = Sql::new_in_memory(MasterConnection::offline()).await?;
+    let block = Arc::new(BlockData {
+        sql: Arc::new(sql),
+        block_id: 1,
+        block_name: "harness".into(),
+        blocks: Arc::new(RwLock::new(vec![])),
+        block_list: Arc::new(RwLock::new(vec![])),
+        lobby: Arc::new(Mutex::new(lobby)),
+        key: PrivateKey::None,
+        latest_mapid,
+        latest_partyid: AtomicU32::new(1),
+        data: Arc::new(DataStore::from_server_data(server_data)),
+        scripts: Arc::new(ServerScripts::load(None)?),
+        clients: Mutex::new(vec![]),
+        client_count: AtomicUsize::new(0),
+        quest_instances: Default::default(),
+    });
+    block.lobby.lock().await.set_block_data(block.clone());
+    Map::start_timers(&block.lobby);
+    Ok(block)
+}
+
+impl MapHarness {
+    /// Creates a harness for a map without any other server data.
+    pub async fn new(map: MapData) -> Result<Self, Error> {
+        Self::with_server_data(map, ServerData::default()).await
+    }
+
+    /// Creates a harness for a map using the provided server data (e.g. for enemy stats).
+    ///
+    /// The `lobby` map from the server data is used for lobby moves if it exists, otherwise a
+    /// copy of the tested map is used.
+    pub async fn with_server_data(map: MapData, server_data: ServerData) -> Result<Self, Error> {
+        let lobby_data = server_data
+            .maps
+            .get("lobby")
+            .cloned()
+            .unwrap_or_else(|| map.clone());
+        let block = new_block(lobby_data, server_data).await?;
+        let map = Map::new_from_data(map, &block.latest_mapid)?;
+        let map = Arc::new(Mutex::new(map));
+        map.lock().await.set_block_data(block.clone());
+        Map::start_timers(&map);
+
+        Ok(Self {
+            block,
+            map,
+            players: vec![],
+            next_id: 1,
+        })
+    }
+
+    /// Runs the quest on the map, so its clear conditions are checked.
+    pub async fn set_quest(&self, quest: &QuestData, diff: u16) {
+        self.map
+            .lock()
+            .await
+            .set_quest_progress(QuestProgress::new(quest, diff));
+    }
+
+    /// Simulates a data reload while the map is running.
+    pub fn reload_data(&self, server_data: ServerData) {
+        self.block.data.replace(server_data);
+    }
+
+    /// Adds a new player to the initial zone of the map and returns their id.
+    pub async fn add_player(&mut self) -> Result<u32, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        let (mut user, _) = User::new(server, self.block.clone(), id as usize)?;
+        user.user_data.id = id;
+        user.user_data.nickname = format!("Player {id}");
+        user.state = UserState::InGame;
+        let mut char_data = CharData::default();
+        char_data.character.player_id = id;
+        char_data.character.name = format!("Player {id}");
+        user.character = Some(char_data);
+        user.set_map(self.map.clone());
+        let user = Arc::new(Mutex::new(user));
+
+        let (send, packets) = unbounded_channel();
+        let mut client = Connection::<Packet>::new_async(
+            client,
+            PacketType::Classic,
+            PrivateKey::None,
+            PublicKey::None,
+        );
+        tokio::spawn(async move {
+            while let Ok(packet) = client.read_packet_async().await {
+                if send.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let partyid = self
+            .block
+            .latest_partyid
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Party::init_player(user.clone(), partyid).await?;
+        self.map.lock().await.init_add_player(user.clone()).await?;
+        self.block
+            .clients
+            .lock()
+            .await
+            .push((id as usize, user.clone()));
+        self.block
+            .client_count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.players.push(MockPlayer { id, user, packets });
+        // skip packets sent during the initial load
+        self.take_packets(id).await?;
+        Ok(id)
+    }
+
+    /// Simulates the client finishing loading the current zone.
+    pub async fn map_loaded(&self, id: u32) -> Result<(), Error> {
+        let (map, zone) = self.current_map(id).await?;
+        map.lock().await.on_map_loaded(zone, id).await
+    }
+
+    /// Simulates an interaction with an object or npc.
+    pub async fn interact(&self, id: u32, object_id: u32, action: &str) -> Result<(), Error> {
+        let packet = InteractPacket {
+            object1: ObjectHeader {
+                id: object_id,
+                entity_type: ObjectType::Object,
+                ..Default::default()
+            },
+            object3: ObjectHeader {
+                id,
+                entity_type: ObjectType::Player,
+                ..Default::default()
+            },
+            action: action.into(),
+            ..Default::default()
+        };
+        self.interaction(id, packet).await
+    }
+
+    /// Sends an arbitrary interaction packet.
+    pub async fn interaction(&self, id: u32, packet: InteractPacket) -> Result<(), Error> {
+        let (map, zone) = self.current_map(id).await?;
+        map.lock().await.interaction(zone, packet, id).await
+    }
+
+    /// Simulates a questwork request.
+    pub async fn questwork(&self, id: u32, packet: SkitItemAddRequestPacket) -> Result<(), Error> {
+        let (map, zone) = self.current_map(id).await?;
+        map.lock().await.on_questwork(zone, id, packet).await
+    }
+
+    /// Simulates the end of a cutscene.
+    pub async fn cutscene_end(&self, id: u32, packet: CutsceneEndPacket) -> Result<(), Error> {
+        let (map, zone) = self.current_map(id).await?;
+        map.lock().await.on_cutscene_end(zone, id, packet).await
+    }
+
+    /// Moves the player to the zone with the specified name.
+    pub async fn move_to_zone(&self, id: u32, zone: &str) -> Result<(), Error> {
+        let (map, _) = self.current_map(id).await?;
+        map.lock().await.move_player_named(id, zone).await
+    }
+
+    /// Simulates the player using the telepipe back to the campship.
+    pub async fn return_to_campship(&self, id: u32) -> Result<(), Error> {
+        let (map, _) = self.current_map(id).await?;
+        map.lock().await.return_to_campship(id).await
+    }
+
+    /// Returns all packets sent to the player since the last call.
+    pub async fn take_packets(&mut self, id: u32) -> Result<Vec<Packet>, Error> {
+        let player = self
+            .players
+            .iter_mut()
+            .find(|p| p.id == id)
+            .ok_or(Error::NoUser)?;
+        player
+            .user
+            .lock()
+            .await
+            .send_packet(&Packet::SystemMessage(SystemMessagePacket {
+                message: SYNC_MARKER.into(),
+                ..Default::default()
+            }))
+            .await?;
+        let mut packets = vec![];
+        loop {
+            let packet = tokio::time::timeout(SYNC_TIMEOUT, player.packets.recv())
+                .await
+                .map_err(|_| Error::InvalidInput("take_packets: timed out"))?
+                .ok_or(Error::InvalidInput("take_packets: connection closed"))?;
+            match packet {
+                Packet::SystemMessage(ref p) if p.message == SYNC_MARKER => return Ok(packets),
+                packet => packets.push(packet),
+            }
+        }
+    }
+
+    /// Returns the name of the zone the player is in or `None` if they were moved to the lobby.
+    pub async fn zone(&self, id: u32) -> Result<Option<String>, Error> {
+        let map = self.user(id)?.lock().await.get_current_map();
+        if map.is_none_or(|m| Arc::ptr_eq(&m, &self.block.lobby)) {
+            return Ok(None);
+        }
+        Ok(self
+            .map
+            .lock()
+            .await
+            .get_player_zone(id)
+            .map(|z| z.to_string()))
+    }
+
+    /// Returns the value of an account flag.
+    pub async fn account_flag(&self, id: u32, flag: usize) -> Result<u8, Error> {
+        Ok(self.user(id)?.lock().await.get_account_flags().get(flag))
+    }
+
+    /// Returns the value of a character flag.
+    pub async fn character_flag(&self, id: u32, flag: usize) -> Result<u8, Error> {
+        let flags = self.user(id)?.lock().await.get_char_flags();
+        Ok(flags.map(|f| f.get(flag)).unwrap_or_default())
+    }
+
+    /// Returns the quests that were unlocked for the player.
+    pub async fn unlocked_quests(&self, id: u32) -> Result<Vec<u32>, Error> {
+        let user = self.user(id)?.lock().await;
+        Ok(user
+            .character
+            .as_ref()
+            .map(|c| c.unlocked_quests.clone())
+            .unwrap_or_default())
+    }
+
+    /// Returns the amount of meseta the player has.
+    pub async fn meseta(&self, id: u32) -> Result<u64, Error> {
+        let user = self.user(id)?.lock().await;
+        Ok(user
+            .character
+            .as_ref()
+            .map(|c| c.inventory.get_meseta())
+            .unwrap_or_default())
+    }
+
+    fn user(&self, id: u32) -> Result<&Arc<Mutex<User>>, Error> {
+        self.players
+            .iter()
+            .find(|p| p.id == id)
+            .map(|p| &p.user)
+            .ok_or(Error::NoUser)
+    }
+
+    async fn current_map(&self, id: u32) -> Result<(Arc<Mutex<Map>>, usize), Error> {
+        let user = self.user(id)?.lock().await;
+        let map = user
+            .get_current_map()
+            .ok_or(Error::InvalidInput("current_map"))?;
+        Ok((map, user.zone_pos))
+    }
+}
@@ -1,5 +1,7 @@
 use crate::Error;
-use data_structs::inventory::{AccountStorages, ItemParameters, StorageInventory};
+use data_structs::inventory::{
+    AccountStorages, ItemParameters, ItemUpgrades, StorageInventory, UpgradeMaterial,
+};
 use pso2packetlib::protocol::{
     ObjectHeader, Packet, ProtocolRW,
     items::{
@@ -13,6 +15,7 @@ use pso2packetlib::protocol::{
     },
     login::Language,
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -36,6 +39,43 @@ pub struct PlayerInventory {
     equiped: Vec<(u32, u64)>,
 }
 
+/// Reason why a shop purchase, sale, trade or item upgrade was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    #[error("Invalid item amount.")]
+    InvalidAmount,
+    #[error("Not enough meseta.")]
+    NotEnoughMeseta,
+    #[error("Inventory is full.")]
+    InventoryFull,
+    #[error("Item not found in the inventory.")]
+    NoItem,
+    #[error("Equipped items can't be sold, traded or used as fodder.")]
+    Equipped,
+    #[error("This item can't be sold.")]
+    NotSellable,
+    #[error("Only weapons can be ground or affixed.")]
+    NotUpgradable,
+    #[error("The weapon is already at the maximum grind level.")]
+    MaxGrind,
+    #[error("Not enough materials.")]
+    NotEnoughMaterials,
+    #[error("An item can't be used as its own fodder.")]
+    SameItem,
+    #[error("The fodder doesn't have this ability.")]
+    NoAffix,
+    #[error("The weapon already has this ability.")]
+    DuplicateAffix,
+    #[error("The weapon can't have more abilities.")]
+    NoAffixSlot,
+    #[error("This item can't be used.")]
+    NotUsable,
+    #[error("This item is on cooldown.")]
+    OnCooldown,
+    #[error("This item would have no effect.")]
+    NoEffect,
+}
+
 enum ChangeItemResult {
     Changed {
         uuid: u64,
@@ -72,17 +112,7 @@ impl Inventory {
         ) {
             packets.push(Packet::LoadItem(x));
         }
-        packets.push(Packet::LoadPlayerInventory(LoadPlayerInventoryPacket {
-            object: ObjectHeader {
-                id: player_id,
-                entity_type: pso2packetlib::protocol::ObjectType::Player,
-                ..Default::default()
-            },
-            name,
-            meseta: self.inventory.meseta,
-            max_capacity: self.inventory.max_capacity,
-            items: self.inventory.items.clone(),
-        }));
+        packets.push(self.inventory_packet(player_id, name));
         packets.push(self.send_equiped(player_id));
 
         // load storages
@@ -145,6 +175,20 @@ impl Inventory {
         }));
         packets
     }
+    /// Returns the packet with the whole player inventory.
+    pub fn inventory_packet(&self, player_id: u32, name: String) -> Packet {
+        Packet::LoadPlayerInventory(LoadPlayerInventoryPacket {
+            object: ObjectHeader {
+                id: player_id,
+                entity_type: pso2packetlib::protocol::ObjectType::Player,
+                ..Default::default()
+            },
+            name,
+            meseta: self.inventory.meseta,
+            max_capacity: self.inventory.max_capacity,
+            items: self.inventory.items.clone(),
+        })
+    }
     pub fn send_equiped(&self, player_id: u32) -> Packet {
         let mut equiped_items = LoadEquipedPacket::default();
         for (pos, uuid) in &self.inventory.equiped {
@@ -524,6 +568,15 @@ impl Inventory {
         }));
         packets
     }
+    pub const fn add_meseta(&mut self, amount: u64) -> Packet {
+        self.inventory.meseta = self.inventory.meseta.saturating_add(amount);
+        Packet::InventoryMeseta(InventoryMesetaPacket {
+            meseta: self.inventory.meseta,
+        })
+    }
+    pub const fn get_meseta(&self) -> u64 {
+        self.inventory.meseta
+    }
     pub fn add_item(&mut self, item: Item) -> Packet {
         let packet = Packet::AddedItem(AddedItemPacket {
             item: item.clone(),
@@ -533,30 +586,513 @@ impl Inventory {
         packet
     }
     pub fn add_default_item(&mut self, uuid: &mut u64, item_id: ItemId) -> Packet {
-        let item = Item {
-            uuid: *uuid,
-            id: item_id,
-            data: ItemType::default(),
-        };
-        *uuid += 1;
-
-        // transform item data into known item data
-        let packet = Packet::AddedItem(AddedItemPacket {
+        let item = new_default_item(uuid, item_id);
+        self.inventory.items.push(item.clone());
+        Packet::AddedItem(AddedItemPacket {
             item,
             ..Default::default()
         })
-        .write(pso2packetlib::protocol::PacketType::NA);
-        let packet = Packet::read(&packet, pso2packetlib::protocol::PacketType::NA)
-            .expect("Reading from memory shouldn't fail")
-            .pop()
-            .expect("Should always contain an item");
-        let Packet::AddedItem(added_item) = &packet else {
-            unreachable!("Read and write impls should agree");
+    }
+    pub fn get_inv_items(&self) -> &[Item] {
+        &self.inventory.items
+    }
+    /// Buys `amount` items for `price` meseta each. Consumables are added to an existing stack if
+    /// possible.
+    pub fn buy_item(
+        &mut self,
+        uuid: &mut u64,
+        item_id: ItemId,
+        amount: u16,
+        price: u32,
+        params: &ItemParameters,
+    ) -> Result<Vec<Packet>, TransactionError> {
+        if amount == 0 {
+            return Err(TransactionError::InvalidAmount);
+        }
+        let cost = price as u64 * amount as u64;
+        if cost > self.inventory.meseta {
+            return Err(TransactionError::NotEnoughMeseta);
+        }
+        let max_qty = params
+            .attrs
+            .consumables
+            .iter()
+            .find(|c| c.id == item_id.id && c.subid == item_id.subid)
+            .map(|c| c.max_qty as u16)
+            .filter(|q| *q != 0);
+        let mut packets = vec![];
+        let stack = self
+            .inventory
+            .items
+            .iter_mut()
+            .find(|i| i.id == item_id)
+            .and_then(|i| match &mut i.data {
+                ItemType::Consumable(data) => Some((i.uuid, data)),
+                _ => None,
+            });
+        if let Some((stack_uuid, data)) = stack {
+            let Some(new_amount) = data.amount.checked_add(amount) else {
+                return Err(TransactionError::InventoryFull);
+            };
+            if max_qty.is_some_and(|q| new_amount > q) {
+                return Err(TransactionError::InventoryFull);
+            }
+            data.amount = new_amount;
+            packets.push(Packet::UpdateInventory(UpdateInventoryPacket {
+                updated: vec![pso2packetlib::protocol::items::UpdatedInventoryItem {
+                    uuid: stack_uuid,
+                    new_amount,
+                    moved: amount,
+                }],
+                unk2: 1,
+                ..Default::default()
+            }));
+        } else {
+            let mut item = new_default_item(uuid, item_id);
+            let count = match &mut item.data {
+                ItemType::Consumable(data) => {
+                    if max_qty.is_some_and(|q| amount > q) {
+                        return Err(TransactionError::InventoryFull);
+                    }
+                    data.amount = amount;
+                    1
+                }
+                _ => amount as usize,
+            };
+            if self.inventory.items.len() + count > self.inventory.max_capacity as usize {
+                return Err(TransactionError::InventoryFull);
+            }
+            let mut items = vec![item];
+            for _ in 1..count {
+                items.push(new_default_item(uuid, item_id));
+            }
+            for item in items {
+                self.inventory.items.push(item.clone());
+                packets.push(Packet::AddedItem(AddedItemPacket {
+                    item,
+                    ..Default::default()
+                }));
+            }
+        }
+        self.inventory.meseta -= cost;
+        packets.push(Packet::InventoryMeseta(InventoryMesetaPacket {
+            meseta: self.inventory.meseta,
+        }));
+        Ok(packets)
+    }
+    pub fn is_equiped(&self, uuid: u64) -> bool {
+        self.inventory.equiped.iter().any(|(_, u)| *u == uuid)
+    }
+    /// Removes offered items and meseta from the inventory and returns the removed items.
+    ///
+    /// The inventory is left partially modified on error, so this should be called on a copy.
+    pub fn take_trade_offer(
+        &mut self,
+        items: &[(u64, u16)],
+        meseta: u64,
+    ) -> Result<(Vec<Item>, Vec<Packet>), TransactionError> {
+        if meseta > self.inventory.meseta {
+            return Err(TransactionError::NotEnoughMeseta);
+        }
+        let mut taken = vec![];
+        let mut update = UpdateInventoryPacket {
+            unk2: 1,
+            ..Default::default()
+        };
+        for &(uuid, amount) in items {
+            if self.is_equiped(uuid) {
+                return Err(TransactionError::Equipped);
+            }
+            if amount == 0 {
+                return Err(TransactionError::InvalidAmount);
+            }
+            let (item, new_amount, moved) =
+                match decrease_item(&mut self.inventory.items, uuid, amount)
+                    .map_err(|_| TransactionError::NoItem)?
+                {
+                    ChangeItemResult::Changed {
+                        new_amount,
+                        moved,
+                        item,
+                        ..
+                    } => (item, new_amount, moved),
+                    ChangeItemResult::Removed { item, amount } => (item, 0, amount),
+                    _ => unreachable!(),
+                };
+            if moved != amount {
+                return Err(TransactionError::InvalidAmount);
+            }
+            update
+                .updated
+                .push(pso2packetlib::protocol::items::UpdatedInventoryItem {
+                    uuid,
+                    new_amount,
+                    moved,
+                });
+            taken.push(item);
+        }
+        self.inventory.meseta -= meseta;
+        let packets = vec![
+            Packet::UpdateInventory(update),
+            Packet::InventoryMeseta(InventoryMesetaPacket {
+                meseta: self.inventory.meseta,
+            }),
+        ];
+        Ok((taken, packets))
+    }
+    /// Adds traded items and meseta to the inventory. Items get new UUIDs.
+    ///
+    /// The inventory is left partially modified on error, so this should be called on a copy.
+    pub fn receive_trade_offer(
+        &mut self,
+        items: Vec<Item>,
+        meseta: u64,
+        uuid: &mut u64,
+    ) -> Result<Vec<Packet>, TransactionError> {
+        let mut packets = vec![];
+        let mut update = UpdateInventoryPacket {
+            unk2: 1,
+            ..Default::default()
+        };
+        for mut item in items {
+            let stack = match &item.data {
+                ItemType::Consumable(data) => self
+                    .inventory
+                    .items
+                    .iter_mut()
+                    .find(|i| i.id == item.id)
+                    .and_then(|i| match &mut i.data {
+                        ItemType::Consumable(stack) => Some((i.uuid, stack, data.amount)),
+                        _ => None,
+                    }),
+                _ => None,
+            };
+            if let Some((stack_uuid, stack, amount)) = stack {
+                stack.amount = stack.amount.saturating_add(amount);
+                update
+                    .updated
+                    .push(pso2packetlib::protocol::items::UpdatedInventoryItem {
+                        uuid: stack_uuid,
+                        new_amount: stack.amount,
+                        moved: amount,
+                    });
+                continue;
+            }
+            if self.inventory.items.len() >= self.inventory.max_capacity as usize {
+                return Err(TransactionError::InventoryFull);
+            }
+            item.uuid = *uuid;
+            *uuid += 1;
+            self.inventory.items.push(item.clone());
+            packets.push(Packet::AddedItem(AddedItemPacket {
+                item,
+                ..Default::default()
+            }));
+        }
+        if !update.updated.is_empty() {
+            packets.push(Packet::UpdateInventory(update));
+        }
+        self.inventory.meseta = self.inventory.meseta.saturating_add(meseta);
+        packets.push(Packet::InventoryMeseta(InventoryMesetaPacket {
+            meseta: self.inventory.meseta,
+        }));
+        Ok(packets)
+    }
+    /// Sells `amount` items from the inventory for their sell price.
+    pub fn sell_item(
+        &mut self,
+        uuid: u64,
+        amount: u16,
+        params: &ItemParameters,
+    ) -> Result<Vec<Packet>, TransactionError> {
+        if amount == 0 {
+            return Err(TransactionError::InvalidAmount);
+        }
+        let item = self
+            .inventory
+            .items
+            .iter()
+            .find(|i| i.uuid == uuid)
+            .ok_or(TransactionError::NoItem)?;
+        if self.is_equiped(uuid) {
+            return Err(TransactionError::Equipped);
+        }
+        let price = params
+            .prices
+            .iter()
+            .find(|p| p.id == item.id)
+            .map(|p| p.sell_price)
+            .filter(|p| *p != 0)
+            .ok_or(TransactionError::NotSellable)?;
+        let (new_amount, sold) = match decrease_item(&mut self.inventory.items, uuid, amount)
+            .map_err(|_| TransactionError::InvalidAmount)?
+        {
+            ChangeItemResult::Changed {
+                new_amount, moved, ..
+            } => (new_amount, moved),
+            ChangeItemResult::Removed { amount, .. } => (0, amount),
+            _ => unreachable!(),
+        };
+        self.inventory.meseta = self
+            .inventory
+            .meseta
+            .saturating_add(price as u64 * sold as u64);
+        Ok(vec![
+            Packet::UpdateInventory(UpdateInventoryPacket {
+                updated: vec![pso2packetlib::protocol::items::UpdatedInventoryItem {
+                    uuid,
+                    new_amount,
+                    moved: sold,
+                }],
+                unk2: 1,
+                ..Default::default()
+            }),
+            Packet::InventoryMeseta(InventoryMesetaPacket {
+                meseta: self.inventory.meseta,
+            }),
+        ])
+    }
+    /// Raises the grind level of the weapon. Meseta and materials are consumed even if the grind
+    /// fails.
+    ///
+    /// Returns whether the grind succeeded.
+    pub fn grind_item(
+        &mut self,
+        uuid: u64,
+        upgrades: &ItemUpgrades,
+        rng: &mut impl Rng,
+    ) -> Result<(bool, Vec<Packet>), TransactionError> {
+        let item = self
+            .inventory
+            .items
+            .iter()
+            .find(|i| i.uuid == uuid)
+            .ok_or(TransactionError::NoItem)?;
+        let ItemType::Weapon(data) = &item.data else {
+            return Err(TransactionError::NotUpgradable);
         };
-        self.inventory.items.push(added_item.item.clone());
+        let level = upgrades
+            .grinds
+            .get(data.grind as usize)
+            .ok_or(TransactionError::MaxGrind)?;
+        if level.meseta as u64 > self.inventory.meseta {
+            return Err(TransactionError::NotEnoughMeseta);
+        }
+        let mut packets = self.take_materials(&level.materials, uuid)?;
+        self.inventory.meseta -= level.meseta as u64;
+        packets.push(Packet::InventoryMeseta(InventoryMesetaPacket {
+            meseta: self.inventory.meseta,
+        }));
+        let success = rng.gen_bool(level.success_rate.clamp(0.0, 1.0));
+        if success {
+            if let Some(ItemType::Weapon(data)) = self
+                .inventory
+                .items
+                .iter_mut()
+                .find(|i| i.uuid == uuid)
+                .map(|i| &mut i.data)
+            {
+                data.grind += 1;
+            }
+        }
+        Ok((success, packets))
+    }
+    /// Transfers an ability from the fodder weapon to the base weapon. `affix_pos` is the position
+    /// of the ability among the fodder abilities. The fodder and meseta are consumed even if the
+    /// transfer fails.
+    ///
+    /// Returns whether the transfer succeeded.
+    pub fn affix_item(
+        &mut self,
+        base_uuid: u64,
+        fodder_uuid: u64,
+        affix_pos: usize,
+        upgrades: &ItemUpgrades,
+        rng: &mut impl Rng,
+    ) -> Result<(bool, Vec<Packet>), TransactionError> {
+        if base_uuid == fodder_uuid {
+            return Err(TransactionError::SameItem);
+        }
+        if self.is_equiped(fodder_uuid) {
+            return Err(TransactionError::Equipped);
+        }
+        let get_weapon = |uuid| match self.inventory.items.iter().find(|i| i.uuid == uuid) {
+            Some(Item {
+                data: ItemType::Weapon(data),
+                ..
+            }) => Ok(data),
+            Some(_) => Err(TransactionError::NotUpgradable),
+            None => Err(TransactionError::NoItem),
+        };
+        let base = get_weapon(base_uuid)?;
+        let fodder = get_weapon(fodder_uuid)?;
+        let affix = fodder
+            .affixes
+            .iter()
+            .copied()
+            .filter(|a| *a != 0)
+            .nth(affix_pos)
+            .ok_or(TransactionError::NoAffix)?;
+        if base.affixes.contains(&affix) {
+            return Err(TransactionError::DuplicateAffix);
+        }
+        let affix_count = base.affixes.iter().filter(|a| **a != 0).count();
+        let free_slot = base
+            .affixes
+            .iter()
+            .position(|a| *a == 0)
+            .ok_or(TransactionError::NoAffixSlot)?;
+        let rate = *upgrades
+            .affix_rates
+            .get(affix_count)
+            .ok_or(TransactionError::NoAffixSlot)?;
+        if upgrades.affix_meseta as u64 > self.inventory.meseta {
+            return Err(TransactionError::NotEnoughMeseta);
+        }
 
-        packet
+        decrease_item(&mut self.inventory.items, fodder_uuid, 1)
+            .map_err(|_| TransactionError::NoItem)?;
+        self.inventory.meseta -= upgrades.affix_meseta as u64;
+        let packets = vec![
+            Packet::UpdateInventory(UpdateInventoryPacket {
+                updated: vec![pso2packetlib::protocol::items::UpdatedInventoryItem {
+                    uuid: fodder_uuid,
+                    new_amount: 0,
+                    moved: 1,
+                }],
+                unk2: 1,
+                ..Default::default()
+            }),
+            Packet::InventoryMeseta(InventoryMesetaPacket {
+                meseta: self.inventory.meseta,
+            }),
+        ];
+        let success = rng.gen_bool(rate.clamp(0.0, 1.0));
+        if success {
+            if let Some(ItemType::Weapon(data)) = self
+                .inventory
+                .items
+                .iter_mut()
+                .find(|i| i.uuid == base_uuid)
+                .map(|i| &mut i.data)
+            {
+                data.affixes[free_slot] = affix;
+            }
+        }
+        Ok((success, packets))
+    }
+    /// Removes one used consumable from the stack.
+    pub fn use_item(&mut self, uuid: u64) -> Result<Packet, TransactionError> {
+        let item = self
+            .inventory
+            .items
+            .iter()
+            .find(|i| i.uuid == uuid)
+            .ok_or(TransactionError::NoItem)?;
+        if !matches!(item.data, ItemType::Consumable(_)) {
+            return Err(TransactionError::NotUsable);
+        }
+        let new_amount = match decrease_item(&mut self.inventory.items, uuid, 1)
+            .map_err(|_| TransactionError::NoItem)?
+        {
+            ChangeItemResult::Changed { new_amount, .. } => new_amount,
+            ChangeItemResult::Removed { .. } => 0,
+            _ => unreachable!(),
+        };
+        Ok(Packet::UpdateInventory(UpdateInventoryPacket {
+            updated: vec![pso2packetlib::protocol::items::UpdatedInventoryItem {
+                uuid,
+                new_amount,
+                moved: 1,
+            }],
+            unk2: 1,
+            ..Default::default()
+        }))
     }
+    /// Removes upgrade materials from the inventory. Nothing is removed if any material is
+    /// missing. Equipped items and the upgraded item itself are never used as materials.
+    fn take_materials(
+        &mut self,
+        materials: &[UpgradeMaterial],
+        target: u64,
+    ) -> Result<Vec<Packet>, TransactionError> {
+        let mut stacks = vec![];
+        for material in materials.iter().filter(|m| m.amount != 0) {
+            let stack = self
+                .inventory
+                .items
+                .iter()
+                .filter(|i| i.uuid != target && !self.is_equiped(i.uuid))
+                .find(|i| i.id == material.item)
+                .filter(|i| match &i.data {
+                    ItemType::Consumable(data) => data.amount >= material.amount,
+                    _ => material.amount <= 1,
+                })
+                .ok_or(TransactionError::NotEnoughMaterials)?;
+            stacks.push((stack.uuid, material.amount));
+        }
+        let mut update = UpdateInventoryPacket {
+            unk2: 1,
+            ..Default::default()
+        };
+        for (uuid, amount) in stacks {
+            let (new_amount, moved) = match decrease_item(&mut self.inventory.items, uuid, amount)
+                .map_err(|_| TransactionError::NotEnoughMaterials)?
+            {
+                ChangeItemResult::Changed {
+                    new_amount, moved, ..
+                } => (new_amount, moved),
+                ChangeItemResult::Removed { amount, .. } => (0, amount),
+                _ => unreachable!(),
+            };
+            update
+                .updated
+                .push(pso2packetlib::protocol::items::UpdatedInventoryItem {
+                    uuid,
+                    new_amount,
+                    moved,
+                });
+        }
+        if update.updated.is_empty() {
+            Ok(vec![])
+        } else {
+            Ok(vec![Packet::UpdateInventory(update)])
+        }
+    }
+}
+/// Returns the name of the item in the language or its ID if the name is unknown.
+pub fn item_name(params: &ItemParameters, id: ItemId, lang: Language) -> String {
+    match params.names.iter().find(|n| n.id == id) {
+        Some(name) => match lang {
+            Language::English => name.en_name.clone(),
+            Language::Japanese => name.jp_name.clone(),
+        },
+        None => format!("{}:{}:{}", id.item_type, id.id, id.subid),
+    }
+}
+/// Creates a new item with default data for the item type.
+fn new_default_item(uuid: &mut u64, item_id: ItemId) -> Item {
+    let item = Item {
+        uuid: *uuid,
+        id: item_id,
+        data: ItemType::default(),
+    };
+    *uuid += 1;
+
+    // transform item data into known item data
+    let packet = Packet::AddedItem(AddedItemPacket {
+        item,
+        ..Default::default()
+    })
+    .write(pso2packetlib::protocol::PacketType::NA);
+    let packet = Packet::read(&packet, pso2packetlib::protocol::PacketType::NA)
+        .expect("Reading from memory shouldn't fail")
+        .pop()
+        .expect("Should always contain an item");
+    let Packet::AddedItem(added_item) = packet else {
+        unreachable!("Read and write impls should agree");
+    };
+    added_item.item
 }
 fn load_items_inner(
     loaded: &mut Vec<ItemId>,
@@ -668,3 +1204,250 @@ impl Default for PlayerInventory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Inventory, TransactionError};
+    use data_structs::inventory::{
+        GrindLevel, ItemParameters, ItemPrice, ItemUpgrades, UpgradeMaterial,
+    };
+    use pso2packetlib::protocol::items::{Item, ItemId, ItemType, WeaponItem};
+
+    #[test]
+    fn shop() {
+        let mut inventory = Inventory::default();
+        let mut uuid = 1;
+        let potion = ItemId {
+            item_type: 3,
+            id: 1,
+            subid: 1,
+            ..Default::default()
+        };
+        let weapon = ItemId {
+            item_type: 1,
+            id: 1,
+            subid: 1,
+            ..Default::default()
+        };
+        let params = ItemParameters {
+            prices: vec![ItemPrice {
+                id: potion,
+                sell_price: 5,
+            }],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            inventory.buy_item(&mut uuid, potion, 2, 50, &params),
+            Err(TransactionError::NotEnoughMeseta)
+        ));
+        inventory.add_meseta(200);
+        inventory
+            .buy_item(&mut uuid, potion, 2, 50, &params)
+            .unwrap();
+        // consumables are stacked
+        inventory
+            .buy_item(&mut uuid, potion, 1, 50, &params)
+            .unwrap();
+        assert_eq!(inventory.get_meseta(), 50);
+        assert_eq!(inventory.get_inv_items().len(), 1);
+        let stack = &inventory.get_inv_items()[0];
+        let ItemType::Consumable(data) = &stack.data else {
+            panic!("Potion should be a consumable");
+        };
+        assert_eq!(data.amount, 3);
+        let stack_uuid = stack.uuid;
+        // stacks without a max quantity can't overflow
+        assert!(matches!(
+            inventory.buy_item(&mut uuid, potion, u16::MAX, 0, &params),
+            Err(TransactionError::InventoryFull)
+        ));
+        assert_eq!(inventory.get_meseta(), 50);
+
+        // selling uses the item price
+        inventory.sell_item(stack_uuid, 2, &params).unwrap();
+        assert_eq!(inventory.get_meseta(), 60);
+
+        // inventory capacity is checked
+        inventory.add_meseta(1000);
+        assert!(matches!(
+            inventory.buy_item(&mut uuid, weapon, 50, 0, &params),
+            Err(TransactionError::InventoryFull)
+        ));
+        inventory
+            .buy_item(&mut uuid, weapon, 49, 0, &params)
+            .unwrap();
+        assert_eq!(inventory.get_inv_items().len(), 50);
+        let weapon_uuid = inventory.get_inv_items()[1].uuid;
+        assert!(matches!(
+            inventory.sell_item(weapon_uuid, 1, &params),
+            Err(TransactionError::NotSellable)
+        ));
+    }
+
+    #[test]
+    fn trade() {
+        let mut first = Inventory::default();
+        let mut second = Inventory::default();
+        let mut first_uuid = 1;
+        let mut second_uuid = 1;
+        let potion = ItemId {
+            item_type: 3,
+            id: 1,
+            subid: 1,
+            ..Default::default()
+        };
+        let params = ItemParameters::default();
+        first.add_meseta(100);
+        first
+            .buy_item(&mut first_uuid, potion, 5, 0, &params)
+            .unwrap();
+        let uuid = first.get_inv_items()[0].uuid;
+        first.equip_item(uuid, 0).unwrap();
+        assert!(matches!(
+            first.clone().take_trade_offer(&[(uuid, 2)], 0),
+            Err(TransactionError::Equipped)
+        ));
+        first.unequip_item(uuid).unwrap();
+        assert!(matches!(
+            first.clone().take_trade_offer(&[(uuid, 2)], 200),
+            Err(TransactionError::NotEnoughMeseta)
+        ));
+
+        let (items, _) = first.take_trade_offer(&[(uuid, 2)], 40).unwrap();
+        second
+            .receive_trade_offer(items, 40, &mut second_uuid)
+            .unwrap();
+        assert_eq!(first.get_meseta(), 60);
+        assert_eq!(second.get_meseta(), 40);
+        let ItemType::Consumable(data) = &first.get_inv_items()[0].data else {
+            panic!("Potion should be a consumable");
+        };
+        assert_eq!(data.amount, 3);
+        let ItemType::Consumable(data) = &second.get_inv_items()[0].data else {
+            panic!("Potion should be a consumable");
+        };
+        assert_eq!(data.amount, 2);
+        assert_eq!(second_uuid, 2);
+    }
+
+    #[test]
+    fn upgrades() {
+        let mut inventory = Inventory::default();
+        let mut uuid = 1;
+        let grinder = ItemId {
+            item_type: 3,
+            id: 2,
+            subid: 1,
+            ..Default::default()
+        };
+        let weapon_id = ItemId {
+            item_type: 1,
+            id: 1,
+            subid: 1,
+            ..Default::default()
+        };
+        let upgrades = ItemUpgrades {
+            grinds: vec![
+                GrindLevel {
+                    meseta: 10,
+                    materials: vec![UpgradeMaterial {
+                        item: grinder,
+                        amount: 2,
+                    }],
+                    success_rate: 1.0,
+                    power: 5,
+                },
+                GrindLevel {
+                    meseta: 10,
+                    success_rate: 0.0,
+                    power: 5,
+                    ..Default::default()
+                },
+            ],
+            affix_meseta: 20,
+            affix_rates: vec![1.0],
+            affixes: vec![],
+        };
+        let mut rng = rand::thread_rng();
+        inventory.add_meseta(100);
+        inventory.add_item(Item {
+            uuid: 1,
+            id: weapon_id,
+            data: ItemType::Weapon(WeaponItem::default()),
+        });
+        inventory.add_item(Item {
+            uuid: 2,
+            id: weapon_id,
+            data: ItemType::Weapon(WeaponItem {
+                affixes: [7, 8, 0, 0, 0, 0, 0, 0],
+                ..Default::default()
+            }),
+        });
+        uuid += 2;
+
+        assert!(matches!(
+            inventory.grind_item(1, &upgrades, &mut rng),
+            Err(TransactionError::NotEnoughMaterials)
+        ));
+        inventory
+            .buy_item(&mut uuid, grinder, 3, 0, &ItemParameters::default())
+            .unwrap();
+        let (success, _) = inventory.grind_item(1, &upgrades, &mut rng).unwrap();
+        assert!(success);
+        // failed grinds still consume meseta
+        let (success, _) = inventory.grind_item(1, &upgrades, &mut rng).unwrap();
+        assert!(!success);
+        assert_eq!(inventory.get_meseta(), 80);
+        assert!(matches!(
+            inventory.grind_item(3, &upgrades, &mut rng),
+            Err(TransactionError::NotUpgradable)
+        ));
+        assert_eq!(upgrades.grind_power(1), 5);
+
+        assert!(matches!(
+            inventory.affix_item(1, 1, 0, &upgrades, &mut rng),
+            Err(TransactionError::SameItem)
+        ));
+        assert!(matches!(
+            inventory.affix_item(1, 2, 2, &upgrades, &mut rng),
+            Err(TransactionError::NoAffix)
+        ));
+        let (success, _) = inventory.affix_item(1, 2, 1, &upgrades, &mut rng).unwrap();
+        assert!(success);
+        assert_eq!(inventory.get_meseta(), 60);
+        assert!(inventory.get_inv_items().iter().all(|i| i.uuid != 2));
+        let ItemType::Weapon(data) = &inventory.get_inv_item(1).unwrap().data else {
+            panic!("Base should be a weapon");
+        };
+        assert_eq!(data.grind, 1);
+        assert_eq!(data.affixes[0], 8);
+
+        // the weapon being ground can't be its own material
+        let self_grind = ItemUpgrades {
+            grinds: vec![GrindLevel {
+                meseta: 10,
+                materials: vec![UpgradeMaterial {
+                    item: weapon_id,
+                    amount: 1,
+                }],
+                success_rate: 1.0,
+                power: 5,
+            }],
+            ..Default::default()
+        };
+        let mut inventory = Inventory::default();
+        inventory.add_meseta(100);
+        inventory.add_item(Item {
+            uuid: 1,
+            id: weapon_id,
+            data: ItemType::Weapon(WeaponItem::default()),
+        });
+        assert!(matches!(
+            inventory.grind_item(1, &self_grind, &mut rng),
+            Err(TransactionError::NotEnoughMaterials)
+        ));
+        assert!(inventory.get_inv_item(1).is_ok());
+        assert_eq!(inventory.get_meseta(), 100);
+    }
+}
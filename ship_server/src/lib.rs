@@ -4,8 +4,13 @@
 #![allow(clippy::await_holding_lock)]
 #![allow(dead_code)]
 
+mod announcements;
 mod battle_stats;
 mod block;
+mod console;
+mod data_store;
+#[cfg(any(test, feature = "harness"))]
+pub mod harness;
 mod inventory;
 mod invites;
 mod map;
@@ -14,12 +19,15 @@ mod mutex;
 mod palette;
 mod party;
 mod quests;
+mod scripts;
 mod settings;
 mod sql;
+mod trade;
 mod user;
 
+use data_store::DataStore;
 use data_structs::{
-    SerDeFile, ServerData,
+    ServerData,
     master_ship::{self, ShipInfo},
 };
 use master_conn::MasterConnection;
@@ -35,7 +43,10 @@ use settings::Settings;
 use std::{
     io,
     net::Ipv4Addr,
-    sync::{Arc, atomic::AtomicU32},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize},
+    },
 };
 use thiserror::Error;
 use user::*;
@@ -48,6 +59,8 @@ pub enum Error {
     InvalidPassword,
     #[error("No user found")]
     NoUser,
+    #[error("User is banned")]
+    UserBanned,
     #[error("No user {0} found in mapset {1}")]
     NoUserInMap(u32, String),
     #[error("Mapid {0} not found in mapset {1}")]
@@ -76,6 +89,8 @@ pub enum Error {
     NoHitboxInfo(String, u32),
     #[error("No ship data available")]
     NoShipData,
+    #[error("Invalid server data: {0}")]
+    InvalidServerData(String),
 
     // passthrough errors
     #[error("SQL error: {0}")]
@@ -117,8 +132,8 @@ struct BlockInfo {
     max_players: u32,
     players: u32,
     lobby_map: String,
-    server_data: Arc<ServerData>,
-    quests: Arc<Quests>,
+    data: Arc<DataStore>,
+    scripts: Arc<scripts::ServerScripts>,
 }
 
 struct BlockData {
@@ -126,13 +141,57 @@ struct BlockData {
     block_id: u32,
     block_name: String,
     blocks: Arc<RwLock<Vec<BlockInfo>>>,
+    /// All blocks running on this ship.
+    block_list: Arc<RwLock<Vec<Arc<BlockData>>>>,
     lobby: Arc<Mutex<map::Map>>,
     key: PrivateKey,
     latest_mapid: AtomicU32,
     latest_partyid: AtomicU32,
-    server_data: Arc<ServerData>,
-    quests: Arc<Quests>,
+    data: Arc<DataStore>,
+    scripts: Arc<scripts::ServerScripts>,
     clients: Mutex<Vec<(usize, Arc<Mutex<User>>)>>,
+    /// Number of entries in `clients`, readable while other locks are held.
+    client_count: AtomicUsize,
+    quest_instances: quests::QuestInstances,
+}
+
+impl BlockData {
+    fn server_data(&self) -> Arc<ServerData> {
+        self.data.server_data()
+    }
+    fn quests(&self) -> Arc<Quests> {
+        self.data.quests()
+    }
+    /// Sends a packet to every player that is in game on this block.
+    async fn send_to_all(&self, packet: &Packet) {
+        let clients: Vec<_> = self
+            .clients
+            .lock()
+            .await
+            .iter()
+            .map(|(_, c)| c.clone())
+            .collect();
+        for client in clients {
+            let mut user = client.lock().await;
+            if user.state != UserState::InGame {
+                continue;
+            }
+            if let Err(e) = user.send_packet(packet).await {
+                log::warn!(
+                    "Failed to send packet to player {}: {e}",
+                    user.get_user_id()
+                );
+            }
+        }
+    }
+}
+
+/// Sends a packet to every player on every block of the ship.
+async fn send_to_ship(block_list: &RwLock<Vec<Arc<BlockData>>>, packet: &Packet) {
+    let blocks = block_list.read().await.clone();
+    for block in blocks {
+        block.send_to_all(packet).await;
+    }
 }
 
 #[derive(Default, Clone)]
@@ -226,36 +285,29 @@ pub async fn run() -> Result<(), Error> {
     log::info!("Registed ship");
     let mut notif_channel = master_conn.take_notif_ch().unwrap();
 
-    let mut server_data = Arc::new(if let Some(data_path) = settings.data_file {
-        log::info!("Loading server data...");
-        ServerData::load_from_mp_comp(data_path)?
-    } else {
-        log::warn!("No server data file provided, receiving from master ship...");
-        match master_conn
-            .run_action(master_ship::MasterShipAction::ServerDataRequest)
-            .await?
-        {
-            master_ship::MasterShipAction::ServerDataResponse(server_data_result) => {
-                match server_data_result {
-                    master_ship::ServerDataResult::Ok(server_data) => *server_data,
-                    master_ship::ServerDataResult::NotAvailable => {
-                        log::error!("No data available from master ship!");
-                        return Err(Error::NoShipData);
-                    }
-                }
-            }
-            master_ship::MasterShipAction::Error(e) => return Err(Error::MSError(e)),
-            _ => return Err(Error::MSUnexpected),
-        }
-    });
+    let sql = Arc::new(sql::Sql::new(&settings.db_name, master_conn).await?);
+    let lobby_maps = settings
+        .blocks
+        .iter()
+        .map(|b| b.lobby_map.clone())
+        .collect();
+    let data = Arc::new(
+        DataStore::load(
+            settings.data_file,
+            settings.data_cache_dir,
+            lobby_maps,
+            &sql,
+        )
+        .await?,
+    );
     log::info!("Loaded server data");
-    let quests = Arc::new(Quests::load(std::mem::take(
-        &mut Arc::get_mut(&mut server_data).unwrap().quests,
-    )));
+    let scripts = Arc::new(scripts::ServerScripts::load(
+        settings.scripts_dir.as_deref(),
+    )?);
 
-    let sql = Arc::new(sql::Sql::new(&settings.db_name, master_conn).await?);
     make_block_balance(server_statuses.clone(), settings.balance_port).await?;
     let mut blocks = vec![];
+    let block_list = Arc::new(RwLock::new(vec![]));
     let mut ports = 13001;
     let mut blockstatus_lock = server_statuses.write().await;
     log::info!("Starting blocks...");
@@ -270,33 +322,72 @@ pub async fn run() -> Result<(), Error> {
             max_players: block.max_players,
             players: 0,
             lobby_map: block.lobby_map,
-            server_data: server_data.clone(),
-            quests: quests.clone(),
+            data: data.clone(),
+            scripts: scripts.clone(),
         };
         blockstatus_lock.push(new_block.clone());
         let server_statuses = server_statuses.clone();
+        let block_list = block_list.clone();
         let sql = sql.clone();
         let key = PrivateKey::Key(key.clone());
         log::debug!("Started block {}", block.name);
         blocks.push(tokio::spawn(async move {
-            match block::init_block(server_statuses, new_block, sql, key).await {
+            match block::init_block(server_statuses, block_list, new_block, sql, key).await {
                 Ok(_) => {}
                 Err(e) => log::error!("Block \"{}\" failed: {e}", block.name),
             }
         }))
     }
     drop(blockstatus_lock);
+    for announcement in settings.announcements {
+        tokio::spawn(announcements::run_scheduled(
+            block_list.clone(),
+            announcement,
+        ));
+    }
 
     log::info!("Server started.");
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    tokio::spawn(console::run(
+        block_list.clone(),
+        data.clone(),
+        sql.clone(),
+        shutdown.clone(),
+    ));
     loop {
         tokio::select! {
             biased;
             Some(action) = notif_channel.recv() => {
-                // this will later be used for other notification (e.g. EQs)
-                #[allow(clippy::single_match)]
                 match action {
                     master_ship::MasterShipAction::Ping => {
-                        sql.run_action(master_ship::MasterShipAction::Pong).await?;
+                        if let Err(e) = sql.run_action(master_ship::MasterShipAction::Pong).await {
+                            log::warn!("Failed to respond to master ship ping: {e}");
+                        }
+                    }
+                    master_ship::MasterShipAction::ServerDataChanged(version) => {
+                        if version != data.version()
+                            && let Err(e) = data.reload(&sql).await
+                        {
+                            log::error!("Failed to reload server data: {e}");
+                        }
+                    }
+                    master_ship::MasterShipAction::Announcement(announcement) => {
+                        let packet = announcements::announcement_packet(&announcement);
+                        send_to_ship(&block_list, &packet).await;
+                    }
+                    master_ship::MasterShipAction::EmergencyQuest(eq) => {
+                        let announcement = eq.announcement.clone();
+                        if data.quests().add_emergency(eq) {
+                            log::info!("Emergency quest started: {announcement}");
+                            let packet = Packet::SystemMessage(
+                                pso2packetlib::protocol::unk19::SystemMessagePacket {
+                                    message: announcement,
+                                    msg_type: pso2packetlib::protocol::unk19::MessageType::GoldenMessage,
+                                    ..Default::default()
+                                },
+                            );
+                            send_to_ship(&block_list, &packet).await;
+                        }
                     }
                     _ => {}
                 }
@@ -304,8 +395,14 @@ pub async fn run() -> Result<(), Error> {
             _ = tokio::signal::ctrl_c() => {
                 break;
             }
+            _ = shutdown.notified() => {
+                break;
+            }
         };
     }
+    log::info!("Shutting down...");
+    console::shutdown(&block_list).await;
+    log::info!("Server stopped.");
     /*
        tokio::select! {
            // we opt out of random selection because the listener is rarely accepting
@@ -1,9 +1,16 @@
 use crate::{
     BlockData, Error, User,
     battle_stats::{BattleResult, EnemyStats},
-    mutex::{Mutex, MutexGuard},
+    mutex::{Mutex, MutexGuard, RwLock},
+    party::Party,
+    quests::{self, FailReason, QuestEvent, QuestProgress, Quests},
+    scripts::{EnemyKill, LevelUp},
 };
-use data_structs::map::{EventData, MapData, NPCData, ObjectData, TransporterData, ZoneData};
+use data_structs::ServerData;
+use data_structs::map::{
+    EventData, MapData, NPCData, ObjectData, ShopData, TransporterData, ZoneData,
+};
+use data_structs::quest::EnemyScaling;
 use mlua::{Lua, LuaSerdeExt, StdLib};
 use pso2packetlib::protocol::{
     self, ObjectHeader, ObjectType, Packet, PacketType,
@@ -18,12 +25,14 @@ use pso2packetlib::protocol::{
 };
 use rand::{prelude::Distribution, seq::IteratorRandom};
 use std::{
+    cell::RefCell,
     collections::HashMap,
+    pin::Pin,
     sync::{
         Arc, Weak,
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 type ZoneId = u32;
@@ -66,11 +75,68 @@ pub enum MapType {
     QuestMap,
 }
 
+/// Maximum number of instructions a single script run can execute.
+const LUA_INSTRUCTION_LIMIT: u32 = 10_000_000;
+/// How often script limits are checked (in instructions).
+const LUA_HOOK_INTERVAL: u32 = 1000;
+/// Maximum wall time of a single script run.
+const LUA_TIME_LIMIT: Duration = Duration::from_secs(1);
+/// Maximum memory used by a map's Lua state.
+const LUA_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Maximum number of active timers in a map.
+const MAX_TIMERS: usize = 32;
+/// How often map timers are checked.
+const TIMER_RESOLUTION: Duration = Duration::from_millis(100);
+
+struct Timer {
+    name: String,
+    zone_pos: usize,
+    // player that started the timer
+    owner: PlayerId,
+    deadline: Instant,
+    // `None` for one-shot timers
+    period: Option<Duration>,
+}
+
+enum TimerAction {
+    Start {
+        name: String,
+        delay: Duration,
+        repeat: bool,
+    },
+    Stop(String),
+}
+
+/// Kill caused by dealt damage.
+enum Kill {
+    Enemy(String),
+    Player,
+}
+
+/// Actions requested by a script that are applied after it finishes.
+#[derive(Default)]
+struct ScriptActions {
+    moves: Vec<(PlayerId, String)>,
+    lobby_moves: Vec<PlayerId>,
+    enemy_spawns: Vec<(String, Position)>,
+    enemy_despawns: Vec<u32>,
+    quest_signals: Vec<String>,
+    // both `start_timer` and `stop_timer` push here
+    timers: RefCell<Vec<TimerAction>>,
+}
+
 struct LuaState {
     lua: Lua,
     // fighting with async recursion
     to_move: Vec<(PlayerId, String)>,
     to_lobby_move: Vec<PlayerId>,
+    // zone position, enemy name, spawn position
+    to_spawn: Vec<(usize, String, Position)>,
+    // zone position, enemy id
+    to_despawn: Vec<(usize, u32)>,
+    quest_signals: Vec<String>,
+    timers: Vec<Timer>,
     procs: HashMap<String, String>,
 }
 
@@ -83,18 +149,30 @@ pub struct Map {
     data: MapData,
     max_id: u32,
     block_data: Option<Arc<BlockData>>,
+    // snapshot of the data at the time the block data was set, so reloads don't affect running
+    // quests
+    server_data: Option<Arc<ServerData>>,
+    quests: Option<Arc<Quests>>,
     enemy_level: u32,
+    enemy_scaling: EnemyScaling,
+    /// Size of the largest party that has started the quest on this map.
+    party_size: usize,
     map_type: MapType,
     quest_obj: ObjectHeader,
+    quest: Option<QuestProgress>,
+    /// Set while a running quest has a time limit, so the timer task knows it has to tick.
+    quest_timed: Arc<AtomicBool>,
 }
 impl Map {
     pub fn new_from_data(mut data: MapData, map_obj_id: &AtomicU32) -> Result<Self, Error> {
-        // will be increased as needed
-        let lua_libs = StdLib::NONE;
         let lua = Arc::new(parking_lot::Mutex::new(LuaState {
-            lua: Lua::new_with(lua_libs, mlua::LuaOptions::default())?,
+            lua: new_lua()?,
             to_move: vec![],
             to_lobby_move: vec![],
+            to_spawn: vec![],
+            to_despawn: vec![],
+            quest_signals: vec![],
+            timers: vec![],
             procs: HashMap::new(),
         }));
         let map_obj = ObjectHeader {
@@ -163,30 +241,97 @@ impl Map {
             data,
             max_id: 0,
             block_data: None,
+            server_data: None,
+            quests: None,
             enemy_level: 0,
+            enemy_scaling: EnemyScaling::default(),
+            party_size: 1,
+            quest_timed: Arc::new(AtomicBool::new(false)),
             map_type: MapType::QuestMap,
             quest_obj: ObjectHeader {
                 entity_type: ObjectType::Quest,
                 ..Default::default()
             },
+            quest: None,
         };
         map.init_lua()?;
         map.find_max_id();
         log::trace!("Map {} created", map_obj.id);
         Ok(map)
     }
-    pub fn set_map_type(&mut self, map_type: MapType) {
+    pub const fn set_map_type(&mut self, map_type: MapType) {
         self.map_type = map_type;
     }
+    /// Sets the block of the map and takes a snapshot of the current server data. This should be
+    /// called only once after the map is created.
     pub fn set_block_data(&mut self, data: Arc<BlockData>) {
+        self.server_data = Some(data.server_data());
+        self.quests = Some(data.quests());
         self.block_data = Some(data);
     }
-    pub fn set_enemy_level(&mut self, level: u32) {
+    /// Returns the server data used by the map. Lobbies are never recreated, so they follow data
+    /// reloads, other maps keep using the snapshot.
+    pub fn server_data(&self) -> Option<Arc<ServerData>> {
+        match (&self.map_type, &self.block_data) {
+            (MapType::Lobby, Some(block_data)) => Some(block_data.server_data()),
+            _ => self.server_data.clone(),
+        }
+    }
+    /// Returns the quests used by the map, see [`Self::server_data`].
+    fn quests(&self) -> Option<Arc<Quests>> {
+        match (&self.map_type, &self.block_data) {
+            (MapType::Lobby, Some(block_data)) => Some(block_data.quests()),
+            _ => self.quests.clone(),
+        }
+    }
+    pub const fn set_enemy_level(&mut self, level: u32) {
         self.enemy_level = level;
     }
-    pub fn set_quest_obj(&mut self, obj: ObjectHeader) {
+    pub const fn set_enemy_scaling(&mut self, scaling: EnemyScaling) {
+        self.enemy_scaling = scaling;
+    }
+    /// Records the size of a party that has started the quest on this map. Enemies are scaled for
+    /// the largest party.
+    pub fn add_party(&mut self, size: usize) {
+        self.party_size = self.party_size.max(size);
+    }
+    /// Returns the enemy level and HP multiplier for the party size.
+    fn enemy_scale(&self) -> (u32, f32) {
+        self.enemy_scaling.scale(self.enemy_level, self.party_size)
+    }
+    pub const fn is_lobby(&self) -> bool {
+        matches!(self.map_type, MapType::Lobby)
+    }
+    /// Returns the name of the zone the player is in.
+    pub fn get_player_zone(&self, id: PlayerId) -> Option<&str> {
+        self.find_player(id)
+            .map(|pos| self.zones[pos].data.name.as_str())
+    }
+    pub fn player_count(&self) -> usize {
+        self.player_cache.len()
+    }
+    /// Returns all players on the map.
+    pub fn get_players(&self) -> Vec<Arc<Mutex<User>>> {
+        self.zones
+            .iter()
+            .flat_map(|z| &z.players)
+            .filter_map(|p| p.user.upgrade())
+            .collect()
+    }
+    pub const fn set_quest_obj(&mut self, obj: ObjectHeader) {
         self.quest_obj = obj;
     }
+    pub fn set_quest_progress(&mut self, progress: QuestProgress) {
+        self.quest_timed
+            .store(progress.has_time_limit(), Ordering::Relaxed);
+        self.quest = Some(progress);
+    }
+    /// Checks if the quest on the map hasn't been cleared or failed yet.
+    pub fn is_quest_running(&self) -> bool {
+        self.quest
+            .as_ref()
+            .is_none_or(|q| !q.is_cleared() && !q.is_failed())
+    }
     fn find_max_id(&mut self) {
         let obj_max = self
             .data
@@ -356,8 +501,9 @@ impl Map {
         };
         let p_id = new_player.lock().await.get_user_id();
         zone.add_player(new_player.clone()).await?;
+        let zone_name = zone.data.name.clone();
         self.player_cache.push((p_id, pos));
-        Ok(())
+        self.quest_event(QuestEvent::ZoneReached(&zone_name)).await
     }
 
     pub async fn remove_player(&mut self, id: PlayerId) -> Option<Arc<Mutex<User>>> {
@@ -410,11 +556,12 @@ impl Map {
         name: &str,
         pos: Position,
     ) -> Result<(), Error> {
-        let Some(block_data) = self.block_data.to_owned() else {
+        let Some(server_data) = self.server_data() else {
             return Err(Error::NoEnemyData(name.to_string()));
         };
+        let enemy_scale = self.enemy_scale();
         self.zones[zone_pos]
-            .spawn_enemy(&block_data, &mut self.max_id, self.enemy_level, name, pos)
+            .spawn_enemy(&server_data, &mut self.max_id, enemy_scale, name, pos)
             .await?;
         Ok(())
     }
@@ -423,10 +570,142 @@ impl Map {
         zone_pos: usize,
         dmg: DealDamagePacket,
     ) -> Result<(), Error> {
-        let Some(block_data) = self.block_data.to_owned() else {
+        let (Some(block_data), Some(server_data)) =
+            (self.block_data.to_owned(), self.server_data())
+        else {
             return Err(Error::InvalidInput("deal_damage"));
         };
-        self.zones[zone_pos].deal_damage(block_data, dmg).await
+        match self.zones[zone_pos]
+            .deal_damage(block_data, &server_data, dmg)
+            .await?
+        {
+            Some(Kill::Enemy(name)) => self.quest_event(QuestEvent::EnemyKilled(&name)).await,
+            Some(Kill::Player) => self.quest_event(QuestEvent::PlayerDied).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the player to the campship (or the lobby if the map has no campship) and shows quest
+    /// results if the quest was cleared.
+    pub async fn return_to_campship(&mut self, id: PlayerId) -> Result<(), Error> {
+        let Some(player) = self
+            .find_player(id)
+            .and_then(|pos| self.zones[pos].players.iter().find(|p| p.player_id == id))
+            .and_then(|p| p.user.upgrade())
+        else {
+            return Err(Error::NoUserInMap(id, self.data.map_data.unk7.to_string()));
+        };
+        if self.zones.iter().any(|z| z.data.name == "campship") {
+            self.move_player_named(id, "campship").await?;
+        } else {
+            self.move_to_lobby(id).await?;
+        }
+        if let Some(result) = self.quest.as_mut().and_then(|q| q.take_result(id)) {
+            player
+                .lock()
+                .await
+                .send_packet(&Packet::QuestResult(result))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn quest_event(&mut self, event: QuestEvent<'_>) -> Result<(), Error> {
+        let Some(quest) = self.quest.as_mut() else {
+            return Ok(());
+        };
+        quest.handle_event(event);
+        if let Some(reason) = quest.take_fail() {
+            return self.fail_quest(reason).await;
+        }
+        let Some(clear_time) = quest.take_clear() else {
+            return Ok(());
+        };
+        self.clear_quest(clear_time).await
+    }
+
+    async fn clear_quest(&mut self, clear_time: Duration) -> Result<(), Error> {
+        let Some(quest) = self.quest.as_ref() else {
+            return Ok(());
+        };
+        let info = quest.info();
+        log::debug!("Quest {} cleared in {}s", info.id, clear_time.as_secs_f32());
+        let players: Vec<_> = self
+            .zones
+            .iter()
+            .flat_map(|z| &z.players)
+            .filter_map(|p| p.user.upgrade())
+            .collect();
+        let mut records = vec![];
+        for player in players {
+            let mut lock = player.lock().await;
+            let Some(quest) = self.quest.as_mut() else {
+                unreachable!("Quest was checked before");
+            };
+            records.extend(quest.new_record(&lock, clear_time));
+            let (packets, level_ups) = match quest.reward(&mut lock, clear_time) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::warn!("Failed to reward player {}: {e}", lock.get_user_id());
+                    continue;
+                }
+            };
+            for packet in packets {
+                let _ = lock.try_send_packet(&packet);
+            }
+            let _ = lock.try_send_packet(&system_msg(
+                "Quest cleared! Return to the campship to see the results.".into(),
+            ));
+            if let Some(quests) = self.quests()
+                && !quests.update_unlocks(&mut lock).is_empty()
+            {
+                let _ = lock.try_send_packet(&system_msg(
+                    "New quests are available at the quest counter.".into(),
+                ));
+            }
+            if let Some(block_data) = &self.block_data {
+                let scripts = &block_data.scripts;
+                scripts.run("on_quest_clear", &mut lock, &info).await;
+                for data in level_ups {
+                    scripts.run("on_level_up", &mut lock, &data).await;
+                }
+            }
+        }
+        if let Some(block_data) = &self.block_data {
+            tokio::spawn(quests::save_records(block_data.sql.clone(), records));
+        }
+        Ok(())
+    }
+
+    /// Notifies players about the failure and abandons the quest of their parties, which moves
+    /// them to the lobby and releases this map.
+    async fn fail_quest(&mut self, reason: FailReason) -> Result<(), Error> {
+        let Some(quest) = self.quest.as_ref() else {
+            return Ok(());
+        };
+        let info = quest.info();
+        log::debug!("Quest {} failed: {reason}", info.id);
+        let players = self.get_players();
+        let message = system_msg(format!("Quest failed: {reason}."));
+        let mut parties: Vec<Arc<RwLock<Party>>> = vec![];
+        for player in players {
+            let mut lock = player.lock().await;
+            let _ = lock.try_send_packet(&message);
+            if let Some(block_data) = &self.block_data {
+                block_data
+                    .scripts
+                    .run("on_quest_fail", &mut lock, &info)
+                    .await;
+            }
+            if let Some(party) = lock.get_current_party()
+                && !parties.iter().any(|p| Arc::ptr_eq(p, &party))
+            {
+                parties.push(party);
+            }
+        }
+        // abandoning locks the map, so it can't be done here
+        tokio::spawn(abandon_quests(parties));
+        Ok(())
     }
 
     pub async fn minimap_reveal(
@@ -435,21 +714,22 @@ impl Map {
         sender_id: PlayerId,
         packet: protocol::questlist::MinimapRevealRequestPacket,
     ) -> Result<(), Error> {
-        let Some(block_data) = self.block_data.to_owned() else {
+        let Some(server_data) = self.server_data() else {
             return Err(Error::InvalidInput("minimap_reveal: no block data"));
         };
+        let enemy_scale = self.enemy_scale();
 
         self.zones[zone_pos]
             .minimap_reveal(
                 sender_id,
-                &block_data,
+                &server_data,
                 &mut self.max_id,
-                self.enemy_level,
+                enemy_scale,
                 &packet,
             )
             .await?;
 
-        self.check_move_lua().await?;
+        self.check_lua_actions().await?;
         Ok(())
     }
 
@@ -460,7 +740,16 @@ impl Map {
         sender_id: PlayerId,
     ) -> Result<(), Error> {
         let zone_id = self.zones[zone_pos].srv_zone_id;
-        let Some(lua_data) = self
+        if let Some(shop) = self
+            .data
+            .npcs
+            .iter()
+            .find(|n| n.zone_id == zone_id && n.data.object.id == packet.object1.id)
+            .and_then(|n| n.shop.clone())
+        {
+            self.zones[zone_pos].open_shop(sender_id, shop).await;
+        }
+        let Some((name, lua_data)) = self
             .data
             .objects
             .iter()
@@ -474,12 +763,22 @@ impl Map {
                     .map(|x| (x.data.object.id, &x.data.name)),
             )
             .find(|(id, _)| *id == packet.object1.id)
-            .and_then(|(_, name)| self.lua.lock().procs.get(name.as_str()).cloned())
+            .and_then(|(_, name)| {
+                let lua = self.lua.lock().procs.get(name.as_str()).cloned()?;
+                Some((name.clone(), lua))
+            })
         else {
             return Ok(());
         };
-        self.run_lua(sender_id, zone_pos, &packet, "interaction", &lua_data)
-            .await?;
+        self.run_lua(
+            sender_id,
+            zone_pos,
+            &packet,
+            "interaction",
+            (&name, &lua_data),
+        )
+        .await?;
+        self.check_lua_actions().await?;
         Ok(())
     }
     pub async fn on_questwork(
@@ -491,9 +790,15 @@ impl Map {
         let Some(lua) = self.lua.lock().procs.get("on_questwork").cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_pos, &packet, "on_questwork", &lua)
-            .await?;
-        self.check_move_lua().await?;
+        self.run_lua(
+            player,
+            zone_pos,
+            &packet,
+            "on_questwork",
+            ("on_questwork", &lua),
+        )
+        .await?;
+        self.check_lua_actions().await?;
         Ok(())
     }
     pub async fn on_cutscene_end(
@@ -505,9 +810,15 @@ impl Map {
         let Some(lua) = self.lua.lock().procs.get("on_cutscene_end").cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_pos, &packet, "on_cutscene_end", &lua)
-            .await?;
-        self.check_move_lua().await?;
+        self.run_lua(
+            player,
+            zone_pos,
+            &packet,
+            "on_cutscene_end",
+            ("on_cutscene_end", &lua),
+        )
+        .await?;
+        self.check_lua_actions().await?;
         Ok(())
     }
 
@@ -515,11 +826,105 @@ impl Map {
         let Some(lua) = self.lua.lock().procs.get("on_map_loaded").cloned() else {
             return Ok(());
         };
-        self.run_lua(player, zone_pos, &Packet::None, "on_map_loaded", &lua)
-            .await?;
-        self.check_move_lua().await?;
+        self.run_lua(
+            player,
+            zone_pos,
+            &Packet::None,
+            "on_map_loaded",
+            ("on_map_loaded", &lua),
+        )
+        .await?;
+        self.check_lua_actions().await?;
         Ok(())
     }
+    /// Starts a task that fires timers created by map scripts.
+    ///
+    /// The task stops when the map is dropped. The map is only locked when a script timer or a
+    /// quest time limit is pending.
+    pub fn start_timers(map: &Arc<Mutex<Self>>) {
+        let map = Arc::downgrade(map);
+        tokio::spawn(async move {
+            let (lua, quest_timed) = {
+                let Some(map) = map.upgrade() else {
+                    return;
+                };
+                let lock = map.lock().await;
+                (lock.lua.clone(), lock.quest_timed.clone())
+            };
+            let start = tokio::time::Instant::now() + TIMER_RESOLUTION;
+            let mut interval = tokio::time::interval_at(start, TIMER_RESOLUTION);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(map) = map.upgrade() else {
+                    return;
+                };
+                // lua is busy while a script runs, in that case fall back to locking the map
+                let no_timers = lua.try_lock().is_some_and(|l| l.timers.is_empty());
+                if no_timers && !quest_timed.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let mut lock = map.lock().await;
+                if let Err(e) = lock.tick_timers().await {
+                    log::warn!("Map {} timer failed: {e}", lock.data.map_data.map_object.id);
+                }
+            }
+        });
+    }
+
+    async fn tick_timers(&mut self) -> Result<(), Error> {
+        self.quest_event(QuestEvent::Tick).await?;
+        if !self.is_quest_running() {
+            self.quest_timed.store(false, Ordering::Relaxed);
+        }
+        let now = Instant::now();
+        let mut due = vec![];
+        let mut lua = self.lua.lock();
+        lua.timers.retain_mut(|t| {
+            if t.deadline > now {
+                return true;
+            }
+            due.push((t.name.clone(), t.zone_pos, t.owner));
+            match t.period {
+                Some(period) => {
+                    t.deadline = now + period;
+                    true
+                }
+                None => false,
+            }
+        });
+        if due.is_empty() {
+            return Ok(());
+        }
+        let proc = lua.procs.get("on_timer").cloned();
+        drop(lua);
+        let Some(proc) = proc else {
+            return Ok(());
+        };
+        for (name, zone_pos, owner) in due {
+            // prefer the player that started the timer, otherwise anyone in the zone
+            let players = &self.zones[zone_pos].players;
+            let sender = players
+                .iter()
+                .filter(|p| p.user.strong_count() != 0)
+                .map(|p| p.player_id)
+                .find(|id| *id == owner)
+                .or_else(|| {
+                    players
+                        .iter()
+                        .find(|p| p.user.strong_count() != 0)
+                        .map(|p| p.player_id)
+                });
+            // nobody to run the script for
+            let Some(sender) = sender else {
+                continue;
+            };
+            self.run_lua(sender, zone_pos, &name, "on_timer", ("on_timer", &proc))
+                .await?;
+        }
+        self.check_lua_actions().await
+    }
+
     pub fn get_close_objects<F>(&self, zone_pos: usize, pred: F) -> Vec<ObjectSpawnPacket>
     where
         F: Fn(&Position) -> bool,
@@ -541,24 +946,36 @@ impl Map {
         zone_id: usize,
         packet: &S,
         call_type: &str,
-        lua_data: &str,
+        script: (&str, &str),
     ) -> Result<(), Error> {
         spawn_blocking(|| {
-            self.zones[zone_id].run_lua_blocking(sender_id, packet, call_type, lua_data)
+            self.zones[zone_id].run_lua_blocking(sender_id, packet, call_type, script)
         })
         .await?
     }
-    async fn check_move_lua(&mut self) -> Result<(), Error> {
+    async fn check_lua_actions(&mut self) -> Result<(), Error> {
         let mut lua = self.lua.lock();
         let to_move: Vec<_> = lua.to_move.drain(..).collect();
         let to_lobby_move: Vec<_> = lua.to_lobby_move.drain(..).collect();
+        let to_spawn: Vec<_> = lua.to_spawn.drain(..).collect();
+        let to_despawn: Vec<_> = lua.to_despawn.drain(..).collect();
+        let quest_signals: Vec<_> = lua.quest_signals.drain(..).collect();
         drop(lua);
+        for (zone_pos, name, pos) in to_spawn {
+            self.spawn_enemy(zone_pos, &name, pos).await?;
+        }
+        for (zone_pos, id) in to_despawn {
+            self.zones[zone_pos].despawn_enemy(id).await;
+        }
         for (player, zone) in to_move {
             self.move_player_named(player, &zone).await?;
         }
         for player in to_lobby_move {
             self.move_to_lobby(player).await?;
         }
+        for signal in quest_signals {
+            self.quest_event(QuestEvent::Signal(&signal)).await?;
+        }
         Ok(())
     }
 }
@@ -675,8 +1092,13 @@ impl Zone {
         let Some(lua) = self.lua.lock().procs.get("on_player_load").cloned() else {
             return Ok(());
         };
-        self.run_lua(np_id, &Packet::None, "on_player_load", &lua)
-            .await?;
+        self.run_lua(
+            np_id,
+            &Packet::None,
+            "on_player_load",
+            ("on_player_load", &lua),
+        )
+        .await?;
 
         Ok(())
     }
@@ -699,9 +1121,10 @@ impl Zone {
                 globals.set("data", obj.data.data.as_slice())?;
                 globals.set("call_type", "to_vita")?;
                 globals.set("size", obj.data.data.len())?;
-                let chunk = lua.load(lua_code);
-                chunk.exec()?;
-                obj.data.data = globals.get::<Vec<u32>>("data")?.into();
+                match exec_limited(lua, &obj.data.name, lua_code) {
+                    Ok(()) => obj.data.data = globals.get::<Vec<u32>>("data")?.into(),
+                    Err(e) => log::warn!("Script {} failed: {e}", obj.data.name),
+                }
                 globals.raw_remove("data")?;
                 globals.raw_remove("call_type")?;
                 globals.raw_remove("size")?;
@@ -884,15 +1307,16 @@ impl Zone {
 
     async fn spawn_enemy(
         &mut self,
-        block_data: &BlockData,
+        server_data: &ServerData,
         max_id: &mut u32,
-        enemy_lvl: u32,
+        (enemy_lvl, hp_mul): (u32, f32),
         name: &str,
         pos: Position,
     ) -> Result<(), Error> {
         let id = *max_id + 1;
         *max_id += 1;
-        let data = EnemyStats::build(name, enemy_lvl, pos, &block_data.server_data)?;
+        let mut data = EnemyStats::build(name, enemy_lvl, pos, server_data)?;
+        data.scale_hp(hp_mul);
         let (packet, mut packet2) = Zone::prepare_enemy_packets(id, &data);
         self.enemies.push((id, data));
 
@@ -909,11 +1333,51 @@ impl Zone {
         Ok(())
     }
 
+    async fn open_shop(&self, player_id: PlayerId, shop: ShopData) {
+        let Some(user) = self
+            .players
+            .iter()
+            .find(|p| p.player_id == player_id)
+            .and_then(|p| p.user.upgrade())
+        else {
+            return;
+        };
+        let mut user = user.lock().await;
+        let message = system_msg(format!(
+            "Welcome to {}! Use !shop to see the items, !buy and !sell to trade.",
+            shop.name
+        ));
+        let _ = user.try_send_packet(&message);
+        user.shop = Some(shop);
+    }
+
+    async fn despawn_enemy(&mut self, enemy_id: u32) {
+        let Some(pos) = self.enemies.iter().position(|(id, _)| *id == enemy_id) else {
+            return;
+        };
+        self.enemies.remove(pos);
+        let item = ObjectHeader {
+            id: enemy_id,
+            entity_type: ObjectType::Object,
+            ..Default::default()
+        };
+        exec_users(&self.players, |_, mut player| {
+            let packet = Packet::DespawnObject(protocol::objects::DespawnObjectPacket {
+                player: player.create_object_header(),
+                item,
+            });
+            let _ = player.try_send_packet(&packet);
+        })
+        .await;
+    }
+
+    /// Returns the name of the killed enemy.
     async fn deal_damage(
         &mut self,
         block_data: Arc<BlockData>,
+        server_data: &ServerData,
         dmg: DealDamagePacket,
-    ) -> Result<(), Error> {
+    ) -> Result<Option<Kill>, Error> {
         let (inflicter, target) = (dmg.inflicter, dmg.target);
         if inflicter.entity_type == ObjectType::Player && target.entity_type == ObjectType::Object {
             let Some((enemy_pos, (_, target))) = self
@@ -922,7 +1386,7 @@ impl Zone {
                 .enumerate()
                 .find(|(_, (id, _))| *id == target.id)
             else {
-                return Ok(());
+                return Ok(None);
             };
             let Some(inflicter) = self
                 .players
@@ -935,7 +1399,7 @@ impl Zone {
             let mut lock = inflicter.lock().await;
             let result = lock
                 .get_stats_mut()
-                .damage_enemy(target, &block_data.server_data, dmg)?;
+                .damage_enemy(target, server_data, dmg)?;
             drop(lock);
             match result {
                 BattleResult::Damaged { dmg_packet } => {
@@ -962,8 +1426,18 @@ impl Zone {
                     let mut dmg_packet = Packet::DamageReceive(dmg_packet);
                     let mut kill_packet = Packet::EnemyKilled(kill_packet);
                     let mut exp_packets = vec![];
-                    exec_users(&self.players, |_, mut player| {
-                        exp_packets.push(player.add_exp(exp_amount))
+                    let mut level_ups = vec![];
+                    exec_users(&self.players, |p, mut player| {
+                        let Some(old_levels) = player.get_class_levels() else {
+                            return;
+                        };
+                        let result = player.add_exp(exp_amount);
+                        if let Ok(exp) = &result {
+                            for data in LevelUp::from_exp(old_levels, exp) {
+                                level_ups.push((p.user.clone(), data));
+                            }
+                        }
+                        exp_packets.push(result)
                     })
                     .await;
                     let exp_packets = exp_packets.into_iter().collect::<Result<Vec<_>, _>>()?;
@@ -990,7 +1464,22 @@ impl Zone {
                         }
                     })
                     .await;
-                    self.enemies.remove(enemy_pos);
+                    let (enemy_id, enemy) = self.enemies.remove(enemy_pos);
+                    let kill = EnemyKill {
+                        id: enemy_id,
+                        name: enemy.get_name().to_string(),
+                        exp: exp_amount,
+                    };
+                    let scripts = &block_data.scripts;
+                    let mut lock = inflicter.lock().await;
+                    scripts.run("on_enemy_kill", &mut lock, &kill).await;
+                    drop(lock);
+                    for (user, data) in level_ups {
+                        scripts
+                            .run("on_level_up", &mut *user.lock().await, &data)
+                            .await;
+                    }
+                    return Ok(Some(Kill::Enemy(kill.name)));
                 }
             }
         } else if inflicter.entity_type == ObjectType::Object
@@ -1006,11 +1495,10 @@ impl Zone {
             };
             let Some((_, inflicter)) = self.enemies.iter_mut().find(|(id, _)| *id == inflicter.id)
             else {
-                return Ok(());
+                return Ok(None);
             };
             let mut lock = target.lock().await;
-            let result =
-                inflicter.damage_player(lock.get_stats_mut(), &block_data.server_data, dmg)?;
+            let result = inflicter.damage_player(lock.get_stats_mut(), server_data, dmg)?;
             drop(lock);
 
             match result {
@@ -1033,19 +1521,19 @@ impl Zone {
                         }
                     })
                     .await;
-                    todo!();
+                    return Ok(Some(Kill::Player));
                 }
             }
         }
 
-        Ok(())
+        Ok(None)
     }
     async fn minimap_reveal(
         &mut self,
         sender_id: PlayerId,
-        block_data: &BlockData,
+        server_data: &ServerData,
         max_id: &mut u32,
-        enemy_lvl: u32,
+        enemy_scale: (u32, f32),
         packet: &protocol::questlist::MinimapRevealRequestPacket,
     ) -> Result<(), Error> {
         let Some(user) = self
@@ -1102,9 +1590,9 @@ impl Zone {
                             if let Some(enemy) = enemy {
                                 let enemy_name = enemy.enemy_name.clone();
                                 self.spawn_enemy(
-                                    block_data,
+                                    server_data,
                                     max_id,
-                                    enemy_lvl,
+                                    enemy_scale,
                                     &enemy_name,
                                     spawn_point,
                                 )
@@ -1157,9 +1645,9 @@ impl Zone {
                             if let Some(enemy) = enemy {
                                 let enemy_name = enemy.enemy_name.clone();
                                 self.spawn_enemy(
-                                    block_data,
+                                    server_data,
                                     max_id,
-                                    enemy_lvl,
+                                    enemy_scale,
                                     &enemy_name,
                                     spawn_point,
                                 )
@@ -1171,8 +1659,13 @@ impl Zone {
                 data_structs::map::EnemySpawnType::Manual => {
                     let proc = self.lua.lock().procs.get("spawn_enemy").cloned();
                     if let Some(lua) = proc {
-                        self.run_lua(user.player_id, &packet, "spawn_enemy", &lua)
-                            .await?;
+                        self.run_lua(
+                            user.player_id,
+                            &packet,
+                            "spawn_enemy",
+                            ("spawn_enemy", &lua),
+                        )
+                        .await?;
                     };
                 }
             }
@@ -1209,8 +1702,13 @@ impl Zone {
 
         let proc = self.lua.lock().procs.get("on_minimap_reveal").cloned();
         if let Some(lua) = proc {
-            self.run_lua(user.player_id, &packet, "on_minimap_reveal", &lua)
-                .await?;
+            self.run_lua(
+                user.player_id,
+                &packet,
+                "on_minimap_reveal",
+                ("on_minimap_reveal", &lua),
+            )
+            .await?;
         };
 
         Ok(())
@@ -1221,9 +1719,23 @@ impl Zone {
         sender_id: PlayerId,
         packet: &S,
         call_type: &str,
-        lua_data: &str,
+        script: (&str, &str),
     ) -> Result<(), Error> {
-        spawn_blocking(|| self.run_lua_blocking(sender_id, packet, call_type, lua_data)).await?
+        spawn_blocking(|| self.run_lua_blocking(sender_id, packet, call_type, script)).await?
+    }
+
+    fn get_player(&self, id: PlayerId) -> Result<Arc<Mutex<User>>, mlua::Error> {
+        self.players
+            .iter()
+            .find(|p| p.player_id == id)
+            .and_then(|p| p.user.upgrade())
+            .ok_or(mlua::Error::runtime("Couldn't find requested player"))
+    }
+
+    fn send_to_all_blocking(&self, packet: &Packet) {
+        for player in self.players.iter().filter_map(|p| p.user.upgrade()) {
+            let _ = player.lock_blocking().try_send_packet(packet);
+        }
     }
 
     fn run_lua_blocking<S: serde::Serialize + Sync>(
@@ -1231,10 +1743,9 @@ impl Zone {
         sender_id: PlayerId,
         packet: &S,
         call_type: &str,
-        lua_data: &str,
+        (script_name, lua_data): (&str, &str),
     ) -> Result<(), Error> {
-        let mut scheduled_move = vec![];
-        let mut lobby_moves = vec![];
+        let mut actions = ScriptActions::default();
 
         let Some(caller) = self
             .players
@@ -1258,7 +1769,7 @@ impl Zone {
             globals.set("players", player_ids)?;
             globals.set("call_type", call_type)?;
             lua.scope(|scope| {
-                self.setup_scope(&globals, scope, &mut scheduled_move, &mut lobby_moves)?;
+                self.setup_scope(&globals, scope, &mut actions)?;
 
                 /* LUA FUNCTIONS */
 
@@ -1283,8 +1794,10 @@ impl Zone {
 
                 /* LUA FUNCTIONS END */
 
-                let chunk = lua.load(lua_data);
-                chunk.exec()?;
+                // script errors shouldn't affect the map
+                if let Err(e) = exec_limited(lua, script_name, lua_data) {
+                    log::warn!("Script {script_name} in zone {} failed: {e}", zone_set.name);
+                }
                 Ok(())
             })?;
             globals.raw_remove("packet")?;
@@ -1293,12 +1806,42 @@ impl Zone {
             globals.raw_remove("call_type")?;
             globals.raw_remove("zone")?;
         }
-        for (receiver, mapid) in scheduled_move {
+        for (receiver, mapid) in actions.moves {
             lua_lock.to_move.push((receiver, mapid));
         }
-        for receiver in lobby_moves {
+        for receiver in actions.lobby_moves {
             lua_lock.to_lobby_move.push(receiver);
         }
+        for (name, pos) in actions.enemy_spawns {
+            lua_lock.to_spawn.push((self.zone_pos, name, pos));
+        }
+        for id in actions.enemy_despawns {
+            lua_lock.to_despawn.push((self.zone_pos, id));
+        }
+        lua_lock.quest_signals.append(&mut actions.quest_signals);
+        for action in actions.timers.into_inner() {
+            match action {
+                TimerAction::Start {
+                    name,
+                    delay,
+                    repeat,
+                } => {
+                    lua_lock.timers.retain(|t| t.name != name);
+                    if lua_lock.timers.len() >= MAX_TIMERS {
+                        log::warn!("Map {}: too many timers, ignoring {name}", self.data.name);
+                        continue;
+                    }
+                    lua_lock.timers.push(Timer {
+                        name,
+                        zone_pos: self.zone_pos,
+                        owner: sender_id,
+                        deadline: Instant::now() + delay,
+                        period: repeat.then_some(delay),
+                    });
+                }
+                TimerAction::Stop(name) => lua_lock.timers.retain(|t| t.name != name),
+            }
+        }
         Ok(())
     }
 
@@ -1306,9 +1849,18 @@ impl Zone {
         &'s self,
         globals: &mlua::Table,
         scope: &'s mlua::Scope<'s, '_>,
-        scheduled_move: &'s mut Vec<(PlayerId, String)>,
-        lobby_moves: &'s mut Vec<PlayerId>,
+        actions: &'s mut ScriptActions,
     ) -> Result<(), mlua::Error> {
+        let ScriptActions {
+            moves,
+            lobby_moves,
+            enemy_spawns,
+            enemy_despawns,
+            quest_signals,
+            timers,
+        } = actions;
+        let timers = &*timers;
+
         /* LUA FUNCTIONS */
 
         // send packet
@@ -1376,7 +1928,7 @@ impl Zone {
         globals.set(
             "move_player",
             scope.create_function_mut(|_, (receiver, zone): (u32, String)| {
-                scheduled_move.push((receiver, zone));
+                moves.push((receiver, zone));
                 Ok(())
             })?,
         )?;
@@ -1495,11 +2047,283 @@ impl Zone {
             )?,
         )?;
 
+        // start a timer that calls `on_timer` with the timer name as the packet
+        globals.set(
+            "start_timer",
+            scope.create_function(|_, (name, seconds, repeat): (String, f64, Option<bool>)| {
+                if !seconds.is_finite() || seconds < 0.0 {
+                    return Err(mlua::Error::runtime("Invalid timer delay"));
+                }
+                let repeat = repeat.unwrap_or(false);
+                let mut delay = Duration::from_secs_f64(seconds);
+                if repeat {
+                    delay = delay.max(TIMER_RESOLUTION);
+                }
+                timers.borrow_mut().push(TimerAction::Start {
+                    name,
+                    delay,
+                    repeat,
+                });
+                Ok(())
+            })?,
+        )?;
+        // stop a timer
+        globals.set(
+            "stop_timer",
+            scope.create_function(|_, name: String| {
+                timers.borrow_mut().push(TimerAction::Stop(name));
+                Ok(())
+            })?,
+        )?;
+        // spawn an enemy, the enemy is spawned after the script finishes
+        globals.set(
+            "spawn_enemy",
+            scope.create_function_mut(|lua, (name, pos): (String, mlua::Value)| {
+                let pos: Position = lua.from_value(pos)?;
+                enemy_spawns.push((name, pos));
+                Ok(())
+            })?,
+        )?;
+        // despawn an enemy, the enemy is despawned after the script finishes
+        globals.set(
+            "despawn_enemy",
+            scope.create_function_mut(|_, id: u32| {
+                enemy_despawns.push(id);
+                Ok(())
+            })?,
+        )?;
+        // signal a quest condition, the signal is processed after the script finishes
+        globals.set(
+            "quest_signal",
+            scope.create_function_mut(|_, name: String| {
+                quest_signals.push(name);
+                Ok(())
+            })?,
+        )?;
+        // get enemies in the zone
+        globals.set(
+            "get_enemies",
+            scope.create_function(move |lua, ()| {
+                let enemies = lua.create_table()?;
+                for (id, enemy) in &self.enemies {
+                    let data = lua.create_table()?;
+                    data.set("id", *id)?;
+                    data.set("name", enemy.get_name())?;
+                    data.set("position", lua.to_value(&enemy.get_position())?)?;
+                    enemies.push(data)?;
+                }
+                Ok(enemies)
+            })?,
+        )?;
+        // get account flag of a player
+        globals.set(
+            "get_player_account_flag",
+            scope.create_function(move |_, (receiver, flag): (u32, u32)| -> Result<u8, _> {
+                let p = self.get_player(receiver)?;
+                Ok(p.lock_blocking().get_account_flags().get(flag as _))
+            })?,
+        )?;
+        // get character flag of a player
+        globals.set(
+            "get_player_character_flag",
+            scope.create_function(move |_, (receiver, flag): (u32, u32)| -> Result<u8, _> {
+                let p = self.get_player(receiver)?;
+                let flags = p.lock_blocking().get_char_flags();
+                flags
+                    .map(|f| f.get(flag as _))
+                    .ok_or(mlua::Error::runtime("Player has no loaded character"))
+            })?,
+        )?;
+        // get player position
+        globals.set(
+            "get_player_position",
+            scope.create_function(move |lua, receiver: u32| {
+                let p = self.get_player(receiver)?;
+                let pos = p.lock_blocking().position;
+                lua.to_value(&pos)
+            })?,
+        )?;
+        // get ids of players in the same party as the player
+        globals.set(
+            "get_party_members",
+            scope.create_function(move |_, receiver: u32| -> Result<Vec<u32>, _> {
+                let p = self.get_player(receiver)?;
+                let party = p.lock_blocking().get_current_party();
+                Ok(party
+                    .map(|p| p.read_blocking().get_player_ids())
+                    .unwrap_or_default())
+            })?,
+        )?;
+        // send system message to a player
+        globals.set(
+            "send_message",
+            scope.create_function(move |_, (receiver, msg): (u32, String)| {
+                if let Ok(p) = self.get_player(receiver) {
+                    p.lock_blocking()
+                        .send_packet_block(&system_msg(msg))
+                        .map_err(mlua::Error::external)?;
+                }
+                Ok(())
+            })?,
+        )?;
+        // send system message to all players in the zone
+        globals.set(
+            "broadcast_message",
+            scope.create_function(move |_, msg: String| {
+                self.send_to_all_blocking(&system_msg(msg));
+                Ok(())
+            })?,
+        )?;
+        // send chat message from a player to all players in the zone
+        globals.set(
+            "broadcast_chat",
+            scope.create_function(move |_, (sender, msg): (u32, String)| {
+                let packet = Packet::ChatMessage(protocol::chat::ChatMessage {
+                    object: ObjectHeader {
+                        id: sender,
+                        entity_type: ObjectType::Player,
+                        ..Default::default()
+                    },
+                    message: msg,
+                    ..Default::default()
+                });
+                self.send_to_all_blocking(&packet);
+                Ok(())
+            })?,
+        )?;
+        // start a cutscene for a player
+        globals.set(
+            "start_cutscene",
+            scope.create_function(move |_, (receiver, name): (u32, String)| {
+                if let Ok(p) = self.get_player(receiver) {
+                    let packet = Packet::StartCutscene(protocol::questlist::StartCutscenePacket {
+                        scene_name: name.into(),
+                        ..Default::default()
+                    });
+                    p.lock_blocking()
+                        .send_packet_block(&packet)
+                        .map_err(mlua::Error::external)?;
+                }
+                Ok(())
+            })?,
+        )?;
+        // change state of an object, npc, event or transporter for all players in the zone
+        globals.set(
+            "set_object_state",
+            scope.create_function(move |_, (id, attribute): (u32, String)| {
+                let object = self
+                    .objects
+                    .objects
+                    .iter()
+                    .map(|x| x.data.object)
+                    .chain(self.objects.npcs.iter().map(|x| x.data.object))
+                    .chain(self.objects.events.iter().map(|x| x.data.object))
+                    .chain(self.objects.transporters.iter().map(|x| x.data.object))
+                    .find(|obj| obj.id == id)
+                    .ok_or(mlua::Error::runtime("Couldn't find requested object"))?;
+                for player in self.players.iter().filter_map(|p| p.user.upgrade()) {
+                    let mut player = player.lock_blocking();
+                    let packet = Packet::SetTag(protocol::objects::SetTagPacket {
+                        receiver: player.create_object_header(),
+                        target: object,
+                        object3: object,
+                        attribute: attribute.as_str().into(),
+                        ..Default::default()
+                    });
+                    let _ = player.try_send_packet(&packet);
+                }
+                Ok(())
+            })?,
+        )?;
+
         /* LUA FUNCTIONS END */
         Ok(())
     }
 }
 
+/// Creates a Lua state for map and server scripts.
+///
+/// Only `string`, `table` and `math` libraries are available and functions that can load
+/// code from files or bytecode are removed.
+///
+/// Fails if the Lua backend doesn't support the memory limit, so scripts never run without it.
+pub(crate) fn new_lua() -> Result<Lua, mlua::Error> {
+    let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH;
+    // hooks are not called from jit compiled code, so it has to be disabled for limits to work
+    #[cfg(not(any(target_env = "musl", target_arch = "arm")))]
+    let libs = libs | StdLib::JIT;
+    let lua = Lua::new_with(libs, mlua::LuaOptions::default())?;
+    #[cfg(not(any(target_env = "musl", target_arch = "arm")))]
+    lua.load("jit.off()").exec()?;
+    let globals = lua.globals();
+    for name in ["dofile", "loadfile", "load", "loadstring", "jit"] {
+        globals.raw_remove(name)?;
+    }
+    globals.get::<mlua::Table>("string")?.raw_remove("dump")?;
+    lua.set_memory_limit(LUA_MEMORY_LIMIT)?;
+    Ok(lua)
+}
+
+/// Runs a script with instruction and time limits.
+fn exec_limited(lua: &Lua, name: &str, code: &str) -> Result<(), mlua::Error> {
+    eval_limited(lua, name, code)
+}
+
+/// Runs a script with instruction and time limits and returns its result.
+pub(crate) fn eval_limited<R: mlua::FromLuaMulti>(
+    lua: &Lua,
+    name: &str,
+    code: &str,
+) -> Result<R, mlua::Error> {
+    run_limited(lua, || lua.load(code).set_name(format!("={name}")).call(()))
+}
+
+/// Runs a function that calls into Lua with instruction and time limits.
+pub(crate) fn run_limited<R>(
+    lua: &Lua,
+    func: impl FnOnce() -> Result<R, mlua::Error>,
+) -> Result<R, mlua::Error> {
+    let start = Instant::now();
+    let count = AtomicU32::new(0);
+    lua.set_hook(
+        mlua::HookTriggers::new().every_nth_instruction(LUA_HOOK_INTERVAL),
+        move |_, _| {
+            let count = count.fetch_add(1, Ordering::Relaxed) + 1;
+            if count >= LUA_INSTRUCTION_LIMIT / LUA_HOOK_INTERVAL {
+                Err(mlua::Error::runtime("instruction limit exceeded"))
+            } else if start.elapsed() > LUA_TIME_LIMIT {
+                Err(mlua::Error::runtime("time limit exceeded"))
+            } else {
+                Ok(mlua::VmState::Continue)
+            }
+        },
+    );
+    let result = func();
+    lua.remove_hook();
+    if result.is_err() {
+        // free whatever the failed script has left behind
+        let _ = lua.gc_collect();
+    }
+    result
+}
+
+// boxed to break the cycle of `abandon` -> `add_player` -> `fail_quest` futures
+fn abandon_quests(parties: Vec<Arc<RwLock<Party>>>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        for party in parties {
+            Party::abandon(&party).await;
+        }
+    })
+}
+
+pub(crate) fn system_msg(message: String) -> Packet {
+    Packet::SystemMessage(protocol::unk19::SystemMessagePacket {
+        message,
+        msg_type: protocol::unk19::MessageType::SystemMessage,
+        ..Default::default()
+    })
+}
+
 impl Drop for Map {
     fn drop(&mut self) {
         log::trace!("Map {} dropped", self.data.map_data.map_object.id);
@@ -1522,7 +2346,7 @@ where
     }
 }
 
-async fn spawn_blocking<F, R>(func: F) -> Result<R, Error>
+pub(crate) async fn spawn_blocking<F, R>(func: F) -> Result<R, Error>
 where
     F: FnOnce() -> R + Send,
     R: Send + 'static,
@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::atomic::AtomicU32,
+    time::Duration,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
 
@@ -27,6 +28,12 @@ struct MasterConnectionImpl {
     receive_ch: Receiver<(MAS, Sender<MAS>)>,
     action_ch: Receiver<ConnectionAction>,
     notif_ch: Sender<MAS>,
+    // data required to restore the session after a reconnect
+    ip: SocketAddr,
+    psk: Vec<u8>,
+    fingerprint: String,
+    format: Option<SerializerFormat>,
+    ship_info: Option<ShipInfo>,
 }
 
 pub struct MasterConnection {
@@ -39,8 +46,12 @@ pub struct MasterConnection {
 
 enum ConnectionAction {
     SetFormat(SerializerFormat),
+    Registered(ShipInfo),
 }
 
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
 fn hostkey_fingerprint(key: &[u8]) -> String {
     use base64::Engine;
     use sha2::Digest;
@@ -51,6 +62,14 @@ fn hostkey_fingerprint(key: &[u8]) -> String {
     base64::engine::general_purpose::STANDARD.encode(hash)
 }
 
+fn log_protocol(conn: &ShipConnection) {
+    log::info!(
+        "Connected to the master ship using protocol version {} (capabilities: {:#x})",
+        conn.protocol_version(),
+        conn.capabilities().bits()
+    );
+}
+
 fn ident_failure(fingerprint: &str) {
     log::warn!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
     log::warn!("@    WARNING: MASTER SERVER IDENTIFICATION HAS CHANGED!    @");
@@ -74,11 +93,15 @@ impl MasterConnection {
                 .unwrap_or_default(),
         )
         .unwrap_or_default();
+        let mut accepted_key = String::new();
         let conn = ShipConnection::new_client(socket, |ip, key| {
             let fingerprint = hostkey_fingerprint(key);
             if let Some(host) = hostkeys.keys.iter().find(|d| d.ip == ip) {
                 match host.fingerprint == fingerprint {
-                    true => return true,
+                    true => {
+                        accepted_key = fingerprint;
+                        return true;
+                    }
                     false => {
                         ident_failure(&fingerprint);
                         return false;
@@ -93,6 +116,7 @@ impl MasterConnection {
                     .interact()
                     .unwrap();
             if confirm {
+                accepted_key.clone_from(&fingerprint);
                 hostkeys.keys.push(HostKey { ip, fingerprint });
                 log::warn!("Permanently added '{ip}' to the list of known master ships.");
                 true
@@ -101,6 +125,7 @@ impl MasterConnection {
             }
         })
         .await?;
+        log_protocol(&conn);
         tokio::fs::write(
             "hostkeys.toml",
             toml::to_string_pretty(&hostkeys)?.as_bytes(),
@@ -123,6 +148,11 @@ impl MasterConnection {
             receive_ch: recv,
             action_ch: ac_recv,
             notif_ch: notif_send,
+            ip,
+            psk: psk.to_vec(),
+            fingerprint: accepted_key,
+            format: None,
+            ship_info: None,
         };
         tokio::spawn(async move { master_conn_impl.run_loop().await });
 
@@ -135,6 +165,21 @@ impl MasterConnection {
             _ => Err(Error::MSUnexpected),
         }
     }
+    /// Creates a connection that answers every request with [`Error::MSNoResponse`].
+    #[cfg(any(test, feature = "harness"))]
+    pub fn offline() -> Self {
+        let (send, mut recv) = tokio::sync::mpsc::channel::<(MAS, Sender<MAS>)>(10);
+        let (ac_send, _) = tokio::sync::mpsc::channel(10);
+        // dropping the response channel makes the request fail
+        tokio::spawn(async move { while recv.recv().await.is_some() {} });
+        Self {
+            send_ch: send,
+            local_addr: Ipv4Addr::LOCALHOST,
+            ship_id: 0.into(),
+            action_ch: ac_send,
+            notif_cf: None,
+        }
+    }
     pub async fn run_action(&self, action: MAS) -> Result<MAS, Error> {
         log::trace!("Request to master ship: {action:?}");
         let (send, mut recv) = tokio::sync::mpsc::channel(1);
@@ -173,13 +218,22 @@ impl MasterConnection {
         self.ship_id
             .swap(info.id, std::sync::atomic::Ordering::Relaxed);
         info.ip = self.local_addr;
-        match self.run_action(MAS::RegisterShip(info)).await? {
-            MAS::RegisterShipResult(x) => Ok(x),
+        match self.run_action(MAS::RegisterShip(info.clone())).await? {
+            MAS::RegisterShipResult(x) => {
+                if let RegisterShipResult::Success = x {
+                    // the run loop is gone, so the registration can't be restored later
+                    self.action_ch
+                        .send(ConnectionAction::Registered(info))
+                        .await
+                        .map_err(|_| Error::MSNoResponse)?;
+                }
+                Ok(x)
+            }
             MAS::Error(e) => Err(Error::MSError(e)),
             _ => Err(Error::MSUnexpected),
         }
     }
-    pub fn take_notif_ch(&mut self) -> Option<Receiver<MAS>> {
+    pub const fn take_notif_ch(&mut self) -> Option<Receiver<MAS>> {
         self.notif_cf.take()
     }
 }
@@ -194,7 +248,8 @@ impl MasterConnectionImpl {
                         Ok(r) => r,
                         Err(e) => {
                             log::error!("Failed to receive data from a master server: {e}");
-                            return
+                            self.reconnect(&mut channels).await;
+                            continue;
                         }
                     };
                     if result.id == 0 {
@@ -203,7 +258,8 @@ impl MasterConnectionImpl {
                     } else {
                         let Some((pos, _)) = channels.iter().enumerate().find(|(_, (id,_))| *id == result.id) else {
                             log::error!("Master server sent unhandled response: {result:?}");
-                            return;
+                            self.reconnect(&mut channels).await;
+                            continue;
                         };
                         log::trace!("Master ship sent: {result:?}");
                         let (_, ch) = channels.swap_remove(pos);
@@ -211,19 +267,133 @@ impl MasterConnectionImpl {
                     }
                 },
                 Some((action, chan)) = self.receive_ch.recv() => {
+                    if !self.conn.capabilities().contains(action.required_capabilities()) {
+                        let error = "Master ship doesn't support this request".to_string();
+                        let _ = chan.send(MAS::Error(error)).await;
+                        continue;
+                    }
                     let id = self.id;
                     self.id += 1;
-                    match self.conn.write(MasterShipComm { id, action }).await {
+                    let comm = MasterShipComm { id, action };
+                    match self.conn.write(comm.clone()).await {
                         Ok(_) => channels.push((id, chan)),
-                        Err(e) => log::error!("Failed to send a request to a master server: {e}"),
+                        Err(e) => {
+                            log::error!("Failed to send a request to a master server: {e}");
+                            self.reconnect(&mut channels).await;
+                            // the request never reached the master ship, so it's safe to retry
+                            match self.conn.write(comm).await {
+                                Ok(_) => channels.push((id, chan)),
+                                Err(e) => log::error!("Failed to resend a request to a master server: {e}"),
+                            }
+                        }
                     }
                 },
                 Some(action) = self.action_ch.recv() => {
                     match action {
-                        ConnectionAction::SetFormat(ac) => self.conn.set_format(ac),
+                        ConnectionAction::SetFormat(ac) => {
+                            self.conn.set_format(ac.clone());
+                            self.format = Some(ac);
+                        }
+                        ConnectionAction::Registered(info) => self.ship_info = Some(info),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reconnects to the master ship with an exponential backoff. Requests that are in flight are
+    /// failed, because their responses will never arrive.
+    async fn reconnect(&mut self, channels: &mut Vec<(u32, Sender<MAS>)>) {
+        channels.clear();
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            log::info!(
+                "Reconnecting to the master ship in {} seconds...",
+                delay.as_secs()
+            );
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    // don't leave requests hanging while the master ship is unreachable
+                    Some((action, _)) = self.receive_ch.recv() => {
+                        log::warn!("Dropping request to the master ship while disconnected: {action:?}");
                     }
                 }
             }
+            match self.resync().await {
+                Ok(_) => {
+                    log::info!("Reconnected to the master ship");
+                    return;
+                }
+                Err(e) => log::warn!("Failed to reconnect to the master ship: {e}"),
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    /// Creates a new connection and restores the session state (login, format and registration).
+    async fn resync(&mut self) -> Result<(), Error> {
+        let socket = tokio::net::TcpStream::connect(self.ip).await?;
+        let known_key = &self.fingerprint;
+        self.conn = ShipConnection::new_client(socket, |_, key| {
+            let fingerprint = hostkey_fingerprint(key);
+            if &fingerprint != known_key {
+                ident_failure(&fingerprint);
+                return false;
+            }
+            true
+        })
+        .await?;
+        log_protocol(&self.conn);
+
+        let login = MAS::ShipLogin(ShipLogin {
+            psk: self.psk.clone(),
+        });
+        match self.request(login).await? {
+            MAS::ShipLoginResult(ShipLoginResult::Ok) => {}
+            MAS::ShipLoginResult(ShipLoginResult::UnknownShip) => return Err(Error::MSInvalidPSK),
+            _ => return Err(Error::MSUnexpected),
+        }
+
+        if let Some(format) = self.format.clone() {
+            match self.request(MAS::SetFormat(format.clone())).await? {
+                MAS::Ok => self.conn.set_format(format),
+                MAS::Error(e) => {
+                    log::warn!("Master ship rejected serializer format {format:?}: {e}");
+                    self.format = None;
+                }
+                _ => return Err(Error::MSUnexpected),
+            }
+        }
+
+        if let Some(info) = self.ship_info.clone() {
+            let id = info.id;
+            match self.request(MAS::RegisterShip(info)).await? {
+                MAS::RegisterShipResult(RegisterShipResult::Success) => {}
+                MAS::RegisterShipResult(RegisterShipResult::AlreadyTaken) => {
+                    return Err(Error::MSError(format!("Ship id {id} is already taken")));
+                }
+                MAS::Error(e) => return Err(Error::MSError(e)),
+                _ => return Err(Error::MSUnexpected),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a request directly over the connection and waits for the response.
+    async fn request(&mut self, action: MAS) -> Result<MAS, Error> {
+        let id = self.id;
+        self.id += 1;
+        self.conn.write(MasterShipComm { id, action }).await?;
+        loop {
+            let result = self.conn.read_for(Duration::from_secs(10)).await?;
+            if result.id == id {
+                return Ok(result.action);
+            } else if result.id == 0 {
+                let _ = self.notif_ch.send(result.action).await;
+            }
         }
     }
 }
@@ -640,14 +640,20 @@ impl Party {
         .await;
     }
 
-    pub async fn abandon(&mut self) {
-        self.quest = None;
-        self.questname.clear();
-        for (id, user) in self
+    /// Removes the party quest and moves all members to the lobby.
+    ///
+    /// The party isn't locked while moving players, because joining a map reads the party.
+    pub async fn abandon(party: &RwLock<Self>) {
+        let mut lock = party.write().await;
+        lock.quest = None;
+        lock.questname.clear();
+        let players: Vec<_> = lock
             .players
             .iter()
             .filter_map(|(i, p)| p.upgrade().map(|p| (*i, p)))
-        {
+            .collect();
+        drop(lock);
+        for (id, user) in players {
             //TODO: there is some packet missing, because abandoning in lobby doesn't remove the
             //quest from the client
             let mut lock = user.lock().await;
@@ -671,6 +677,9 @@ impl Party {
     pub const fn get_obj(&self) -> ObjectHeader {
         self.id
     }
+    pub fn get_player_ids(&self) -> Vec<u32> {
+        self.players.iter().map(|(id, _)| *id).collect()
+    }
 }
 
 async fn exec_users<F>(users: &[(u32, Weak<Mutex<User>>)], mut f: F)
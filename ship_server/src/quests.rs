@@ -1,35 +1,192 @@
-use std::sync::{Arc, atomic::AtomicU32};
+use std::{
+    fmt,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 
-use crate::{Error, map::Map, mutex::Mutex};
-use data_structs::quest::QuestData;
+use crate::{
+    BlockData, Error, User,
+    map::Map,
+    mutex::Mutex,
+    scripts::{LevelUp, QuestInfo},
+    sql::{ClearedQuest, Sql},
+};
+use data_structs::{
+    master_ship::{EmergencyQuest, QuestRecord},
+    quest::{ClearCondition, FailConditions, QuestData, QuestRewards},
+    unix_time,
+};
+use parking_lot::RwLock;
 use pso2packetlib::protocol::{
+    Packet,
     party::{SetPartyQuestPacket, SetQuestInfoPacket},
+    playerstatus::GainedEXPPacket,
     questlist::{
         AcceptQuestPacket, AcceptStoryQuestPacket, AvailableQuestType, AvailableQuestsPacket,
-        QuestCategoryPacket, QuestDifficulty, QuestType,
+        QuestCategoryPacket, QuestDifficulty, QuestDifficultyType, QuestResultEntry,
+        QuestResultPacket, QuestResultRank, QuestType,
     },
 };
 
+/// Maximum number of parties that can share a quest instance.
+pub const MAX_QUEST_PARTIES: usize = 4;
+
 pub struct PartyQuest {
     quest: QuestData,
     diff: u16,
     map: Arc<Mutex<Map>>,
+    /// Keeps the party's place in a shared quest instance.
+    slot: Arc<()>,
+}
+
+/// Quest instances of a block that can be shared by several parties.
+pub struct QuestInstances {
+    instances: Mutex<Vec<QuestInstance>>,
+}
+
+struct QuestInstance {
+    name_id: u32,
+    diff: u16,
+    map: Weak<Mutex<Map>>,
+    slots: Vec<Weak<()>>,
+}
+
+/// Event that can clear or fail a quest.
+pub enum QuestEvent<'a> {
+    EnemyKilled(&'a str),
+    ZoneReached(&'a str),
+    Signal(&'a str),
+    PlayerDied,
+    /// Periodic check of the time limit.
+    Tick,
+}
+
+/// Reason why a quest has failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailReason {
+    TimeLimit,
+    Deaths,
+    Signal(String),
+}
+
+impl fmt::Display for FailReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimeLimit => write!(f, "time limit reached"),
+            Self::Deaths => write!(f, "too many party members were incapacitated"),
+            Self::Signal(_) => write!(f, "objective failed"),
+        }
+    }
+}
+
+/// Clear state of the quest running on a map.
+pub struct QuestProgress {
+    name_id: u32,
+    diff: u16,
+    conditions: Vec<ClearCondition>,
+    rewards: QuestRewards,
+    fail_conditions: FailConditions,
+    start: Instant,
+    clear_time: Option<Duration>,
+    rewarded: bool,
+    deaths: u32,
+    failed: bool,
+    fail_reason: Option<FailReason>,
+    /// Results of players that haven't returned to the campship yet.
+    results: Vec<(u32, QuestResultPacket)>,
 }
 
 pub struct Quests {
     quests: Vec<QuestData>,
+    /// Emergency quests announced by the master ship.
+    emergency: RwLock<Vec<EmergencyQuest>>,
 }
 
 impl Quests {
     pub const fn load(quests: Vec<QuestData>) -> Self {
-        Self { quests }
+        Self {
+            quests,
+            emergency: RwLock::new(vec![]),
+        }
+    }
+    /// Adds a new emergency quest. Returns `false` if the quest is already known or has ended.
+    pub fn add_emergency(&self, eq: EmergencyQuest) -> bool {
+        let now = unix_time();
+        let mut lock = self.emergency.write();
+        lock.retain(|e| e.end > now);
+        if eq.end <= now
+            || lock
+                .iter()
+                .any(|e| e.quest_id == eq.quest_id && e.start == eq.start)
+        {
+            return false;
+        }
+        if self.get_quest_by_nameid(eq.quest_id).is_none() {
+            log::warn!("Unknown emergency quest: {}", eq.quest_id);
+        }
+        lock.push(eq);
+        true
+    }
+    /// Copies emergency quests from the previously loaded quests.
+    pub fn copy_emergencies(&self, other: &Self) {
+        *self.emergency.write() = other.emergency.read().clone();
+    }
+    /// Checks if the quest is currently running as an emergency quest.
+    pub fn is_emergency(&self, id: u32) -> bool {
+        let now = unix_time();
+        self.emergency
+            .read()
+            .iter()
+            .any(|e| e.quest_id == id && e.start <= now && now < e.end)
+    }
+    /// Unlocks quests whose unlock conditions are met by the player's character.
+    ///
+    /// Newly unlocked quests are also added to the quest counter notifications and returned.
+    pub fn update_unlocks(&self, user: &mut User) -> Vec<u32> {
+        let account_flags = &user.user_data.accountflags;
+        let Some(char) = user.character.as_mut() else {
+            return vec![];
+        };
+        let level = char.character.get_level().level1;
+        let mut unlocked = vec![];
+        for quest in &self.quests {
+            let id = quest.definition.name_id;
+            let Some(unlock) = &quest.unlock else {
+                continue;
+            };
+            if char.unlocked_quests.contains(&id) {
+                continue;
+            }
+            let is_met = level >= unlock.min_level
+                && unlock
+                    .cleared_quests
+                    .iter()
+                    .all(|q| char.cleared_quests.iter().any(|c| c.name_id == *q))
+                && unlock
+                    .account_flags
+                    .iter()
+                    .all(|f| account_flags.get(*f) != 0)
+                && unlock
+                    .character_flags
+                    .iter()
+                    .all(|f| char.flags.get(*f) != 0);
+            if is_met {
+                char.unlocked_quests.push(id);
+                char.unlocked_quests_notif.push(id);
+                unlocked.push(id);
+            }
+        }
+        unlocked
+    }
+    fn is_available(&self, quest: &QuestData, unlocked: &[u32]) -> bool {
+        unlocked.contains(&quest.definition.name_id) || self.is_emergency(quest.definition.name_id)
     }
     pub fn get_availiable(&self, unlocked: &[u32]) -> AvailableQuestsPacket {
         let mut available = AvailableQuestsPacket::default();
         for quest in self
             .quests
             .iter()
-            .filter(|q| unlocked.contains(&q.definition.name_id))
+            .filter(|q| self.is_available(q, unlocked))
         {
             match quest.definition.quest_type {
                 QuestType::Unk0 => {
@@ -305,14 +462,25 @@ impl Quests {
         available
     }
     //FIXME: this will not work for limited time quests
-    pub fn get_category(&self, category: QuestType, unlocked: &[u32]) -> QuestCategoryPacket {
+    pub fn get_category(
+        &self,
+        category: QuestType,
+        unlocked: &[u32],
+        cleared: &[ClearedQuest],
+    ) -> QuestCategoryPacket {
         QuestCategoryPacket {
             quests: self
                 .quests
                 .iter()
-                .filter(|q| unlocked.contains(&q.definition.name_id))
+                .filter(|q| self.is_available(q, unlocked))
                 .filter(|q| q.definition.quest_type == category)
-                .map(|q| q.definition.clone())
+                .map(|q| {
+                    let mut definition = q.definition.clone();
+                    if let Some(c) = cleared.iter().find(|c| c.name_id == definition.name_id) {
+                        definition.difficulties_completed = c.difficulties.clone();
+                    }
+                    definition
+                })
                 .collect(),
         }
     }
@@ -322,10 +490,13 @@ impl Quests {
             .find(|q| q.definition.quest_obj.id == id)
             .map(|q| q.difficulties.clone())
     }
+    /// Creates a new instance of the quest.
+    ///
+    /// The map uses the block's server data at the time of the creation.
     pub fn get_quest(
         &self,
         packet: AcceptQuestPacket,
-        map_obj_id: &AtomicU32,
+        block: &Arc<BlockData>,
     ) -> Result<PartyQuest, Error> {
         let Some(quest) = self
             .quests
@@ -337,19 +508,24 @@ impl Quests {
         if packet.diff >= 8 {
             return Err(Error::InvalidInput("get_quest"));
         }
-        let mut map = Map::new_from_data(quest.map.clone(), map_obj_id)?;
+        let mut map = Map::new_from_data(quest.map.clone(), &block.latest_mapid)?;
+        map.set_block_data(block.clone());
         map.set_enemy_level(quest.difficulties.diffs[packet.diff as usize].monster_level as _);
+        map.set_enemy_scaling(quest.get_enemy_scaling(packet.diff as _).clone());
+        map.set_quest_progress(QuestProgress::new(quest, packet.diff));
         let map = Arc::new(Mutex::new(map));
+        Map::start_timers(&map);
         Ok(PartyQuest {
             quest: quest.clone(),
             diff: packet.diff,
             map,
+            slot: Arc::new(()),
         })
     }
     pub fn get_story_quest(
         &self,
         packet: AcceptStoryQuestPacket,
-        map_obj_id: &AtomicU32,
+        block: &Arc<BlockData>,
     ) -> Result<PartyQuest, Error> {
         let Some(quest) = self
             .quests
@@ -358,14 +534,19 @@ impl Quests {
         else {
             return Err(Error::InvalidInput("get_quest"));
         };
-        let mut map = Map::new_from_data(quest.map.clone(), map_obj_id)?;
+        let mut map = Map::new_from_data(quest.map.clone(), &block.latest_mapid)?;
+        map.set_block_data(block.clone());
         map.set_enemy_level(quest.difficulties.diffs[0].monster_level as _);
+        map.set_enemy_scaling(quest.get_enemy_scaling(0).clone());
         map.set_quest_obj(quest.definition.quest_obj);
+        map.set_quest_progress(QuestProgress::new(quest, 0));
         let map = Arc::new(Mutex::new(map));
+        Map::start_timers(&map);
         Ok(PartyQuest {
             quest: quest.clone(),
             diff: 0,
             map,
+            slot: Arc::new(()),
         })
     }
     pub fn get_quest_by_nameid(&self, id: u32) -> Option<&QuestData> {
@@ -373,6 +554,72 @@ impl Quests {
     }
 }
 
+impl Default for QuestInstances {
+    fn default() -> Self {
+        Self {
+            instances: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl QuestInstances {
+    /// Places the party into a running instance of the quest that has room for another party or
+    /// creates a new instance.
+    pub async fn get_quest(
+        &self,
+        quests: &Quests,
+        packet: AcceptQuestPacket,
+        block: &Arc<BlockData>,
+    ) -> Result<PartyQuest, Error> {
+        let Some(quest) = quests
+            .quests
+            .iter()
+            .find(|q| q.definition.quest_obj.id == packet.quest_obj.id)
+        else {
+            return Err(Error::InvalidInput("get_quest"));
+        };
+        let max_parties = (quest.max_parties as usize).min(MAX_QUEST_PARTIES);
+        if max_parties <= 1 {
+            return quests.get_quest(packet, block);
+        }
+        let name_id = quest.definition.name_id;
+        let diff = packet.diff;
+        let mut instances = self.instances.lock().await;
+        instances.retain(|i| i.map.strong_count() != 0);
+        for instance in instances
+            .iter_mut()
+            .filter(|i| i.name_id == name_id && i.diff == diff)
+        {
+            instance.slots.retain(|s| s.strong_count() != 0);
+            if instance.slots.len() >= max_parties {
+                continue;
+            }
+            let Some(map) = instance.map.upgrade() else {
+                continue;
+            };
+            if !map.lock().await.is_quest_running() {
+                continue;
+            }
+            let slot = Arc::new(());
+            instance.slots.push(Arc::downgrade(&slot));
+            return Ok(PartyQuest {
+                quest: quest.clone(),
+                diff,
+                map,
+                slot,
+            });
+        }
+        let party_quest = quests.get_quest(packet, block)?;
+        instances.push(QuestInstance {
+            name_id,
+            diff,
+            map: Arc::downgrade(&party_quest.map),
+            slots: vec![Arc::downgrade(&party_quest.slot)],
+        });
+        Ok(party_quest)
+    }
+}
+
 impl PartyQuest {
     pub fn set_party_packet(&self) -> SetPartyQuestPacket {
         SetPartyQuestPacket {
@@ -400,4 +647,292 @@ impl PartyQuest {
     pub const fn is_insta_transfer(&self) -> bool {
         self.quest.immediate_move
     }
+    pub const fn get_name_id(&self) -> u32 {
+        self.quest.definition.name_id
+    }
+    pub const fn get_difficulty(&self) -> u16 {
+        self.diff
+    }
+}
+
+impl QuestProgress {
+    pub fn new(quest: &QuestData, diff: u16) -> Self {
+        Self {
+            name_id: quest.definition.name_id,
+            diff,
+            conditions: quest.clear_conditions.clone(),
+            rewards: quest.rewards.clone(),
+            fail_conditions: quest.get_fail_conditions(diff).clone(),
+            start: Instant::now(),
+            clear_time: None,
+            rewarded: false,
+            deaths: 0,
+            failed: false,
+            fail_reason: None,
+            results: vec![],
+        }
+    }
+    pub const fn info(&self) -> QuestInfo {
+        QuestInfo {
+            id: self.name_id,
+            difficulty: self.diff,
+        }
+    }
+    pub const fn is_cleared(&self) -> bool {
+        self.clear_time.is_some()
+    }
+    pub const fn is_failed(&self) -> bool {
+        self.failed
+    }
+    pub const fn has_time_limit(&self) -> bool {
+        self.fail_conditions.time_limit != 0
+    }
+    /// Checks clear and fail conditions against the event.
+    pub fn handle_event(&mut self, event: QuestEvent) {
+        if self.is_cleared() || self.is_failed() {
+            return;
+        }
+        let fail = &self.fail_conditions;
+        let fail_reason = match &event {
+            QuestEvent::PlayerDied => {
+                self.deaths += 1;
+                (fail.max_deaths != 0 && self.deaths >= fail.max_deaths)
+                    .then_some(FailReason::Deaths)
+            }
+            QuestEvent::Tick => (fail.time_limit != 0
+                && self.start.elapsed().as_secs() >= fail.time_limit as u64)
+                .then_some(FailReason::TimeLimit),
+            QuestEvent::Signal(s) if fail.signals.iter().any(|f| f == s) => {
+                Some(FailReason::Signal(s.to_string()))
+            }
+            _ => None,
+        };
+        if fail_reason.is_some() {
+            self.failed = true;
+            self.fail_reason = fail_reason;
+            return;
+        }
+        let is_met = self.conditions.iter().any(|c| match (c, &event) {
+            (ClearCondition::KillEnemy(name), QuestEvent::EnemyKilled(e)) => name == e,
+            (ClearCondition::ReachZone(name), QuestEvent::ZoneReached(z)) => name == z,
+            (ClearCondition::Signal(name), QuestEvent::Signal(s)) => name == s,
+            _ => false,
+        });
+        if is_met {
+            self.clear_time = Some(self.start.elapsed());
+        }
+    }
+    /// Returns the clear time if the quest was cleared, but players weren't rewarded yet.
+    pub const fn take_clear(&mut self) -> Option<Duration> {
+        if self.rewarded {
+            return None;
+        }
+        let Some(clear_time) = self.clear_time else {
+            return None;
+        };
+        self.rewarded = true;
+        Some(clear_time)
+    }
+    /// Returns the reason of the failure if the quest has failed, but it wasn't handled yet.
+    pub const fn take_fail(&mut self) -> Option<FailReason> {
+        self.fail_reason.take()
+    }
+    fn rank(&self, clear_time: Duration) -> QuestResultRank {
+        if self.rewards.rank_times.is_empty() {
+            return QuestResultRank::S;
+        }
+        let ranks = [QuestResultRank::S, QuestResultRank::A, QuestResultRank::B];
+        ranks
+            .into_iter()
+            .zip(&self.rewards.rank_times)
+            .find(|(_, max)| clear_time.as_secs() <= **max as u64)
+            .map(|(rank, _)| rank)
+            .unwrap_or(QuestResultRank::C)
+    }
+    /// Grants clear rewards to the player, records the clear and stores the results until the
+    /// player returns to the campship.
+    ///
+    /// Returns packets that should be sent to the player and gained levels.
+    pub fn reward(
+        &mut self,
+        user: &mut User,
+        clear_time: Duration,
+    ) -> Result<(Vec<Packet>, Vec<LevelUp>), Error> {
+        let mut packets = vec![];
+        let mut level_ups = vec![];
+        let rewards = &self.rewards;
+        if rewards.exp != 0 {
+            let old_levels = user.get_class_levels();
+            let exp = user.add_exp(rewards.exp)?;
+            if let Some(old_levels) = old_levels {
+                level_ups = LevelUp::from_exp(old_levels, &exp);
+            }
+            packets.push(Packet::GainedEXP(GainedEXPPacket {
+                sender: user.create_object_header(),
+                receivers: vec![exp],
+            }));
+        }
+        let player_id = user.get_user_id();
+        let Some(char) = user.character.as_mut() else {
+            return Err(Error::InvalidInput("reward"));
+        };
+        if rewards.meseta != 0 {
+            packets.push(char.inventory.add_meseta(rewards.meseta as u64));
+        }
+        let mut items = vec![];
+        for id in &rewards.items {
+            let packet = char
+                .inventory
+                .add_default_item(&mut user.user_data.last_uuid, *id);
+            if let Packet::AddedItem(data) = &packet {
+                items.push(data.item.clone());
+            }
+            packets.push(packet);
+        }
+        // only 8 items fit in the results screen
+        items.truncate(8);
+
+        let difficulty = QuestDifficultyType::from_bits_retain(1 << self.diff);
+        match char
+            .cleared_quests
+            .iter_mut()
+            .find(|q| q.name_id == self.name_id)
+        {
+            Some(cleared) => {
+                cleared.difficulties |= difficulty;
+                cleared.clear_count += 1;
+                cleared.best_time = cleared.best_time.min(clear_time);
+            }
+            None => char.cleared_quests.push(ClearedQuest {
+                name_id: self.name_id,
+                difficulties: difficulty,
+                clear_count: 1,
+                best_time: clear_time,
+            }),
+        }
+
+        let result = QuestResultPacket {
+            clear_time: QuestResultEntry {
+                value: clear_time.as_secs() as u32,
+                ..Default::default()
+            },
+            rank: self.rank(clear_time),
+            meseta_earned: rewards.meseta,
+            exp_earned: rewards.exp,
+            gained_items: items.into(),
+            ..Default::default()
+        };
+        self.results.retain(|(id, _)| *id != player_id);
+        self.results.push((player_id, result));
+        Ok((packets, level_ups))
+    }
+    /// Creates a clear time record of the player. Party members are filled in by
+    /// [`save_records`].
+    pub fn new_record(&self, user: &User, clear_time: Duration) -> Option<QuestRecord> {
+        let char = &user.character.as_ref()?.character;
+        Some(QuestRecord {
+            quest_id: self.name_id,
+            difficulty: self.diff,
+            time: clear_time.as_millis() as u64,
+            char_id: char.character_id,
+            char_name: char.name.clone(),
+            timestamp: unix_time(),
+            ..Default::default()
+        })
+    }
+    /// Returns the results of the player if they haven't seen them yet.
+    pub fn take_result(&mut self, player_id: u32) -> Option<QuestResultPacket> {
+        let pos = self.results.iter().position(|(id, _)| *id == player_id)?;
+        Some(self.results.swap_remove(pos).1)
+    }
+}
+
+/// Saves clear times of the party and reports new personal bests to the master ship.
+pub async fn save_records(sql: Arc<Sql>, mut records: Vec<QuestRecord>) {
+    let names: Vec<_> = records.iter().map(|r| r.char_name.clone()).collect();
+    for record in &mut records {
+        record.party = names
+            .iter()
+            .filter(|n| **n != record.char_name)
+            .cloned()
+            .collect();
+    }
+    for record in records {
+        match sql.put_quest_record(&record).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                log::warn!("Failed to save quest record: {e}");
+                continue;
+            }
+        }
+        if let Err(e) = sql.put_network_quest_record(record).await {
+            log::warn!("Failed to send quest record to the master ship: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuestInstances;
+    use crate::harness::new_block;
+    use data_structs::{ServerData, quest::QuestData};
+    use pso2packetlib::protocol::questlist::AcceptQuestPacket;
+    use std::{path::Path, sync::Arc};
+
+    #[tokio::test]
+    async fn shared_instances() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../data/quests/200030 - Test Quest");
+        let data = std::fs::read_to_string(path.join("data.json")).unwrap();
+        let mut quest: QuestData = serde_json::from_str(&data).unwrap();
+        let data = std::fs::read_to_string(path.join("map/map.json")).unwrap();
+        quest.map = serde_json::from_str(&data).unwrap();
+        quest.max_parties = 2;
+        let packet = AcceptQuestPacket {
+            quest_obj: quest.definition.quest_obj,
+            ..Default::default()
+        };
+        let server_data = ServerData {
+            quests: vec![quest.clone()],
+            ..Default::default()
+        };
+        let block = new_block(quest.map.clone(), server_data).await.unwrap();
+        let old_data = block.server_data();
+        let instances = QuestInstances::default();
+
+        let first = instances
+            .get_quest(&block.quests(), packet.clone(), &block)
+            .await
+            .unwrap();
+        // parties joining after a reload keep the data of the running instance
+        block.data.replace(ServerData {
+            quests: vec![quest],
+            ..Default::default()
+        });
+        let quests = block.quests();
+        // lobbies aren't recreated, so they use the new data
+        let lobby_data = block.lobby.lock().await.server_data().unwrap();
+        assert!(Arc::ptr_eq(&lobby_data, &block.server_data()));
+        let second = instances
+            .get_quest(&quests, packet.clone(), &block)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first.map, &second.map));
+        let map_data = second.map.lock().await.server_data().unwrap();
+        assert!(Arc::ptr_eq(&map_data, &old_data));
+        // the instance is full
+        let third = instances
+            .get_quest(&quests, packet.clone(), &block)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first.map, &third.map));
+        assert!(!Arc::ptr_eq(
+            &third.map.lock().await.server_data().unwrap(),
+            &old_data
+        ));
+        // a party leaving the quest frees its place
+        drop(second);
+        let fourth = instances.get_quest(&quests, packet, &block).await.unwrap();
+        assert!(Arc::ptr_eq(&first.map, &fourth.map));
+    }
 }
@@ -0,0 +1,603 @@
+//! Server-level scripts run on player events and custom chat commands.
+//!
+//! Each hook is a `<hook>.lua` file in the scripts directory. Hooks get the id of the player in
+//! the `player` global, hook specific data in the `data` global and can use the functions set up
+//! in [`setup_scope`].
+//!
+//! Scripts in the `commands` subdirectory are run on load and can register chat commands with
+//! `register_command(spec, handler)`, where `spec` is a table deserialized to [`CommandSpec`].
+//! Handlers get parsed arguments as a table (also available in the `data` global) and can use
+//! the same functions as hooks. If a handler returns a string, it is sent to the player.
+use crate::{
+    BlockData, Error, User,
+    map::{eval_limited, new_lua, run_limited, spawn_blocking, system_msg},
+};
+use mlua::{Lua, LuaSerdeExt};
+use pso2packetlib::protocol::{
+    chat::MessageChannel, models::character::Class, playerstatus::EXPReceiver,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+};
+
+/// Names of the supported hooks.
+pub const HOOKS: &[&str] = &[
+    "on_login",
+    "on_logout",
+    "on_level_up",
+    "on_enemy_kill",
+    "on_quest_start",
+    "on_quest_clear",
+    "on_quest_fail",
+    "on_chat",
+];
+
+struct ScriptsState {
+    lua: Lua,
+    hooks: HashMap<String, String>,
+    commands: Vec<LuaCommand>,
+}
+
+struct LuaCommand {
+    spec: CommandSpec,
+    handler: mlua::Function,
+}
+
+/// Definition of a chat command registered by a script.
+#[derive(Deserialize)]
+struct CommandSpec {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    args: Vec<CommandArg>,
+    #[serde(default)]
+    permission: Permission,
+    /// Description displayed in `!help`.
+    #[serde(default)]
+    help: String,
+}
+
+#[derive(Deserialize)]
+struct CommandArg {
+    name: String,
+    #[serde(default, rename = "type")]
+    kind: ArgType,
+    /// Optional arguments are `nil` if not provided.
+    #[serde(default)]
+    optional: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ArgType {
+    #[default]
+    String,
+    Number,
+    Integer,
+    /// Consumes all remaining arguments.
+    Rest,
+}
+
+/// Players that can use a command.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Permission {
+    #[default]
+    All,
+    Gm,
+    NotGm,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum ArgValue {
+    String(String),
+    Number(f64),
+    Integer(i64),
+}
+
+/// Hooks defined by the ship operator.
+pub struct ServerScripts {
+    dir: Option<PathBuf>,
+    state: parking_lot::Mutex<ScriptsState>,
+}
+
+#[derive(Serialize)]
+struct PlayerInfo {
+    id: u32,
+    nickname: String,
+    name: String,
+    level: u32,
+    class: Class,
+    is_gm: bool,
+}
+
+#[derive(Serialize)]
+struct BlockInfo {
+    id: u32,
+    name: String,
+    players: usize,
+}
+
+/// Data of the `on_level_up` hook.
+#[derive(Serialize)]
+pub struct LevelUp {
+    class: Class,
+    level: u16,
+    /// Whether the subclass has levelled up.
+    subclass: bool,
+}
+
+/// Data of the `on_enemy_kill` hook.
+#[derive(Serialize)]
+pub struct EnemyKill {
+    pub id: u32,
+    pub name: String,
+    pub exp: u32,
+}
+
+/// Data of the `on_quest_start`, `on_quest_clear` and `on_quest_fail` hooks.
+#[derive(Serialize)]
+pub struct QuestInfo {
+    pub id: u32,
+    pub difficulty: u16,
+}
+
+/// Data of the `on_chat` hook.
+#[derive(Serialize)]
+pub struct ChatInfo<'a> {
+    pub message: &'a str,
+    pub channel: MessageChannel,
+}
+
+impl LevelUp {
+    /// Returns level ups caused by gaining EXP. `old_levels` are levels of the main class and the
+    /// subclass before gaining EXP.
+    pub fn from_exp(old_levels: (u16, u16), exp: &EXPReceiver) -> Vec<Self> {
+        let mut level_ups = vec![];
+        if exp.level > old_levels.0 {
+            level_ups.push(Self {
+                class: exp.class,
+                level: exp.level,
+                subclass: false,
+            });
+        }
+        if exp.subclass != Class::Unknown && exp.level_sub > old_levels.1 {
+            level_ups.push(Self {
+                class: exp.subclass,
+                level: exp.level_sub,
+                subclass: true,
+            });
+        }
+        level_ups
+    }
+}
+
+impl CommandSpec {
+    fn matches(&self, name: &str, is_gm: bool) -> bool {
+        let allowed = match self.permission {
+            Permission::All => true,
+            Permission::Gm => is_gm,
+            Permission::NotGm => !is_gm,
+        };
+        allowed && (self.name == name || self.aliases.iter().any(|a| a == name))
+    }
+
+    // same format as the help of built-in commands
+    fn help_msg(&self) -> String {
+        let mut help = format!("{{yel}} - {}", self.name);
+        for arg in &self.args {
+            if arg.optional {
+                help.push_str(&format!(" [{}]", arg.name));
+            } else {
+                help.push_str(&format!(" {{{}}}", arg.name));
+            }
+        }
+        if !self.aliases.is_empty() {
+            help.push_str(&format!(" (aliases: {})", self.aliases.join(", ")));
+        }
+        help.push_str("{def}");
+        if !self.help.is_empty() {
+            help.push_str(": ");
+            help.push_str(&self.help);
+        }
+        help.push('\n');
+        help
+    }
+
+    fn parse_args(&self, input: &str) -> Result<HashMap<&str, ArgValue>, String> {
+        let mut stream = input.split_whitespace();
+        let mut args = HashMap::new();
+        for arg in &self.args {
+            let value = match arg.kind {
+                ArgType::Rest => {
+                    Some(stream.by_ref().collect::<Vec<_>>().join(" ")).filter(|s| !s.is_empty())
+                }
+                _ => stream.next().map(str::to_string),
+            };
+            let Some(value) = value else {
+                if arg.optional {
+                    continue;
+                }
+                return Err(format!("{{red}}Missing argument: {}{{def}}", arg.name));
+            };
+            let invalid = |e: &dyn std::fmt::Display| {
+                format!("{{red}}Invalid argument {}: {e}{{def}}", arg.name)
+            };
+            let value = match arg.kind {
+                ArgType::String | ArgType::Rest => ArgValue::String(value),
+                ArgType::Number => ArgValue::Number(value.parse().map_err(|e| invalid(&e))?),
+                ArgType::Integer => ArgValue::Integer(value.parse().map_err(|e| invalid(&e))?),
+            };
+            args.insert(arg.name.as_str(), value);
+        }
+        Ok(args)
+    }
+}
+
+impl ScriptsState {
+    fn load(dir: Option<&Path>) -> Result<Self, Error> {
+        let lua = new_lua()?;
+        let hooks = load_hooks(dir)?;
+        let commands = load_commands(&lua, dir)?;
+        Ok(Self {
+            lua,
+            hooks,
+            commands,
+        })
+    }
+
+    /// Runs `func` with the player accessors set up.
+    fn run_scoped<R>(
+        &self,
+        call_type: &str,
+        user: &mut User,
+        data: mlua::Value,
+        func: impl FnOnce() -> Result<R, mlua::Error>,
+    ) -> Result<Result<R, mlua::Error>, Error> {
+        let lua = &self.lua;
+        let globals = lua.globals();
+        let block = user.get_blockdata().clone();
+        globals.set("call_type", call_type)?;
+        globals.set("player", user.get_user_id())?;
+        globals.set("data", data)?;
+        let user = RefCell::new(user);
+        let result = lua.scope(|scope| {
+            setup_scope(&globals, scope, &user, &block)?;
+            Ok(run_limited(lua, func))
+        })?;
+        globals.raw_remove("call_type")?;
+        globals.raw_remove("player")?;
+        globals.raw_remove("data")?;
+        Ok(result)
+    }
+}
+
+impl ServerScripts {
+    /// Loads scripts from the provided directory. If no directory is provided no scripts are run.
+    pub fn load(dir: Option<&str>) -> Result<Self, Error> {
+        let dir = dir.map(PathBuf::from);
+        let state = ScriptsState::load(dir.as_deref())?;
+        Ok(Self {
+            dir,
+            state: parking_lot::Mutex::new(state),
+        })
+    }
+
+    /// Reloads scripts from the scripts directory and returns the number of loaded hooks and
+    /// commands.
+    pub fn reload(&self) -> Result<(usize, usize), Error> {
+        let state = ScriptsState::load(self.dir.as_deref())?;
+        let counts = (state.hooks.len(), state.commands.len());
+        *self.state.lock() = state;
+        Ok(counts)
+    }
+
+    /// Checks if a script command (or its alias) exists and is available for the player.
+    pub fn has_command(&self, name: &str, is_gm: bool) -> bool {
+        let state = self.state.lock();
+        state.commands.iter().any(|c| c.spec.matches(name, is_gm))
+    }
+
+    /// Returns help messages of script commands available for the player.
+    pub fn commands_help(&self, is_gm: bool) -> String {
+        let state = self.state.lock();
+        state
+            .commands
+            .iter()
+            .filter(|c| c.spec.matches(&c.spec.name, is_gm))
+            .map(|c| c.spec.help_msg())
+            .collect()
+    }
+
+    /// Runs a script command (e.g. `"cmd arg1 arg2"`) and returns a message for the player.
+    pub async fn run_command(&self, user: &mut User, input: &str) -> Option<String> {
+        let player_id = user.get_user_id();
+        match spawn_blocking(|| self.run_command_blocking(user, input)).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(e)) | Err(e) => {
+                log::warn!("Failed to run command {input} for player {player_id}: {e}");
+                Some("{red}Command failed{def}".into())
+            }
+        }
+    }
+
+    fn run_command_blocking(&self, user: &mut User, input: &str) -> Result<Option<String>, Error> {
+        let state = self.state.lock();
+        let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        let is_gm = user.user_data.isgm;
+        let Some(command) = state.commands.iter().find(|c| c.spec.matches(name, is_gm)) else {
+            return Ok(Some(format!("{{red}}Unknown command: {name}{{def}}")));
+        };
+        let args = match command.spec.parse_args(args) {
+            Ok(args) => state.lua.to_value(&args)?,
+            Err(e) => return Ok(Some(e)),
+        };
+        let result = state.run_scoped("command", user, args.clone(), || {
+            command.handler.call::<mlua::Value>(args)
+        })?;
+        match result {
+            Ok(mlua::Value::String(msg)) => Ok(Some(msg.to_string_lossy())),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                log::warn!("Command {} failed: {e}", command.spec.name);
+                Ok(Some("{red}Command failed{def}".into()))
+            }
+        }
+    }
+
+    /// Runs a hook for the player and returns `true` if the hook returned `true`. For `on_chat`
+    /// this means that the message is not sent.
+    ///
+    /// Script errors are logged and treated as if the hook returned nothing.
+    pub async fn run<S: Serialize + Sync>(&self, hook: &str, user: &mut User, data: &S) -> bool {
+        if !self.state.lock().hooks.contains_key(hook) {
+            return false;
+        }
+        let player_id = user.get_user_id();
+        match spawn_blocking(|| self.run_blocking(hook, user, data)).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) | Err(e) => {
+                log::warn!("Failed to run hook {hook} for player {player_id}: {e}");
+                false
+            }
+        }
+    }
+
+    fn run_blocking<S: Serialize>(
+        &self,
+        hook: &str,
+        user: &mut User,
+        data: &S,
+    ) -> Result<bool, Error> {
+        let state = self.state.lock();
+        let Some(code) = state.hooks.get(hook) else {
+            return Ok(false);
+        };
+        let lua = &state.lua;
+        let result = state.run_scoped(hook, user, lua.to_value(data)?, || {
+            lua.load(code)
+                .set_name(format!("={hook}"))
+                .call::<mlua::Value>(())
+        })?;
+        match result {
+            Ok(value) => Ok(matches!(value, mlua::Value::Boolean(true))),
+            // script errors shouldn't affect the caller
+            Err(e) => {
+                log::warn!("Server script {hook} failed: {e}");
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn load_hooks(dir: Option<&Path>) -> Result<HashMap<String, String>, Error> {
+    let mut hooks = HashMap::new();
+    let Some(dir) = dir else {
+        return Ok(hooks);
+    };
+    if !dir.exists() {
+        log::warn!("Scripts directory {} doesn't exist", dir.display());
+        return Ok(hooks);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != "lua") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !HOOKS.contains(&name) {
+            log::warn!("Unknown hook script: {}", path.display());
+            continue;
+        }
+        log::debug!("Loaded hook {name}");
+        hooks.insert(name.to_string(), std::fs::read_to_string(&path)?);
+    }
+    Ok(hooks)
+}
+
+fn load_commands(lua: &Lua, dir: Option<&Path>) -> Result<Vec<LuaCommand>, Error> {
+    let Some(dir) = dir.map(|d| d.join("commands")).filter(|d| d.exists()) else {
+        return Ok(vec![]);
+    };
+    let commands = RefCell::new(Vec::<LuaCommand>::new());
+    let globals = lua.globals();
+    lua.scope(|scope| {
+        globals.set(
+            "register_command",
+            scope.create_function(|lua, (spec, handler): (mlua::Value, mlua::Function)| {
+                let spec: CommandSpec = lua.from_value(spec)?;
+                if spec.name.is_empty() || spec.name.contains(char::is_whitespace) {
+                    return Err(mlua::Error::runtime("invalid command name"));
+                }
+                let mut commands = commands.borrow_mut();
+                if let Some(pos) = commands.iter().position(|c| c.spec.name == spec.name) {
+                    log::warn!("Command {} was registered twice", spec.name);
+                    commands.remove(pos);
+                }
+                log::debug!("Registered command {}", spec.name);
+                commands.push(LuaCommand { spec, handler });
+                Ok(())
+            })?,
+        )?;
+        for entry in std::fs::read_dir(&dir).map_err(mlua::Error::external)? {
+            let path = entry.map_err(mlua::Error::external)?.path();
+            if path.extension().is_none_or(|e| e != "lua") {
+                continue;
+            }
+            let code = std::fs::read_to_string(&path).map_err(mlua::Error::external)?;
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            if let Err(e) = eval_limited::<()>(lua, &name, &code) {
+                log::warn!("Command script {} failed: {e}", path.display());
+            }
+        }
+        Ok(())
+    })?;
+    globals.raw_remove("register_command")?;
+    Ok(commands.into_inner())
+}
+
+fn setup_scope<'s>(
+    globals: &mlua::Table,
+    scope: &'s mlua::Scope<'s, '_>,
+    user: &'s RefCell<&mut User>,
+    block: &'s Arc<BlockData>,
+) -> Result<(), mlua::Error> {
+    /* LUA FUNCTIONS */
+
+    // get information about the player
+    globals.set(
+        "get_player",
+        scope.create_function(|lua, ()| {
+            let user = user.borrow();
+            let character = user.character.as_ref().map(|c| &c.character);
+            lua.to_value(&PlayerInfo {
+                id: user.get_user_id(),
+                nickname: user.user_data.nickname.clone(),
+                name: character.map(|c| c.name.clone()).unwrap_or_default(),
+                level: character.map(|c| c.get_level().level1 as u32).unwrap_or(0),
+                class: character.map(|c| c.classes.main_class).unwrap_or_default(),
+                is_gm: user.user_data.isgm,
+            })
+        })?,
+    )?;
+    // get account flag
+    globals.set(
+        "get_account_flag",
+        scope.create_function(|_, flag: u32| -> Result<u8, _> {
+            Ok(user.borrow().get_account_flags().get(flag as _))
+        })?,
+    )?;
+    // get character flag
+    globals.set(
+        "get_character_flag",
+        scope.create_function(|_, flag: u32| -> Result<u8, _> {
+            let flags = user.borrow().get_char_flags();
+            Ok(flags.map(|f| f.get(flag as _)).unwrap_or_default())
+        })?,
+    )?;
+    // set account flag
+    globals.set(
+        "set_account_flag",
+        scope.create_function(|_, (flag, value): (u32, bool)| {
+            // the player might have already disconnected, but the flag is still saved
+            let _ = user.borrow_mut().set_account_flag_block(flag, value);
+            Ok(())
+        })?,
+    )?;
+    // set character flag
+    globals.set(
+        "set_character_flag",
+        scope.create_function(|_, (flag, value): (u32, bool)| {
+            let _ = user.borrow_mut().set_char_flag_block(flag, value);
+            Ok(())
+        })?,
+    )?;
+    // send system message to the player
+    globals.set(
+        "send_message",
+        scope.create_function(|_, msg: String| {
+            let _ = user.borrow_mut().try_send_packet(&system_msg(msg));
+            Ok(())
+        })?,
+    )?;
+    // send system message to all players on the block
+    globals.set(
+        "broadcast_message",
+        scope.create_function(|_, msg: String| {
+            // caller holds the lock on the player, so the message is sent in the background
+            let block = block.clone();
+            tokio::spawn(async move { block.send_to_all(&system_msg(msg)).await });
+            Ok(())
+        })?,
+    )?;
+    // get information about the block
+    globals.set(
+        "get_block",
+        scope.create_function(|lua, ()| {
+            lua.to_value(&BlockInfo {
+                id: block.block_id,
+                name: block.block_name.clone(),
+                // hooks run while the player is locked, so `clients` can't be locked here
+                players: block.client_count.load(Ordering::Relaxed),
+            })
+        })?,
+    )?;
+
+    /* LUA FUNCTIONS END */
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let dir = std::env::temp_dir().join(format!("scripts_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("commands")).unwrap();
+        std::fs::write(
+            dir.join("commands/test.lua"),
+            r#"
+            register_command({
+                name = "give",
+                aliases = { "g" },
+                args = {
+                    { name = "amount", type = "integer" },
+                    { name = "reason", type = "rest", optional = true },
+                },
+                permission = "gm",
+                help = "Gives something.",
+            }, function(args) return "ok" end)
+            "#,
+        )
+        .unwrap();
+        let scripts = ServerScripts::load(dir.to_str()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(scripts.has_command("give", true));
+        assert!(scripts.has_command("g", true));
+        assert!(!scripts.has_command("give", false));
+        assert_eq!(scripts.commands_help(false), "");
+        assert_eq!(
+            scripts.commands_help(true),
+            "{yel} - give {amount} [reason] (aliases: g){def}: Gives something.\n"
+        );
+
+        let state = scripts.state.lock();
+        let spec = &state.commands[0].spec;
+        let args = spec.parse_args("5 for being nice").unwrap();
+        assert_eq!(args["amount"], ArgValue::Integer(5));
+        assert_eq!(args["reason"], ArgValue::String("for being nice".into()));
+        assert!(!spec.parse_args("5").unwrap().contains_key("reason"));
+        assert!(spec.parse_args("").is_err());
+        assert!(spec.parse_args("five").is_err());
+    }
+}
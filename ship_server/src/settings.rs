@@ -1,5 +1,6 @@
 use crate::Error;
 use clap::Parser;
+use data_structs::master_ship::ScheduledAnnouncement;
 use rsa::{
     RsaPrivateKey,
     pkcs8::{DecodePrivateKey, EncodePrivateKey},
@@ -22,9 +23,14 @@ pub struct Settings {
     pub master_ship: Option<String>,
     pub master_ship_psk: String,
     pub data_file: Option<String>,
+    /// Directory where server data received from the master ship is cached.
+    pub data_cache_dir: String,
+    /// Directory with server-level hook scripts.
+    pub scripts_dir: Option<String>,
     pub log_dir: String,
     pub file_log_level: log::LevelFilter,
     pub console_log_level: log::LevelFilter,
+    pub announcements: Vec<ScheduledAnnouncement>,
 }
 
 #[derive(Parser, Debug)]
@@ -165,9 +171,12 @@ impl Default for Settings {
             master_ship: None,
             master_ship_psk: String::from("master_ship_psk"),
             data_file: None,
+            data_cache_dir: String::from("data_cache"),
+            scripts_dir: None,
             log_dir: String::from("logs"),
             file_log_level: log::LevelFilter::Info,
             console_log_level: log::LevelFilter::Debug,
+            announcements: vec![],
         }
     }
 }
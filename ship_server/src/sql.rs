@@ -2,7 +2,7 @@ use crate::{Error, inventory::Inventory, master_conn::MasterConnection, palette:
 use data_structs::{
     flags::Flags,
     inventory::AccountStorages,
-    master_ship::{MasterShipAction, SetNicknameResult, UserCreds, UserLoginResult},
+    master_ship::{MasterShipAction, QuestRecord, SetNicknameResult, UserCreds, UserLoginResult},
 };
 use pso2packetlib::{
     AsciiString,
@@ -10,6 +10,7 @@ use pso2packetlib::{
         PacketType,
         login::{Language, LoginAttempt, UserInfoPacket},
         models::character::Character,
+        questlist::QuestDifficultyType,
     },
 };
 use sqlx::{Executor, Row, migrate::MigrateDatabase};
@@ -50,6 +51,16 @@ pub struct CharData {
     pub unlocked_quests: Vec<u32>,
     pub unlocked_quests_notif: Vec<u32>,
     pub play_time: Duration,
+    pub cleared_quests: Vec<ClearedQuest>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct ClearedQuest {
+    pub name_id: u32,
+    pub difficulties: QuestDifficultyType,
+    pub clear_count: u32,
+    pub best_time: Duration,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
@@ -65,6 +76,8 @@ impl Sql {
             Self::create_db(path).await?
         } else {
             let conn = sqlx::SqlitePool::connect(path).await?;
+            // creates tables that were added after the database was created
+            Self::create_tables(&conn).await?;
             sqlx::query("delete from Challenges").execute(&conn).await?;
             conn
         };
@@ -74,9 +87,31 @@ impl Sql {
         })
     }
 
+    /// Creates a database that only lives in memory.
+    #[cfg(any(test, feature = "harness"))]
+    pub async fn new_in_memory(master_ship: MasterConnection) -> Result<Self, Error> {
+        // every connection to an in-memory database opens a new database
+        let conn = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await?;
+        Self::create_tables(&conn).await?;
+        Ok(Self {
+            connection: conn,
+            master_ship,
+        })
+    }
+
     async fn create_db(path: &str) -> Result<sqlx::SqlitePool, Error> {
         sqlx::Sqlite::create_database(path).await?;
         let conn = sqlx::SqlitePool::connect(path).await?;
+        Self::create_tables(&conn).await?;
+        Ok(conn)
+    }
+
+    async fn create_tables(conn: &sqlx::SqlitePool) -> Result<(), Error> {
         conn.execute(
             "
             create table if not exists Users (
@@ -114,7 +149,19 @@ impl Sql {
         ",
         )
         .await?;
-        Ok(conn)
+        conn.execute(
+            "
+            create table if not exists QuestRecords (
+                CharId integer,
+                QuestId integer,
+                Difficulty integer,
+                Time integer,
+                Data blob
+            );
+        ",
+        )
+        .await?;
+        Ok(())
     }
 
     pub async fn run_action(&self, action: MasterShipAction) -> Result<MasterShipAction, Error> {
@@ -162,6 +209,7 @@ impl Sql {
                     ..Default::default()
                 })
             }
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned(_)) => Err(Error::UserBanned),
             MasterShipAction::UserLoginResult(UserLoginResult::InvalidPassword(_)) => {
                 Err(Error::InvalidPassword)
             }
@@ -208,6 +256,7 @@ impl Sql {
                     ..Default::default()
                 })
             }
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned(_)) => Err(Error::UserBanned),
             MasterShipAction::UserLoginResult(UserLoginResult::InvalidPassword(_)) => {
                 Err(Error::MSUnexpected)
             }
@@ -379,6 +428,7 @@ impl Sql {
                     last_uuid,
                 })
             }
+            MasterShipAction::UserLoginResult(UserLoginResult::Banned(_)) => Err(Error::UserBanned),
             MasterShipAction::UserLoginResult(UserLoginResult::InvalidPassword(_)) => {
                 Err(Error::MSUnexpected)
             }
@@ -456,6 +506,19 @@ impl Sql {
             .await?;
         Ok(())
     }
+    /// Saves several characters in one transaction (e.g. after a trade).
+    pub async fn update_characters(&self, chars: &[&CharData]) -> Result<(), Error> {
+        let mut transaction = self.connection.begin().await?;
+        for char in chars {
+            sqlx::query("update Characters set Data = ? where Id = ?")
+                .bind(rmp_serde::to_vec(char)?)
+                .bind(char.character.character_id as i64)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
     pub async fn put_character(&self, id: u32, char: CharData) -> Result<u32, Error> {
         let mut transaction = self.connection.begin().await?;
         let data = rmp_serde::to_vec(&char)?;
@@ -579,6 +642,90 @@ impl Sql {
         self.put_uuid(data.id, data.last_uuid).await?;
         Ok(())
     }
+    /// Saves the record if it's the best time of the character. Returns `true` if it was saved.
+    pub async fn put_quest_record(&self, record: &QuestRecord) -> Result<bool, Error> {
+        let mut transaction = self.connection.begin().await?;
+        let best = sqlx::query(
+            "select Time from QuestRecords where CharId = ? and QuestId = ? and Difficulty = ?",
+        )
+        .bind(record.char_id as i64)
+        .bind(record.quest_id as i64)
+        .bind(record.difficulty as i64)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(row) = best
+            && row.try_get::<i64, _>("Time")? as u64 <= record.time
+        {
+            return Ok(false);
+        }
+        sqlx::query("delete from QuestRecords where CharId = ? and QuestId = ? and Difficulty = ?")
+            .bind(record.char_id as i64)
+            .bind(record.quest_id as i64)
+            .bind(record.difficulty as i64)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(
+            "insert into QuestRecords (CharId, QuestId, Difficulty, Time, Data) values (?, ?, ?, ?, ?)",
+        )
+        .bind(record.char_id as i64)
+        .bind(record.quest_id as i64)
+        .bind(record.difficulty as i64)
+        .bind(record.time as i64)
+        .bind(rmp_serde::to_vec(record)?)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+    /// Returns the best clear times of a quest on this ship, fastest first.
+    pub async fn get_quest_records(
+        &self,
+        quest_id: u32,
+        difficulty: u16,
+        limit: u32,
+    ) -> Result<Vec<QuestRecord>, Error> {
+        let rows = sqlx::query(
+            "select Data from QuestRecords where QuestId = ? and Difficulty = ? order by Time asc limit ?",
+        )
+        .bind(quest_id as i64)
+        .bind(difficulty as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.connection)
+        .await?;
+        let mut records = vec![];
+        for row in rows {
+            records.push(rmp_serde::from_slice(row.try_get("Data")?)?);
+        }
+        Ok(records)
+    }
+    pub async fn put_network_quest_record(&self, record: QuestRecord) -> Result<(), Error> {
+        let result = self
+            .run_action(MasterShipAction::PutQuestRecord(record))
+            .await?;
+        match result {
+            MasterShipAction::Ok => Ok(()),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
+    /// Returns the best clear times of a quest on all ships, fastest first.
+    pub async fn get_network_quest_records(
+        &self,
+        quest_id: u32,
+        difficulty: u16,
+    ) -> Result<Vec<QuestRecord>, Error> {
+        let result = self
+            .run_action(MasterShipAction::GetQuestRecords {
+                quest_id,
+                difficulty,
+            })
+            .await?;
+        match result {
+            MasterShipAction::QuestRecords(records) => Ok(records),
+            MasterShipAction::Error(e) => Err(Error::MSError(e)),
+            _ => Err(Error::MSUnexpected),
+        }
+    }
     async fn update_userdata<F>(&self, user_id: u32, f: F) -> Result<(), Error>
     where
         F: FnOnce(&mut UserData) + Send,
@@ -0,0 +1,309 @@
+use crate::{
+    Error, User,
+    inventory::{TransactionError, item_name},
+    mutex::Mutex,
+};
+use data_structs::inventory::ItemParameters;
+use pso2packetlib::protocol::{Packet, items::ItemId, login::Language};
+use std::sync::{Arc, Weak};
+
+/// Direct trade of items and meseta between two players.
+pub struct Trade {
+    sides: [TradeSide; 2],
+}
+
+pub struct TradeSide {
+    pub player_id: u32,
+    pub name: String,
+    pub user: Weak<Mutex<User>>,
+    // uuid, amount, item id
+    items: Vec<(u64, u16, ItemId)>,
+    meseta: u64,
+    /// Offer is locked by the first confirmation.
+    locked: bool,
+    /// Trade is accepted by the second confirmation.
+    confirmed: bool,
+}
+
+/// Result of a trade confirmation.
+pub enum Confirmation {
+    /// The player has locked their offer.
+    Locked,
+    /// The other player hasn't locked their offer yet.
+    WaitingForOther,
+    /// The player has accepted the trade, but the other player hasn't yet.
+    Accepted,
+    /// Both players have accepted the trade.
+    Completed,
+}
+
+impl TradeSide {
+    pub const fn new(player_id: u32, name: String, user: Weak<Mutex<User>>) -> Self {
+        Self {
+            player_id,
+            name,
+            user,
+            items: vec![],
+            meseta: 0,
+            locked: false,
+            confirmed: false,
+        }
+    }
+}
+
+impl Trade {
+    pub const fn new(first: TradeSide, second: TradeSide) -> Self {
+        Self {
+            sides: [first, second],
+        }
+    }
+    /// Returns the side of the player and the side of their partner.
+    const fn sides_mut(&mut self, player_id: u32) -> (&mut TradeSide, &mut TradeSide) {
+        let [first, second] = &mut self.sides;
+        if first.player_id == player_id {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+    const fn sides(&self, player_id: u32) -> (&TradeSide, &TradeSide) {
+        let [first, second] = &self.sides;
+        if first.player_id == player_id {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+    /// Returns the trade partner of the player.
+    pub const fn partner(&self, player_id: u32) -> &TradeSide {
+        self.sides(player_id).1
+    }
+    fn reset_confirmations(&mut self) {
+        for side in &mut self.sides {
+            side.locked = false;
+            side.confirmed = false;
+        }
+    }
+    /// Sets the offered amount of the item. Amount of 0 removes the item from the offer.
+    ///
+    /// Changing the offer resets confirmations of both players.
+    pub fn set_item(&mut self, player_id: u32, uuid: u64, amount: u16, id: ItemId) {
+        let (side, _) = self.sides_mut(player_id);
+        side.items.retain(|(u, ..)| *u != uuid);
+        if amount != 0 {
+            side.items.push((uuid, amount, id));
+        }
+        self.reset_confirmations();
+    }
+    /// Sets the offered amount of meseta.
+    ///
+    /// Changing the offer resets confirmations of both players.
+    pub fn set_meseta(&mut self, player_id: u32, meseta: u64) {
+        self.sides_mut(player_id).0.meseta = meseta;
+        self.reset_confirmations();
+    }
+    /// Confirms the trade. The first confirmation locks the offer, the second one accepts the
+    /// trade after both offers are locked.
+    pub const fn confirm(&mut self, player_id: u32) -> Confirmation {
+        let (side, partner) = self.sides_mut(player_id);
+        if !side.locked {
+            side.locked = true;
+            return Confirmation::Locked;
+        }
+        if !partner.locked {
+            return Confirmation::WaitingForOther;
+        }
+        side.confirmed = true;
+        if partner.confirmed {
+            Confirmation::Completed
+        } else {
+            Confirmation::Accepted
+        }
+    }
+    /// Describes the offers of both players.
+    pub fn summary(&self, player_id: u32, params: &ItemParameters, lang: Language) -> String {
+        let (side, partner) = self.sides(player_id);
+        let describe = |side: &TradeSide| {
+            let mut msg = String::new();
+            for (_, amount, id) in &side.items {
+                msg.push_str(&format!("\n - {} x{amount}", item_name(params, *id, lang)));
+            }
+            msg.push_str(&format!("\n - {} meseta", side.meseta));
+            if side.confirmed {
+                msg.push_str("\n(accepted)");
+            } else if side.locked {
+                msg.push_str("\n(locked)");
+            }
+            msg
+        };
+        format!(
+            "{{yel}}Trade with {}{{def}}\nYour offer:{}\n{}'s offer:{}",
+            partner.name,
+            describe(side),
+            partner.name,
+            describe(partner)
+        )
+    }
+
+    /// Returns offers of both players if both of them have accepted the trade.
+    fn accepted_offers(&self) -> Option<[Offer; 2]> {
+        if !self.sides.iter().all(|s| s.confirmed) {
+            return None;
+        }
+        Some(self.sides.each_ref().map(|s| {
+            let items: Vec<_> = s.items.iter().map(|(u, a, _)| (*u, *a)).collect();
+            (items, s.meseta)
+        }))
+    }
+
+    /// Moves offered items and meseta between the players and saves both characters.
+    ///
+    /// Either all items are moved or none of them. Nothing happens if an offer was changed after
+    /// the trade was accepted.
+    pub async fn execute(trade: &Arc<parking_lot::Mutex<Self>>) -> Result<(), Error> {
+        let users = trade.lock().sides.each_ref().map(|s| s.user.upgrade());
+        let [Some(first), Some(second)] = users else {
+            for user in users.into_iter().flatten() {
+                let mut user = user.lock().await;
+                user.trade = None;
+                user.send_system_msg("Trade was cancelled.").await?;
+            }
+            return Ok(());
+        };
+        // lock in a fixed order, so concurrent trades can't deadlock
+        let (mut first, mut second) = if Arc::as_ptr(&first) < Arc::as_ptr(&second) {
+            let first = first.lock().await;
+            (first, second.lock().await)
+        } else {
+            let second = second.lock().await;
+            (first.lock().await, second)
+        };
+        let is_same_trade =
+            |user: &User| user.trade.as_ref().is_some_and(|t| Arc::ptr_eq(t, trade));
+        let is_same_map = match (first.get_current_map(), second.get_current_map()) {
+            (Some(m1), Some(m2)) => Arc::ptr_eq(&m1, &m2),
+            _ => false,
+        };
+        if !is_same_trade(&first) || !is_same_trade(&second) || !is_same_map {
+            for user in [&mut first, &mut second] {
+                if is_same_trade(user) {
+                    user.trade = None;
+                    user.send_system_msg("Trade was cancelled.").await?;
+                }
+            }
+            return Ok(());
+        }
+        // offers can only change while the player is locked, so they are final now
+        let Some([first_offer, second_offer]) = trade.lock().accepted_offers() else {
+            return Ok(());
+        };
+        first.trade = None;
+        second.trade = None;
+        match exchange(&mut first, &mut second, first_offer, second_offer) {
+            Ok([first_packets, second_packets]) => {
+                for packet in first_packets {
+                    first.send_packet(&packet).await?;
+                }
+                for packet in second_packets {
+                    second.send_packet(&packet).await?;
+                }
+                first.send_system_msg("Trade completed.").await?;
+                second.send_system_msg("Trade completed.").await?;
+            }
+            Err(e) => {
+                let msg = format!("{{red}}Trade failed: {e}{{def}}");
+                first.send_system_msg(&msg).await?;
+                second.send_system_msg(&msg).await?;
+                return Ok(());
+            }
+        }
+
+        let sql = first.get_blockdata().sql.clone();
+        let (Some(first_char), Some(second_char)) = (&first.character, &second.character) else {
+            unreachable!("Trading players should have characters")
+        };
+        sql.update_characters(&[first_char, second_char]).await?;
+        for user in [&first, &second] {
+            if let Err(e) = sql
+                .put_uuid(user.get_user_id(), user.user_data.last_uuid)
+                .await
+            {
+                log::warn!("Failed to save UUID of player {}: {e}", user.get_user_id());
+            }
+        }
+        Ok(())
+    }
+}
+
+type Offer = (Vec<(u64, u16)>, u64);
+
+/// Exchanges offers using copies of the inventories, so they are only changed if every item can
+/// be moved.
+fn exchange(
+    first: &mut User,
+    second: &mut User,
+    first_offer: Offer,
+    second_offer: Offer,
+) -> Result<[Vec<Packet>; 2], TransactionError> {
+    let (Some(first_char), Some(second_char)) = (&first.character, &second.character) else {
+        return Err(TransactionError::NoItem);
+    };
+    let mut first_inv = first_char.inventory.clone();
+    let mut second_inv = second_char.inventory.clone();
+    let mut first_uuid = first.user_data.last_uuid;
+    let mut second_uuid = second.user_data.last_uuid;
+
+    let (first_items, mut first_packets) =
+        first_inv.take_trade_offer(&first_offer.0, first_offer.1)?;
+    let (second_items, mut second_packets) =
+        second_inv.take_trade_offer(&second_offer.0, second_offer.1)?;
+    first_packets.extend(first_inv.receive_trade_offer(
+        second_items,
+        second_offer.1,
+        &mut first_uuid,
+    )?);
+    second_packets.extend(second_inv.receive_trade_offer(
+        first_items,
+        first_offer.1,
+        &mut second_uuid,
+    )?);
+
+    if let Some(char) = first.character.as_mut() {
+        char.inventory = first_inv;
+    }
+    if let Some(char) = second.character.as_mut() {
+        char.inventory = second_inv;
+    }
+    first.user_data.last_uuid = first_uuid;
+    second.user_data.last_uuid = second_uuid;
+    Ok([first_packets, second_packets])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Confirmation, Trade, TradeSide};
+    use std::sync::Weak;
+
+    #[test]
+    fn trade_confirmation() {
+        let mut trade = Trade::new(
+            TradeSide::new(1, "first".into(), Weak::new()),
+            TradeSide::new(2, "second".into(), Weak::new()),
+        );
+        assert!(matches!(trade.confirm(1), Confirmation::Locked));
+        assert!(matches!(trade.confirm(1), Confirmation::WaitingForOther));
+        assert!(matches!(trade.confirm(2), Confirmation::Locked));
+        assert!(matches!(trade.confirm(2), Confirmation::Accepted));
+        // changing the offer requires new confirmations
+        trade.set_meseta(1, 100);
+        assert!(matches!(trade.confirm(2), Confirmation::Locked));
+        assert!(matches!(trade.confirm(1), Confirmation::Locked));
+        assert!(trade.accepted_offers().is_none());
+        assert!(matches!(trade.confirm(1), Confirmation::Accepted));
+        assert!(matches!(trade.confirm(2), Confirmation::Completed));
+        assert!(trade.accepted_offers().is_some());
+        // an offer changed after the acceptance can't be executed
+        trade.set_meseta(2, 50);
+        assert!(trade.accepted_offers().is_none());
+    }
+}
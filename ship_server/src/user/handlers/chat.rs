@@ -1,10 +1,24 @@
-use super::HResult;
-use crate::{Action, mutex::MutexGuard, user::User};
+use super::{HResult, item, trade};
+use crate::{
+    Action,
+    announcements::{self, BroadcastScope},
+    battle_stats::PlayerStats,
+    inventory::{TransactionError, item_name},
+    mutex::MutexGuard,
+    scripts::ChatInfo,
+    user::User,
+};
+use data_structs::master_ship::{Announcement, AnnouncementType, QuestRecord};
 use indicatif::HumanBytes;
 use memory_stats::memory_stats;
 use pso2packetlib::protocol::{
-    ObjectType, Packet, chat::MessageChannel, flag::FlagType, items::ItemId, playerstatus,
+    ObjectType, Packet,
+    chat::MessageChannel,
+    flag::FlagType,
+    items::{ItemId, ItemType},
+    playerstatus,
 };
+use std::time::Duration;
 
 #[derive(Debug, cmd_derive::ChatCommand)]
 enum ChatCommand {
@@ -47,6 +61,65 @@ enum ChatCommand {
     ForceQuest { quest_id: u32, difficulty_id: u16 },
     /// Spawns a new enemy at the players location.
     SpawnEnemy { enemy_name: String },
+    /// Shows the best clear times of a quest on this ship.
+    #[alias("records")]
+    QuestRecords { quest_id: u32, difficulty: u16 },
+    /// Shows the best clear times of a quest on all ships.
+    #[alias("global_records")]
+    GlobalQuestRecords { quest_id: u32, difficulty: u16 },
+    /// Lists the items of the last visited NPC shop and the sell prices of inventory items.
+    Shop,
+    /// Buys an item from the last visited NPC shop. Item is the number from the shop list.
+    Buy { item: usize, amount: u16 },
+    /// Sells an inventory item. Slot is the number from the inventory list in the shop.
+    Sell { slot: usize, amount: u16 },
+    /// Requests a trade with a player in the same area or accepts their trade request.
+    Trade {
+        #[rest]
+        name: String,
+    },
+    /// Adds an inventory item to the trade offer. Slot is the number from the inventory list in
+    /// the shop. Amount of 0 removes the item from the offer.
+    TradeItem { slot: usize, amount: u16 },
+    /// Sets the amount of meseta in the trade offer.
+    TradeMeseta { amount: u64 },
+    /// Locks the trade offer. Confirming again after both offers are locked accepts the trade.
+    TradeConfirm,
+    /// Cancels the trade and rejects all trade requests.
+    TradeCancel,
+    /// Uses a consumable. Slot is the number from the inventory list in the shop.
+    #[alias("use")]
+    UseItem { slot: usize },
+    /// Shows the grind level, abilities and next grind cost of a weapon. Slot is the number from
+    /// the inventory list in the shop.
+    WeaponInfo { slot: usize },
+    /// Raises the grind level of a weapon. Slot is the number from the inventory list in the shop.
+    Grind { slot: usize },
+    /// Transfers an ability from the fodder weapon to the base weapon. The fodder is consumed.
+    /// Ability is the number from the fodder ability list.
+    Affix {
+        base: usize,
+        fodder: usize,
+        ability: usize,
+    },
+    /// Reloads server data (maps, quests, scripts). Only new map instances use the new data.
+    #[only_gm]
+    ReloadData,
+    /// Sends a system message to all players. Scope can be "block", "ship" or "all".
+    #[only_gm]
+    Announce {
+        scope: BroadcastScope,
+        #[rest]
+        message: String,
+    },
+    /// Sends a scrolling announcement to all players. Scope can be "block", "ship" or "all".
+    #[only_gm]
+    #[alias("scroll")]
+    AnnounceScroll {
+        scope: BroadcastScope,
+        #[rest]
+        message: String,
+    },
     #[help]
     Help(String),
 }
@@ -57,7 +130,17 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
     };
     if data.message.starts_with('!') {
         let args = data.message.strip_prefix("!").unwrap();
-        let cmd = ChatCommand::parse(args, user.user_data.isgm);
+        let is_gm = user.user_data.isgm;
+        let scripts = user.blockdata.scripts.clone();
+        let name = args.split_whitespace().next().unwrap_or_default();
+        // built-in commands take precedence over script commands
+        if !ChatCommand::is_command(name, is_gm) && scripts.has_command(name, is_gm) {
+            if let Some(msg) = scripts.run_command(&mut user, args.trim_start()).await {
+                user.send_system_msg(&msg).await?;
+            }
+            return Ok(Action::Nothing);
+        }
+        let cmd = ChatCommand::parse(args, is_gm, &scripts.commands_help(is_gm));
         let Ok(cmd) = cmd else {
             let err = cmd.unwrap_err();
             user.send_system_msg(&err).await?;
@@ -182,7 +265,7 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 user.send_packet(&packet).await?;
             }
             ChatCommand::ChangeLevel { new_level } => {
-                let srv_data = user.blockdata.server_data.clone();
+                let srv_data = user.blockdata.server_data().clone();
                 let Some(char) = user.character.as_mut() else {
                     user.send_system_msg("No character loaded").await?;
                     return Ok(Action::Nothing);
@@ -248,12 +331,198 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
                 drop(user);
                 map.lock().await.spawn_enemy(zone, &enemy_name, pos).await?;
             }
+            ChatCommand::QuestRecords {
+                quest_id,
+                difficulty,
+            } => {
+                let sql = user.blockdata.sql.clone();
+                let records = sql.get_quest_records(quest_id, difficulty, 10).await?;
+                send_records(&mut user, &records).await?;
+            }
+            ChatCommand::GlobalQuestRecords {
+                quest_id,
+                difficulty,
+            } => {
+                let sql = user.blockdata.sql.clone();
+                match sql.get_network_quest_records(quest_id, difficulty).await {
+                    Ok(records) => send_records(&mut user, &records).await?,
+                    Err(e) => {
+                        user.send_system_msg(&format!("{{red}}Failed to get records: {e}{{def}}"))
+                            .await?
+                    }
+                }
+            }
+            ChatCommand::Shop => send_shop(&mut user).await?,
+            ChatCommand::Buy { item, amount } => {
+                let Some(shop_item) = user
+                    .shop
+                    .as_ref()
+                    .and_then(|s| s.items.get(item.wrapping_sub(1)))
+                    .cloned()
+                else {
+                    user.send_system_msg("{red}Unknown shop item{def}").await?;
+                    return Ok(Action::Nothing);
+                };
+                let srv_data = user.blockdata.server_data();
+                let user: &mut User = &mut user;
+                let Some(character) = user.character.as_mut() else {
+                    unreachable!("User should be in state >= `InGame`")
+                };
+                let result = character.inventory.buy_item(
+                    &mut user.user_data.last_uuid,
+                    shop_item.item,
+                    amount,
+                    shop_item.price,
+                    &srv_data.item_params,
+                );
+                match result {
+                    Ok(packets) => {
+                        for packet in packets {
+                            user.send_packet(&packet).await?;
+                        }
+                    }
+                    Err(e) => user.send_system_msg(&format!("{{red}}{e}{{def}}")).await?,
+                }
+            }
+            ChatCommand::Sell { slot, amount } => {
+                if user.shop.is_none() {
+                    user.send_system_msg("{red}Talk to a shop first{def}")
+                        .await?;
+                    return Ok(Action::Nothing);
+                }
+                let srv_data = user.blockdata.server_data();
+                let Some(character) = user.character.as_mut() else {
+                    unreachable!("User should be in state >= `InGame`")
+                };
+                let result = match character
+                    .inventory
+                    .get_inv_items()
+                    .get(slot.wrapping_sub(1))
+                {
+                    Some(item) => {
+                        let uuid = item.uuid;
+                        character
+                            .inventory
+                            .sell_item(uuid, amount, &srv_data.item_params)
+                    }
+                    None => Err(TransactionError::NoItem),
+                };
+                match result {
+                    Ok(packets) => {
+                        for packet in packets {
+                            user.send_packet(&packet).await?;
+                        }
+                    }
+                    Err(e) => user.send_system_msg(&format!("{{red}}{e}{{def}}")).await?,
+                }
+            }
+            ChatCommand::Trade { name } => trade::request(&mut user, &name).await?,
+            ChatCommand::TradeItem { slot, amount } => {
+                trade::offer_item(&mut user, slot, amount).await?
+            }
+            ChatCommand::TradeMeseta { amount } => trade::offer_meseta(&mut user, amount).await?,
+            ChatCommand::TradeConfirm => trade::confirm(&mut user).await?,
+            ChatCommand::TradeCancel => trade::cancel(&mut user).await?,
+            ChatCommand::UseItem { slot } => item::use_item(&mut user, slot).await?,
+            ChatCommand::WeaponInfo { slot } => send_weapon_info(&mut user, slot).await?,
+            ChatCommand::Grind { slot } => {
+                let srv_data = user.blockdata.server_data();
+                let Some(character) = user.character.as_mut() else {
+                    unreachable!("User should be in state >= `InGame`")
+                };
+                let inventory = &mut character.inventory;
+                let result = match inventory.get_inv_items().get(slot.wrapping_sub(1)) {
+                    Some(item) => {
+                        let uuid = item.uuid;
+                        inventory.grind_item(
+                            uuid,
+                            &srv_data.item_params.upgrades,
+                            &mut rand::thread_rng(),
+                        )
+                    }
+                    None => Err(TransactionError::NoItem),
+                };
+                send_upgrade_result(&mut user, result, "Grind").await?;
+            }
+            ChatCommand::Affix {
+                base,
+                fodder,
+                ability,
+            } => {
+                let srv_data = user.blockdata.server_data();
+                let Some(character) = user.character.as_mut() else {
+                    unreachable!("User should be in state >= `InGame`")
+                };
+                let inventory = &mut character.inventory;
+                let items = inventory.get_inv_items();
+                let base = items.get(base.wrapping_sub(1)).map(|i| i.uuid);
+                let fodder = items.get(fodder.wrapping_sub(1)).map(|i| i.uuid);
+                let result = match base.zip(fodder) {
+                    Some((base, fodder)) => inventory.affix_item(
+                        base,
+                        fodder,
+                        ability.wrapping_sub(1),
+                        &srv_data.item_params.upgrades,
+                        &mut rand::thread_rng(),
+                    ),
+                    None => Err(TransactionError::NoItem),
+                };
+                send_upgrade_result(&mut user, result, "Affixing").await?;
+            }
+            ChatCommand::ReloadData => {
+                let block_data = user.blockdata.clone();
+                match block_data.data.reload(&block_data.sql).await {
+                    Ok(true) => {
+                        let version = block_data.data.version();
+                        user.send_system_msg(&format!("Server data reloaded (version {version})"))
+                            .await?
+                    }
+                    Ok(false) => {
+                        user.send_system_msg("Server data is already up to date")
+                            .await?
+                    }
+                    Err(e) => {
+                        user.send_system_msg(&format!("{{red}}Failed to reload data: {e}{{def}}"))
+                            .await?
+                    }
+                }
+                match block_data.scripts.reload() {
+                    Ok((hooks, commands)) => {
+                        user.send_system_msg(&format!(
+                            "Loaded {hooks} server hooks and {commands} script commands"
+                        ))
+                        .await?
+                    }
+                    Err(e) => {
+                        user.send_system_msg(&format!(
+                            "{{red}}Failed to reload server scripts: {e}{{def}}"
+                        ))
+                        .await?
+                    }
+                }
+            }
+            ChatCommand::Announce { scope, message } => {
+                let kind = AnnouncementType::SystemMessage;
+                announce(user, scope, Announcement { message, kind }).await?;
+            }
+            ChatCommand::AnnounceScroll { scope, message } => {
+                let kind = AnnouncementType::Scrolling;
+                announce(user, scope, Announcement { message, kind }).await?;
+            }
             ChatCommand::Help(msg) => {
                 user.send_system_msg(&msg).await?;
             }
         }
         return Ok(Action::Nothing);
     }
+    let scripts = user.blockdata.scripts.clone();
+    let info = ChatInfo {
+        message: &data.message,
+        channel: data.channel,
+    };
+    if scripts.run("on_chat", &mut user, &info).await {
+        return Ok(Action::Nothing);
+    }
     let id = user.get_user_id();
     match data.channel {
         MessageChannel::Map => {
@@ -276,6 +545,174 @@ pub async fn send_chat(mut user: MutexGuard<'_, User>, packet: Packet) -> HResul
     Ok(Action::Nothing)
 }
 
+async fn announce(
+    user: MutexGuard<'_, User>,
+    scope: BroadcastScope,
+    announcement: Announcement,
+) -> Result<(), crate::Error> {
+    let block_data = user.blockdata.clone();
+    log::info!(
+        "Player {} sent announcement ({scope:?}): {}",
+        user.get_user_id(),
+        announcement.message
+    );
+    // the announcement is also sent to the caller
+    drop(user);
+    announcements::broadcast(&block_data, scope, announcement).await
+}
+
+async fn send_records(user: &mut User, records: &[QuestRecord]) -> Result<(), crate::Error> {
+    if records.is_empty() {
+        user.send_system_msg("No records").await?;
+        return Ok(());
+    }
+    for (i, record) in records.iter().enumerate() {
+        let time = Duration::from_millis(record.time);
+        let mut msg = format!(
+            "{}. {:02}:{:02}.{:03} {}",
+            i + 1,
+            time.as_secs() / 60,
+            time.as_secs() % 60,
+            time.subsec_millis(),
+            record.char_name
+        );
+        if !record.party.is_empty() {
+            msg.push_str(&format!(" with {}", record.party.join(", ")));
+        }
+        if record.ship_id != 0 {
+            msg.push_str(&format!(" (ship {})", record.ship_id));
+        }
+        user.send_system_msg(&msg).await?;
+    }
+    Ok(())
+}
+
+async fn send_shop(user: &mut User) -> Result<(), crate::Error> {
+    let Some(shop) = user.shop.clone() else {
+        user.send_system_msg("{red}Talk to a shop first{def}")
+            .await?;
+        return Ok(());
+    };
+    let srv_data = user.blockdata.server_data();
+    let params = &srv_data.item_params;
+    let lang = user.user_data.lang;
+    let mut msg = format!("{{yel}}{}{{def}}", shop.name);
+    for (i, item) in shop.items.iter().enumerate() {
+        msg.push_str(&format!(
+            "\n{}. {} - {} meseta",
+            i + 1,
+            item_name(params, item.item, lang),
+            item.price
+        ));
+    }
+    msg.push_str("\n{yel}Inventory{def}");
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    for (i, item) in character.inventory.get_inv_items().iter().enumerate() {
+        let amount = match &item.data {
+            ItemType::Consumable(data) => format!(" x{}", data.amount),
+            _ => String::new(),
+        };
+        let price = params
+            .prices
+            .iter()
+            .find(|p| p.id == item.id && p.sell_price != 0)
+            .map_or("can't be sold".to_string(), |p| {
+                format!("sells for {} meseta", p.sell_price)
+            });
+        msg.push_str(&format!(
+            "\n{}. {}{amount} - {price}",
+            i + 1,
+            item_name(params, item.id, lang)
+        ));
+    }
+    user.send_system_msg(&msg).await?;
+    Ok(())
+}
+
+async fn send_weapon_info(user: &mut User, slot: usize) -> Result<(), crate::Error> {
+    let srv_data = user.blockdata.server_data();
+    let params = &srv_data.item_params;
+    let upgrades = &params.upgrades;
+    let lang = user.user_data.lang;
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let Some(item) = character
+        .inventory
+        .get_inv_items()
+        .get(slot.wrapping_sub(1))
+    else {
+        let msg = format!("{{red}}{}{{def}}", TransactionError::NoItem);
+        return user.send_system_msg(&msg).await;
+    };
+    let ItemType::Weapon(data) = &item.data else {
+        let msg = format!("{{red}}{}{{def}}", TransactionError::NotUpgradable);
+        return user.send_system_msg(&msg).await;
+    };
+    let mut msg = format!(
+        "{{yel}}{} +{}{{def}}",
+        item_name(params, item.id, lang),
+        data.grind
+    );
+    match upgrades.grinds.get(data.grind as usize) {
+        Some(level) => {
+            msg.push_str(&format!(
+                "\nNext grind: {} meseta, {:.0}% success",
+                level.meseta,
+                level.success_rate * 100.0
+            ));
+            for material in &level.materials {
+                msg.push_str(&format!(
+                    "\n - {} x{}",
+                    item_name(params, material.item, lang),
+                    material.amount
+                ));
+            }
+        }
+        None => msg.push_str("\nMaximum grind level"),
+    }
+    msg.push_str("\nAbilities:");
+    for (i, affix) in data.affixes.iter().filter(|a| **a != 0).enumerate() {
+        let name = upgrades
+            .get_affix(*affix)
+            .map_or_else(|| affix.to_string(), |a| a.name.clone());
+        msg.push_str(&format!("\n{}. {name}", i + 1));
+    }
+    user.send_system_msg(&msg).await
+}
+
+/// Sends the result of grinding or affixing and updates the changed weapon.
+async fn send_upgrade_result(
+    user: &mut User,
+    result: Result<(bool, Vec<Packet>), TransactionError>,
+    action: &str,
+) -> Result<(), crate::Error> {
+    let (success, packets) = match result {
+        Ok(result) => result,
+        Err(e) => return user.send_system_msg(&format!("{{red}}{e}{{def}}")).await,
+    };
+    for packet in packets {
+        user.send_packet(&packet).await?;
+    }
+    if !success {
+        return user
+            .send_system_msg(&format!("{{red}}{action} failed.{{def}}"))
+            .await;
+    }
+    // there is no packet for updating item data, so the whole inventory is resent
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let packet = character
+        .inventory
+        .inventory_packet(user.get_user_id(), character.character.name.clone());
+    user.send_packet(&packet).await?;
+    PlayerStats::update(user)?;
+    user.send_system_msg(&format!("{action} succeeded.")).await
+}
+
 async fn set_flag_parse(
     user: &mut User,
     ftype: FlagType,
@@ -1,7 +1,12 @@
 use super::HResult;
-use crate::{Action, Error, User, mutex::MutexGuard};
+use crate::{
+    Action, Error, User,
+    inventory::TransactionError,
+    mutex::{Mutex, MutexGuard},
+};
+use data_structs::inventory::ItemEffect;
 use pso2packetlib::protocol::{
-    self, Packet,
+    self, ObjectHeader, ObjectType, Packet,
     items::{
         DiscardItemRequestPacket, DiscardStorageItemRequestPacket, EquipItemPacket,
         EquipItemRequestPacket, GetItemDescriptionPacket, ItemType, LoadItemDescriptionPacket,
@@ -9,6 +14,11 @@ use pso2packetlib::protocol::{
         MoveToStorageRequestPacket, UnequipItemPacket, UnequipItemRequestPacket,
     },
     login::Language,
+    objects::DamageReceivePacket,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 pub async fn move_to_storage(user: &mut User, packet: MoveToStorageRequestPacket) -> HResult {
@@ -62,7 +72,7 @@ pub async fn move_storages(user: &mut User, packet: MoveStoragesRequestPacket) -
 }
 
 pub async fn get_description(user: &mut User, packet: GetItemDescriptionPacket) -> HResult {
-    let names_ref = &user.blockdata.server_data.item_params;
+    let names_ref = &user.blockdata.server_data().item_params;
     match names_ref.names.iter().find(|x| x.id == packet.item) {
         Some(name) => {
             let packet = LoadItemDescriptionPacket {
@@ -93,7 +103,7 @@ pub async fn equip_item(mut user: MutexGuard<'_, User>, packet: EquipItemRequest
         //BUG: a (0x0F, 0x2B) packet should also be sent, but let's not worry about it at this time
         let block_data = user.get_blockdata();
         let clothing_stats = block_data
-            .server_data
+            .server_data()
             .item_params
             .attrs
             .human_costumes
@@ -146,3 +156,148 @@ pub async fn unequip_item(
 
     Ok(Action::Nothing)
 }
+
+/// Uses a consumable on the player and party members in range of the item.
+///
+/// The item is only consumed if it affects at least one player.
+pub async fn use_item(user: &mut MutexGuard<'_, User>, slot: usize) -> Result<(), Error> {
+    let (uuid, effect) = match usable_item(user, slot) {
+        Ok(result) => result,
+        Err(e) => return user.send_system_msg(&format!("{{red}}{e}{{def}}")).await,
+    };
+    let targets = party_in_range(user, &effect).await;
+    let packet = if targets.is_empty() && !user.get_stats().is_affected_by(&effect) {
+        Err(TransactionError::NoEffect)
+    } else {
+        take_used_item(user, uuid, &effect)
+    };
+    let packet = match packet {
+        Ok(packet) => packet,
+        Err(e) => return user.send_system_msg(&format!("{{red}}{e}{{def}}")).await,
+    };
+    user.send_packet(&packet).await?;
+    let user_id = user.get_user_id();
+    apply_effect(user, user_id, &effect).await?;
+    if targets.is_empty() {
+        return Ok(());
+    }
+    MutexGuard::unlocked_async(user, || async move {
+        for player in targets {
+            apply_effect(&mut *player.lock().await, user_id, &effect).await?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Checks that the item in the slot can be used and returns its UUID and effect.
+fn usable_item(user: &User, slot: usize) -> Result<(u64, ItemEffect), TransactionError> {
+    if user.get_stats().is_incapacitated() {
+        return Err(TransactionError::NotUsable);
+    }
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let item = character
+        .inventory
+        .get_inv_items()
+        .get(slot.wrapping_sub(1))
+        .ok_or(TransactionError::NoItem)?;
+    let effect = user
+        .blockdata
+        .server_data()
+        .item_params
+        .effects
+        .iter()
+        .find(|e| e.id == item.id)
+        .cloned()
+        .ok_or(TransactionError::NotUsable)?;
+    Ok((item.uuid, effect))
+}
+
+/// Returns other party members in range of the item that would be affected by it.
+async fn party_in_range(
+    user: &mut MutexGuard<'_, User>,
+    effect: &ItemEffect,
+) -> Vec<Arc<Mutex<User>>> {
+    if effect.range <= 0.0 {
+        return vec![];
+    }
+    let (Some(map), Some(party)) = (user.get_current_map(), user.get_current_party()) else {
+        return vec![];
+    };
+    let (user_id, position, zone) = (user.get_user_id(), user.position, user.zone_pos);
+    MutexGuard::unlocked_async(user, || async move {
+        let players = map.lock().await.get_players();
+        let mut targets = vec![];
+        for player in players {
+            let lock = player.lock().await;
+            let in_range = lock.get_user_id() != user_id
+                && lock.zone_pos == zone
+                && lock
+                    .get_current_party()
+                    .is_some_and(|p| Arc::ptr_eq(&p, &party))
+                && lock.position.dist_2d(&position) <= effect.range;
+            if in_range && lock.get_stats().is_affected_by(effect) {
+                drop(lock);
+                targets.push(player);
+            }
+        }
+        targets
+    })
+    .await
+}
+
+/// Removes the used item from the inventory and starts its cooldown.
+fn take_used_item(
+    user: &mut User,
+    uuid: u64,
+    effect: &ItemEffect,
+) -> Result<Packet, TransactionError> {
+    let now = Instant::now();
+    user.item_cooldowns.retain(|(_, ready)| *ready > now);
+    if user.item_cooldowns.iter().any(|(i, _)| *i == effect.id) {
+        return Err(TransactionError::OnCooldown);
+    }
+    let Some(character) = user.character.as_mut() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let packet = character.inventory.use_item(uuid)?;
+    if effect.cooldown != 0 {
+        let ready = now + Duration::from_secs(effect.cooldown as u64);
+        user.item_cooldowns.push((effect.id, ready));
+    }
+    Ok(packet)
+}
+
+/// Applies the item effect to the player and sends them their new HP.
+///
+/// Restored PP is only tracked on the server, there is no known packet that sets the PP of a
+/// player.
+async fn apply_effect(player: &mut User, user_id: u32, effect: &ItemEffect) -> Result<(), Error> {
+    let (old_hp, _) = player.get_stats().get_hp();
+    if !player.get_stats_mut().apply_effect(effect) {
+        return Ok(());
+    }
+    let (hp, _) = player.get_stats().get_hp();
+    if hp == old_hp {
+        return Ok(());
+    }
+    // pso2packetlib documents this packet as also used for healing, but the sign of
+    // `dmg_amount` for healing hasn't been verified against a captured session, so this is a
+    // guess.
+    let header = player.create_object_header();
+    let packet = Packet::DamageReceive(DamageReceivePacket {
+        receiver: header,
+        dmg_target: header,
+        dmg_inflicter: ObjectHeader {
+            id: user_id,
+            entity_type: ObjectType::Player,
+            ..Default::default()
+        },
+        dmg_amount: -((hp - old_hp) as i32),
+        new_hp: hp,
+        ..Default::default()
+    });
+    player.send_packet(&packet).await
+}
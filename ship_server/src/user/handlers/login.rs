@@ -11,6 +11,8 @@ use pso2packetlib::protocol::{
 };
 use std::time::{SystemTime, UNIX_EPOCH};
 
+const BANNED_MSG: &str = "This account has been suspended";
+
 pub async fn encryption_request(user: &mut User, _: login::EncryptionRequestPacket) -> HResult {
     let key = user.connection.get_key();
     user.send_packet(&Packet::EncryptionResponse(
@@ -50,19 +52,28 @@ pub async fn login_request(user: &mut User, packet: Packet) -> HResult {
                     status = login::LoginStatus::Failure;
                     error = "Empty username or password".to_string();
                 }
+                Err(Error::UserBanned) => {
+                    status = login::LoginStatus::Failure;
+                    error = BANNED_MSG.to_string();
+                }
                 Err(e) => return Err(e),
             }
         }
         Packet::VitaLogin(packet) => {
             user.user_data.packet_type = PacketType::Vita;
             user.connection.change_packet_type(PacketType::Vita);
-            let mut user_psn = user
-                .blockdata
-                .sql
-                .get_psn_user(&packet.username, ip)
-                .await?;
-            user_psn.packet_type = user.user_data.packet_type;
-            user.user_data = user_psn;
+            let user_psn = user.blockdata.sql.get_psn_user(&packet.username, ip).await;
+            match user_psn {
+                Ok(mut user_psn) => {
+                    user_psn.packet_type = user.user_data.packet_type;
+                    user.user_data = user_psn;
+                }
+                Err(Error::UserBanned) => {
+                    status = login::LoginStatus::Failure;
+                    error = BANNED_MSG.to_string();
+                }
+                Err(e) => return Err(e),
+            }
         }
         _ => unreachable!(),
     }
@@ -229,6 +240,10 @@ pub async fn challenge_login(user: &mut User, packet: login::BlockLoginPacket) -
             status = login::LoginStatus::Failure;
             error = "Invalid user".to_string();
         }
+        Err(Error::UserBanned) => {
+            status = login::LoginStatus::Failure;
+            error = BANNED_MSG.to_string();
+        }
 
         Err(e) => return Err(e),
     }
@@ -409,7 +424,7 @@ pub async fn new_character(user: &mut User, packet: login::CharacterCreatePacket
     if !matches!(char_data.character.look.race, Race::Cast) {
         let clothes = user
             .blockdata
-            .server_data
+            .server_data()
             .item_params
             .attrs
             .human_costumes
@@ -438,7 +453,7 @@ pub async fn new_character(user: &mut User, packet: login::CharacterCreatePacket
     // add items
     {
         let block_data = user.blockdata.clone();
-        let class_data = &block_data.server_data.default_classes.classes
+        let class_data = &block_data.server_data().default_classes.classes
             [char_data.character.classes.main_class as usize];
         for item in &class_data.items {
             let uuid = user.user_data.last_uuid;
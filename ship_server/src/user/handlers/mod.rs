@@ -14,5 +14,6 @@ pub mod quest;
 pub mod server;
 pub mod settings;
 pub mod symbolart;
+pub mod trade;
 
 type HResult = Result<Action, Error>;
@@ -114,7 +114,7 @@ pub async fn abandon_quest(user: MutexGuard<'_, User>) -> HResult {
     let party = user.get_current_party();
     drop(user);
     if let Some(party) = party {
-        party.write().await.abandon().await
+        party::Party::abandon(&party).await
     }
     Ok(Action::Nothing)
 }
@@ -1,5 +1,5 @@
 use super::HResult;
-use crate::{Action, User, mutex::MutexGuard, quests::PartyQuest};
+use crate::{Action, User, mutex::MutexGuard, quests::PartyQuest, scripts::QuestInfo};
 use pso2packetlib::protocol::{
     Packet, PacketHeader,
     flag::{CutsceneEndPacket, SkitItemAddRequestPacket},
@@ -42,7 +42,8 @@ pub async fn counter_request(user: &mut User) -> HResult {
         data,
     )))
     .await?;
-    let quests = user.blockdata.quests.clone();
+    let quests = user.blockdata.quests().clone();
+    quests.update_unlocks(user);
     let char = user
         .character
         .as_mut()
@@ -74,8 +75,11 @@ pub async fn avaliable_quests(
         .character
         .as_ref()
         .expect("Character should be loaded at this moment");
-    let packet =
-        Packet::AvailableQuests(user.blockdata.quests.get_availiable(&char.unlocked_quests));
+    let packet = Packet::AvailableQuests(
+        user.blockdata
+            .quests()
+            .get_availiable(&char.unlocked_quests),
+    );
     user.send_packet(&packet).await?;
     Ok(Action::Nothing)
 }
@@ -85,10 +89,11 @@ pub async fn quest_category(user: &mut User, packet: QuestCategoryRequestPacket)
         .character
         .as_ref()
         .expect("Character should be loaded at this moment");
-    let packet = user
-        .blockdata
-        .quests
-        .get_category(packet.category, &char.unlocked_quests);
+    let packet = user.blockdata.quests().get_category(
+        packet.category,
+        &char.unlocked_quests,
+        &char.cleared_quests,
+    );
     user.send_packet(&Packet::QuestCategory(packet)).await?;
     user.send_packet(&Packet::QuestCategoryStopper).await?;
 
@@ -97,7 +102,7 @@ pub async fn quest_category(user: &mut User, packet: QuestCategoryRequestPacket)
 
 pub async fn quest_difficulty(user: &mut User, packet: QuestDifficultyRequestPacket) -> HResult {
     for quest in packet.quests {
-        let diff = user.blockdata.quests.get_diff(quest.id);
+        let diff = user.blockdata.quests().get_diff(quest.id);
         if let Some(packet) = diff {
             user.send_packet(&Packet::QuestDifficulty(QuestDifficultyPacket {
                 quests: vec![packet],
@@ -109,11 +114,13 @@ pub async fn quest_difficulty(user: &mut User, packet: QuestDifficultyRequestPac
     Ok(Action::Nothing)
 }
 
-pub async fn set_quest(user: MutexGuard<'_, User>, packet: AcceptQuestPacket) -> HResult {
-    let quest = user
-        .blockdata
-        .quests
-        .get_quest(packet, &user.blockdata.latest_mapid)?;
+pub async fn set_quest(mut user: MutexGuard<'_, User>, packet: AcceptQuestPacket) -> HResult {
+    let block = user.blockdata.clone();
+    let quests = block.quests();
+    let quest = MutexGuard::unlocked_async(&mut user, || {
+        block.quest_instances.get_quest(&quests, packet, &block)
+    })
+    .await?;
     start_quest(user, quest).await
 }
 
@@ -151,20 +158,29 @@ pub async fn set_story_quest(
 ) -> HResult {
     let quest = user
         .blockdata
-        .quests
-        .get_story_quest(packet, &user.blockdata.latest_mapid)?;
+        .quests()
+        .get_story_quest(packet, &user.blockdata)?;
     start_quest(user, quest).await
 }
 
-pub async fn start_quest(user: MutexGuard<'_, User>, quest: PartyQuest) -> HResult {
+pub async fn start_quest(mut user: MutexGuard<'_, User>, quest: PartyQuest) -> HResult {
+    let scripts = user.blockdata.scripts.clone();
+    let info = QuestInfo {
+        id: quest.get_name_id(),
+        difficulty: quest.get_difficulty(),
+    };
+    scripts.run("on_quest_start", &mut user, &info).await;
     let is_insta = quest.is_insta_transfer();
     let user_id = user.get_user_id();
     let old_map = user.get_current_map().expect("User should have a map");
     let map = quest.get_map();
-    // we are the only owner of the map, so this never blocks
-    map.lock_blocking().set_block_data(user.blockdata.clone());
     let party = user.get_current_party();
     drop(user);
+    let party_size = match &party {
+        Some(party) => party.read().await.get_player_ids().len(),
+        None => 1,
+    };
+    map.lock().await.add_party(party_size);
     if let Some(party) = party {
         party.write().await.set_quest(quest).await;
     }
@@ -38,6 +38,7 @@ pub async fn initial_load(mut user: MutexGuard<'_, User>) -> HResult {
         .await?;
     let mut user_lock = user.lock().await;
     user_lock.state = UserState::InGame;
+    blockdata.scripts.run("on_login", &mut user_lock, &()).await;
     Ok(Action::Nothing)
 }
 
@@ -125,6 +126,18 @@ pub async fn campship_down(user: MutexGuard<'_, User>, _: CampshipDownPacket) ->
     Ok(Action::Nothing)
 }
 
+pub async fn return_to_campship(user: MutexGuard<'_, User>) -> HResult {
+    let map = user.get_current_map();
+    let id = user.get_user_id();
+    drop(user);
+    if let Some(map) = map {
+        let mut lock = map.lock().await;
+        lock.return_to_campship(id).await?;
+    }
+
+    Ok(Action::Nothing)
+}
+
 pub async fn map_loaded(mut user_guard: MutexGuard<'_, User>, _: MapLoadedPacket) -> HResult {
     let user = &mut *user_guard;
     let user_id = user.get_user_id();
@@ -136,7 +149,7 @@ pub async fn map_loaded(mut user_guard: MutexGuard<'_, User>, _: MapLoadedPacket
     let inventory_packets = character.inventory.send(
         user_id,
         character.character.name.clone(),
-        &user.blockdata.server_data.item_params,
+        &user.blockdata.server_data().item_params,
         user.user_data.lang,
     );
     let palette = character.palette.send_palette();
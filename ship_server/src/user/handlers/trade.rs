@@ -0,0 +1,244 @@
+use crate::{
+    Error, User,
+    inventory::TransactionError,
+    mutex::MutexGuard,
+    trade::{Confirmation, Trade, TradeSide},
+};
+use pso2packetlib::protocol::items::ItemType;
+use std::sync::Arc;
+
+type SharedTrade = Arc<parking_lot::Mutex<Trade>>;
+
+/// Requests a trade with a player in the same map or accepts their trade request.
+pub async fn request(user: &mut MutexGuard<'_, User>, name: &str) -> Result<(), Error> {
+    if user.trade.is_some() {
+        user.send_system_msg("{red}You are already trading{def}")
+            .await?;
+        return Ok(());
+    }
+    let Some(map) = user.get_current_map() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let own_id = user.get_user_id();
+    let name = name.to_string();
+    let found = MutexGuard::unlocked_async(user, || async move {
+        let mut own = None;
+        let mut target = None;
+        let players = map.lock().await.get_players();
+        for player in players {
+            let lock = player.lock().await;
+            let id = lock.get_user_id();
+            let char_name = lock
+                .character
+                .as_ref()
+                .map(|c| c.character.name.clone())
+                .unwrap_or_default();
+            drop(lock);
+            if id == own_id {
+                own = Some((player, char_name));
+            } else if char_name.eq_ignore_ascii_case(&name) {
+                target = Some((id, player, char_name));
+            }
+        }
+        own.zip(target)
+    })
+    .await;
+    let Some(((own, own_name), (target_id, target, target_name))) = found else {
+        user.send_system_msg("{red}Player not found in this area{def}")
+            .await?;
+        return Ok(());
+    };
+
+    if !user.trade_requests.contains(&target_id) {
+        let msg = format!(
+            "{own_name} wants to trade with you. Use !trade {own_name} to accept the request."
+        );
+        MutexGuard::unlocked_async(user, || async move {
+            let mut target = target.lock().await;
+            if !target.trade_requests.contains(&own_id) {
+                target.trade_requests.push(own_id);
+            }
+            target.send_system_msg(&msg).await
+        })
+        .await?;
+        user.send_system_msg(&format!("Trade request sent to {target_name}."))
+            .await?;
+        return Ok(());
+    }
+
+    user.trade_requests.retain(|id| *id != target_id);
+    let trade = Arc::new(parking_lot::Mutex::new(Trade::new(
+        TradeSide::new(own_id, own_name, Arc::downgrade(&own)),
+        TradeSide::new(target_id, target_name.clone(), Arc::downgrade(&target)),
+    )));
+    // mark us as trading, so nobody else can start a trade in the meantime
+    user.trade = Some(trade.clone());
+    let started = MutexGuard::unlocked_async(user, || {
+        let trade = trade.clone();
+        async move {
+            let mut target = target.lock().await;
+            if target.trade.is_some() {
+                return Ok(false);
+            }
+            target.trade = Some(trade.clone());
+            let summary = summary(&target, &trade);
+            target.send_system_msg(&summary).await?;
+            Ok::<_, Error>(true)
+        }
+    })
+    .await?;
+    if !started {
+        user.trade = None;
+        user.send_system_msg(&format!("{{red}}{target_name} is already trading{{def}}"))
+            .await?;
+        return Ok(());
+    }
+    let summary = summary(user, &trade);
+    user.send_system_msg(&summary).await?;
+    Ok(())
+}
+
+/// Adds an inventory item to the trade offer. Amount of 0 removes the item from the offer.
+pub async fn offer_item(
+    user: &mut MutexGuard<'_, User>,
+    slot: usize,
+    amount: u16,
+) -> Result<(), Error> {
+    let Some(trade) = get_trade(user).await? else {
+        return Ok(());
+    };
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    let inventory = &character.inventory;
+    let item = inventory.get_inv_items().get(slot.wrapping_sub(1)).cloned();
+    let result = match item {
+        None => Err(TransactionError::NoItem),
+        Some(item) if inventory.is_equiped(item.uuid) => Err(TransactionError::Equipped),
+        Some(item) => match &item.data {
+            ItemType::Consumable(data) if amount > data.amount => {
+                Err(TransactionError::InvalidAmount)
+            }
+            ItemType::Consumable(_) => Ok((item.uuid, amount, item.id)),
+            _ if amount > 1 => Err(TransactionError::InvalidAmount),
+            _ => Ok((item.uuid, amount, item.id)),
+        },
+    };
+    let (uuid, amount, id) = match result {
+        Ok(offer) => offer,
+        Err(e) => {
+            user.send_system_msg(&format!("{{red}}{e}{{def}}")).await?;
+            return Ok(());
+        }
+    };
+    trade.lock().set_item(user.get_user_id(), uuid, amount, id);
+    send_summaries(user, &trade).await
+}
+
+/// Sets the amount of meseta in the trade offer.
+pub async fn offer_meseta(user: &mut MutexGuard<'_, User>, meseta: u64) -> Result<(), Error> {
+    let Some(trade) = get_trade(user).await? else {
+        return Ok(());
+    };
+    let Some(character) = user.character.as_ref() else {
+        unreachable!("User should be in state >= `InGame`")
+    };
+    if meseta > character.inventory.get_meseta() {
+        let msg = format!("{{red}}{}{{def}}", TransactionError::NotEnoughMeseta);
+        user.send_system_msg(&msg).await?;
+        return Ok(());
+    }
+    trade.lock().set_meseta(user.get_user_id(), meseta);
+    send_summaries(user, &trade).await
+}
+
+/// Locks the offer or accepts the trade. The trade is executed after both players accept it.
+pub async fn confirm(user: &mut MutexGuard<'_, User>) -> Result<(), Error> {
+    let Some(trade) = get_trade(user).await? else {
+        return Ok(());
+    };
+    let confirmation = trade.lock().confirm(user.get_user_id());
+    match confirmation {
+        Confirmation::Locked | Confirmation::Accepted => send_summaries(user, &trade).await,
+        Confirmation::WaitingForOther => {
+            user.send_system_msg("Waiting for the other player to lock their offer.")
+                .await
+        }
+        Confirmation::Completed => {
+            MutexGuard::unlocked_async(user, || Trade::execute(&trade)).await
+        }
+    }
+}
+
+/// Cancels the current trade and rejects all trade requests.
+pub async fn cancel(user: &mut MutexGuard<'_, User>) -> Result<(), Error> {
+    user.trade_requests.clear();
+    let Some(trade) = user.trade.take() else {
+        return Ok(());
+    };
+    let partner = trade.lock().partner(user.get_user_id()).user.upgrade();
+    if let Some(partner) = partner {
+        let name = user
+            .character
+            .as_ref()
+            .map(|c| c.character.name.clone())
+            .unwrap_or_default();
+        MutexGuard::unlocked_async(user, || async move {
+            let mut partner = partner.lock().await;
+            if partner
+                .trade
+                .as_ref()
+                .is_some_and(|t| Arc::ptr_eq(t, &trade))
+            {
+                partner.trade = None;
+                let msg = format!("{name} has cancelled the trade.");
+                partner.send_system_msg(&msg).await?;
+            }
+            Ok::<_, Error>(())
+        })
+        .await?;
+    }
+    user.send_system_msg("Trade was cancelled.").await
+}
+
+/// Returns the current trade of the player or notifies them that they aren't trading.
+async fn get_trade(user: &mut User) -> Result<Option<SharedTrade>, Error> {
+    let trade = user.trade.clone();
+    if trade.is_none() {
+        user.send_system_msg("{red}You are not trading{def}")
+            .await?;
+    }
+    Ok(trade)
+}
+
+fn summary(user: &User, trade: &SharedTrade) -> String {
+    let srv_data = user.blockdata.server_data();
+    trade.lock().summary(
+        user.get_user_id(),
+        &srv_data.item_params,
+        user.user_data.lang,
+    )
+}
+
+/// Sends the trade state to both players.
+async fn send_summaries(user: &mut MutexGuard<'_, User>, trade: &SharedTrade) -> Result<(), Error> {
+    let own_summary = summary(user, trade);
+    user.send_system_msg(&own_summary).await?;
+    let partner = trade.lock().partner(user.get_user_id()).user.upgrade();
+    let Some(partner) = partner else {
+        return Ok(());
+    };
+    MutexGuard::unlocked_async(user, || async move {
+        let mut partner = partner.lock().await;
+        if partner
+            .trade
+            .as_ref()
+            .is_some_and(|t| Arc::ptr_eq(t, trade))
+        {
+            let summary = summary(&partner, trade);
+            partner.send_system_msg(&summary).await?;
+        }
+        Ok(())
+    })
+    .await
+}
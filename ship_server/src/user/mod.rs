@@ -7,13 +7,15 @@ use crate::{
     mutex::{Mutex, MutexGuard, RwLock},
     party::{self, Party},
     sql::{self, CharData},
+    trade::Trade,
 };
-use data_structs::flags::Flags;
+use data_structs::{flags::Flags, map::ShopData};
 use pso2packetlib::{
     Connection, PublicKey,
     connection::{ConnectionError, ConnectionRead, ConnectionWrite},
     protocol::{
         self as Pr, ObjectHeader, Packet, PacketType,
+        items::ItemId,
         login::Language,
         models::{
             Position,
@@ -47,6 +49,13 @@ pub struct User {
     battle_stats: PlayerStats,
     conn_id: usize,
     pub user_data: sql::User,
+    /// Shop of the NPC the player has last talked to.
+    pub shop: Option<ShopData>,
+    pub trade: Option<Arc<parking_lot::Mutex<Trade>>>,
+    /// Players that have requested a trade with this player.
+    pub trade_requests: Vec<u32>,
+    /// Consumables and the time when they can be used again.
+    pub item_cooldowns: Vec<(ItemId, Instant)>,
 
     session_start: Instant,
 }
@@ -100,6 +109,10 @@ impl User {
                     last_uuid: 1,
                     ..Default::default()
                 },
+                shop: None,
+                trade: None,
+                trade_requests: vec![],
+                item_cooldowns: vec![],
                 session_start: Instant::now(),
             },
             read,
@@ -166,6 +179,9 @@ impl User {
         self.party.clone()
     }
     pub fn set_map(&mut self, map: Arc<Mutex<Map>>) {
+        self.shop = None;
+        self.trade = None;
+        self.trade_requests.clear();
         self.map = Some(map)
     }
     pub const fn get_user_id(&self) -> u32 {
@@ -177,7 +193,7 @@ impl User {
     pub const fn get_stats(&self) -> &PlayerStats {
         &self.battle_stats
     }
-    pub fn get_stats_mut(&mut self) -> &mut PlayerStats {
+    pub const fn get_stats_mut(&mut self) -> &mut PlayerStats {
         &mut self.battle_stats
     }
     pub const fn create_object_header(&self) -> ObjectHeader {
@@ -188,12 +204,12 @@ impl User {
             map_id: 0,
         }
     }
-    pub fn get_blockdata(&self) -> &BlockData {
+    pub const fn get_blockdata(&self) -> &Arc<BlockData> {
         &self.blockdata
     }
     pub async fn send_item_attrs(&mut self) -> Result<(), Error> {
         let blockdata = self.blockdata.clone();
-        let item_attrs = &blockdata.server_data.item_params;
+        let item_attrs = &blockdata.server_data().item_params;
         let data = match self.user_data.packet_type {
             PacketType::Vita => &item_attrs.vita_attrs,
             _ => &item_attrs.pc_attrs,
@@ -235,6 +251,12 @@ impl User {
         .await?;
         Ok(())
     }
+    /// Shows the reason to the player and disconnects them shortly after.
+    pub async fn kick(&mut self, reason: &str) -> Result<(), Error> {
+        self.ready_to_shutdown = true;
+        self.last_ping = Instant::now();
+        self.send_error(reason).await
+    }
     pub async fn send_position(
         user: MutexGuard<'_, User>,
         packet: Packet,
@@ -248,6 +270,13 @@ impl User {
         }
         Ok(Action::Nothing)
     }
+    /// Returns levels of the main class and the subclass.
+    pub fn get_class_levels(&self) -> Option<(u16, u16)> {
+        self.character.as_ref().map(|c| {
+            let c = &c.character;
+            (c.get_level().level1, c.get_sublevel().level1)
+        })
+    }
     pub fn add_exp(&mut self, exp: u32) -> Result<EXPReceiver, Error> {
         let mut packet = EXPReceiver {
             object: self.create_object_header(),
@@ -256,7 +285,7 @@ impl User {
             gained: exp as _,
             ..Default::default()
         };
-        let srv_data = &self.blockdata.server_data;
+        let srv_data = &self.blockdata.server_data();
         let char = self
             .character
             .as_mut()
@@ -384,6 +413,9 @@ pub async fn packet_handler(
         (US::InGame, P::MapLoaded(data)) => H::server::map_loaded(user_guard, data).await,
         (US::InGame, P::ToCampship(data)) => H::server::to_campship(user_guard, data).await,
         (US::InGame, P::CampshipDown(data)) => H::server::campship_down(user_guard, data).await,
+        (US::InGame, P::ReturnToCampship(_))
+        | (US::InGame, P::ReturnToCampshipFinal(_))
+        | (US::InGame, P::DeathToCampship(_)) => H::server::return_to_campship(user_guard).await,
         (US::InGame, P::CasinoToLobby(data)) => H::server::move_from_casino(user_guard, data).await,
         (US::InGame, P::CasinoTransport(data)) => H::server::move_to_casino(user_guard, data).await,
         (US::InGame, P::BridgeToLobby(data)) => H::server::move_from_bridge(user_guard, data).await,
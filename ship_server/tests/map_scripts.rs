@@ -0,0 +1,167 @@
+use data_structs::{
+    ServerData,
+    map::MapData,
+    quest::{QuestData, UnlockConditions},
+};
+use pso2packetlib::protocol::Packet;
+use pso2ship_server::harness::MapHarness;
+use std::path::Path;
+
+const XION_MAP: &str = "../data/quests/Story Quests/EP1/700000 - An Encounter with Xion/map";
+const TEST_QUEST: &str = "../data/quests/200030 - Test Quest";
+
+fn load_map(path: &str) -> MapData {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+    let data = std::fs::read_to_string(path.join("map.json")).unwrap();
+    let mut map: MapData = serde_json::from_str(&data).unwrap();
+    let Ok(luas) = std::fs::read_dir(path.join("luas")) else {
+        return map;
+    };
+    for entry in luas {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        map.luas
+            .insert(name, std::fs::read_to_string(path).unwrap());
+    }
+    map
+}
+
+fn load_test_quest() -> QuestData {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_QUEST);
+    let data = std::fs::read_to_string(path.join("data.json")).unwrap();
+    let mut quest: QuestData = serde_json::from_str(&data).unwrap();
+    // the harness doesn't have level data
+    quest.rewards.exp = 0;
+    quest
+}
+
+/// Returns server data with the quest and a quest (200031) that is unlocked by clearing it.
+fn server_data_with_next_quest(quest: &QuestData) -> ServerData {
+    let mut next_quest = QuestData {
+        unlock: Some(UnlockConditions {
+            cleared_quests: vec![quest.definition.name_id],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    next_quest.definition.name_id = 200031;
+    ServerData {
+        quests: vec![quest.clone(), next_quest],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_xion_intro() {
+    let mut harness = MapHarness::new(load_map(XION_MAP)).await.unwrap();
+    let id = harness.add_player().await.unwrap();
+    assert_eq!(harness.zone(id).await.unwrap().as_deref(), Some("landing"));
+
+    // landing zone moves the player to the cutscene zone
+    harness.map_loaded(id).await.unwrap();
+    assert_eq!(harness.account_flag(id, 93).await.unwrap(), 1);
+    assert_eq!(harness.zone(id).await.unwrap().as_deref(), Some("cutscene"));
+    let packets = harness.take_packets(id).await.unwrap();
+    assert!(packets.iter().any(|p| matches!(p, Packet::MapTransfer(_))));
+
+    // cutscene zone starts the cutscene
+    harness.map_loaded(id).await.unwrap();
+    let packets = harness.take_packets(id).await.unwrap();
+    assert!(
+        packets
+            .iter()
+            .any(|p| matches!(p, Packet::StartCutscene(c) if c.scene_name == "st_010120_om"))
+    );
+
+    // the end of the cutscene unlocks the next quest and returns the player to the lobby
+    harness.cutscene_end(id, Default::default()).await.unwrap();
+    assert!(harness.unlocked_quests(id).await.unwrap().contains(&700020));
+    assert_eq!(harness.zone(id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_quest_clear() {
+    let quest = load_test_quest();
+    let server_data = server_data_with_next_quest(&quest);
+    let mut harness =
+        MapHarness::with_server_data(load_map(&format!("{TEST_QUEST}/map")), server_data)
+            .await
+            .unwrap();
+    harness.set_quest(&quest, 0).await;
+    let id = harness.add_player().await.unwrap();
+    assert_eq!(harness.zone(id).await.unwrap().as_deref(), Some("campship"));
+
+    // reaching the target zone clears the quest
+    harness.move_to_zone(id, "campship_down").await.unwrap();
+    assert_eq!(harness.meseta(id).await.unwrap(), 1000);
+    let packets = harness.take_packets(id).await.unwrap();
+    assert!(
+        packets
+            .iter()
+            .any(|p| matches!(p, Packet::InventoryMeseta(_)))
+    );
+    // the clear unlocks the next quest
+    assert!(harness.unlocked_quests(id).await.unwrap().contains(&200031));
+
+    // results are shown after returning to the campship
+    harness.return_to_campship(id).await.unwrap();
+    assert_eq!(harness.zone(id).await.unwrap().as_deref(), Some("campship"));
+    let packets = harness.take_packets(id).await.unwrap();
+    assert!(
+        packets
+            .iter()
+            .any(|p| matches!(p, Packet::QuestResult(r) if r.meseta_earned == 1000))
+    );
+
+    // rewards are given only once
+    harness.move_to_zone(id, "campship_down").await.unwrap();
+    assert_eq!(harness.meseta(id).await.unwrap(), 1000);
+}
+
+#[tokio::test]
+async fn test_quest_fail() {
+    let mut quest = load_test_quest();
+    quest.fail_conditions.signals.push("abort".into());
+    let mut map = load_map(&format!("{TEST_QUEST}/map"));
+    map.luas
+        .insert("on_questwork".into(), "quest_signal(\"abort\")".into());
+    let mut harness = MapHarness::new(map).await.unwrap();
+    harness.set_quest(&quest, 0).await;
+    let id = harness.add_player().await.unwrap();
+
+    // the failure signal sends the party to the lobby
+    harness.questwork(id, Default::default()).await.unwrap();
+    for _ in 0..100 {
+        if harness.zone(id).await.unwrap().is_none() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(harness.zone(id).await.unwrap(), None);
+    let packets = harness.take_packets(id).await.unwrap();
+    assert!(
+        packets.iter().any(
+            |p| matches!(p, Packet::SystemMessage(m) if m.message.starts_with("Quest failed"))
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_reload_keeps_running_map_data() {
+    let quest = load_test_quest();
+    let server_data = server_data_with_next_quest(&quest);
+    let mut harness =
+        MapHarness::with_server_data(load_map(&format!("{TEST_QUEST}/map")), server_data)
+            .await
+            .unwrap();
+    harness.set_quest(&quest, 0).await;
+    let id = harness.add_player().await.unwrap();
+
+    // the reloaded data no longer has the next quest, but the running map uses the old data
+    harness.reload_data(ServerData {
+        quests: vec![quest],
+        ..Default::default()
+    });
+    harness.move_to_zone(id, "campship_down").await.unwrap();
+    assert!(harness.unlocked_quests(id).await.unwrap().contains(&200031));
+}